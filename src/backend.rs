@@ -0,0 +1,578 @@
+//! Where the build crate is compiled and run.
+
+use std::io::{self, BufRead};
+use std::sync::Arc;
+use std::{env, ffi, path, process, thread, time};
+
+use crate::interrupt;
+use crate::json::Json;
+use crate::output_log::{self, OutputLog};
+use crate::timeout;
+use crate::{Toolchain, STAGING_DIR_ENV};
+
+/// Where to compile and run the staged build crate.
+#[derive(Clone, Debug, Default)]
+pub enum ExecutionBackend {
+    /// On this machine.
+    #[default]
+    Local,
+    /// On another machine, over SSH. See `SshBackend`.
+    Ssh(SshBackend),
+    /// For a foreign architecture, with `cross`. See `CrossBackend`.
+    Cross(CrossBackend),
+}
+
+/// How a build crate compiled on this machine is run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunStrategy {
+    /// Compile and run it in one go with `cargo run`, leaving cargo to pick
+    /// the executable, so renamed bins, custom targets and
+    /// `required-features` need no guessing. The script sees the outer build
+    /// script's environment, bar what the inner cargo would take as its own
+    /// configuration.
+    ///
+    /// Unlike with `BuildThenExec`, the default until now, the script runs
+    /// in the staged crate rather than the build crate's source dir, and
+    /// cargo sets `CARGO_MANIFEST_DIR` and `CARGO_PKG_*` to the build
+    /// crate's own rather than the parent's. A script relying on either
+    /// should ask for `BuildThenExec`.
+    ///
+    /// `BuildThenExec` is used instead when the compiled script is cached,
+    /// run with an exec runner, found with `executable_discovery`, audited or
+    /// exported, when the build crate's lockfile is exported, updated or
+    /// checked for overlap, when `compile_timeout` is set, and on any backend
+    /// but `Local`.
+    #[default]
+    CargoRun,
+    /// Compile it with `cargo build`, then run the executable cargo reports
+    /// building, from the build crate's source dir.
+    BuildThenExec,
+}
+
+/// Compiles and runs the build crate on a remote host, e.g. one with licensed
+/// SDKs installed.
+///
+/// The staging dir is copied to the host with `tar` over `ssh`, compiled
+/// with the host's `cargo`, and the executable cargo reports building is run
+/// there with its output streamed back, so `cargo:` directives are rebased,
+/// collected and passed on as for a local run. Whatever the script writes to
+/// its `OUT_DIR` is copied back into the local one afterwards. The remote
+/// `cargo build` is logged and timed out like a local one, by killing `ssh`.
+///
+/// The staged crate must be self-contained: path dependencies are rewritten
+/// to absolute local paths, which must also exist on the remote host.
+#[derive(Clone, Debug)]
+pub struct SshBackend {
+    host: String,
+    remote_root: String,
+    ssh: ffi::OsString,
+    ssh_args: Vec<ffi::OsString>,
+}
+
+impl SshBackend {
+    /// Run on `host`, which is passed to ssh as-is, so `user@host` and
+    /// aliases from `~/.ssh/config` work.
+    pub fn new<S: Into<String>>(host: S) -> Self {
+        SshBackend {
+            host: host.into(),
+            remote_root: "/tmp".to_owned(),
+            ssh: "ssh".into(),
+            ssh_args: Vec::new(),
+        }
+    }
+
+    /// The directory on the remote host to stage build crates in. Defaults
+    /// to `/tmp`.
+    pub fn remote_root<S: Into<String>>(mut self, remote_root: S) -> Self {
+        self.remote_root = remote_root.into();
+        self
+    }
+
+    /// The ssh executable to use. Defaults to `ssh` on the `PATH`.
+    pub fn ssh_program<S: AsRef<ffi::OsStr>>(mut self, ssh: S) -> Self {
+        self.ssh = ssh.as_ref().to_owned();
+        self
+    }
+
+    /// An extra argument for every ssh invocation, e.g. `-p` and `2222`.
+    pub fn ssh_arg<S: AsRef<ffi::OsStr>>(mut self, arg: S) -> Self {
+        self.ssh_args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    fn ssh(&self, remote_command: &str) -> process::Command {
+        let mut command = process::Command::new(&self.ssh);
+        command
+            .args(&self.ssh_args)
+            .arg(&self.host)
+            .arg(remote_command);
+        command
+    }
+
+    fn remote_dir(&self, staging_dir: &path::Path) -> String {
+        let name = staging_dir
+            .file_name()
+            .expect("staging dir has no name")
+            .to_string_lossy();
+        format!("{}/{}", self.remote_root.trim_end_matches('/'), name)
+    }
+
+    /// Ask the remote toolchain for its versions.
+    pub(crate) fn detect_toolchain(&self) -> Toolchain {
+        let output = self
+            .ssh("cargo --version; rustc --version")
+            .stderr(process::Stdio::inherit())
+            .output();
+        let stdout = match output {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+            Err(_) => String::new(),
+        };
+        let find = |tool: &str| {
+            stdout
+                .lines()
+                .find(|line| line.starts_with(tool))
+                .map(str::to_owned)
+        };
+
+        Toolchain {
+            cargo_version: find("cargo "),
+            rustc_version: find("rustc "),
+            host: None,
+        }
+    }
+
+    /// Copy `staging_dir` to the remote host, to compile and run it there.
+    pub(crate) fn stage(&self, staging_dir: &path::Path, keep: bool) -> RemoteDir<'_> {
+        let remote_dir = RemoteDir {
+            backend: self,
+            path: self.remote_dir(staging_dir),
+            keep,
+        };
+        println!(
+            "Copying staged build crate to {}:{}",
+            self.host, remote_dir.path
+        );
+        self.push(staging_dir, &remote_dir.path);
+        remote_dir
+    }
+
+    /// `tar -C local -c . | ssh host 'mkdir -p remote && tar -C remote -x'`
+    fn push(&self, local: &path::Path, remote: &str) {
+        let mut tar = process::Command::new("tar")
+            .arg("-C")
+            .arg(local)
+            .args(["--exclude", "./target", "-cf", "-", "."])
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to run tar: {}", e));
+        let remote_command = format!("mkdir -p {0} && tar -C {0} -xf -", shell_quote(remote));
+        let status = self
+            .ssh(&remote_command)
+            .stdin(tar.stdout.take().expect("tar stdout is piped"))
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run ssh: {}", e));
+        let tar_status = tar.wait().expect("Failed to wait for tar");
+        assert!(
+            tar_status.success() && status.success(),
+            "Failed to copy {} to {}:{} (tar: {}, ssh: {})",
+            local.display(),
+            self.host,
+            remote,
+            tar_status,
+            status
+        );
+    }
+
+    /// `ssh host 'tar -C remote -c .' | tar -C local -x`
+    fn pull(&self, remote: &str, local: &path::Path) {
+        let mut ssh = self
+            .ssh(&format!("tar -C {} -cf - .", shell_quote(remote)))
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to run ssh: {}", e));
+        let status = process::Command::new("tar")
+            .arg("-C")
+            .arg(local)
+            .arg("-xf")
+            .arg("-")
+            .stdin(ssh.stdout.take().expect("ssh stdout is piped"))
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run tar: {}", e));
+        let ssh_status = ssh.wait().expect("Failed to wait for ssh");
+        assert!(
+            ssh_status.success() && status.success(),
+            "Failed to copy {}:{} to {} (ssh: {}, tar: {})",
+            self.host,
+            remote,
+            local.display(),
+            ssh_status,
+            status
+        );
+    }
+}
+
+/// Compiles the build crate for a foreign architecture with
+/// [`cross`](https://github.com/cross-rs/cross), for build tooling that must
+/// itself run on the target, e.g. to probe the target's libc.
+///
+/// The compiled script is run through the runner set with
+/// `BuildCrateRunner::exec_runner` (typically `qemu-<arch>` in user mode),
+/// exactly as the local backend would run it. Without a runner, it is run
+/// with `cross run` inside the container instead; only the staged crate is
+/// mounted there, so the script can't write to the outer `OUT_DIR`.
+#[derive(Clone, Debug)]
+pub struct CrossBackend {
+    target: String,
+    image: Option<String>,
+    cross: ffi::OsString,
+}
+
+/// Variables `cross` needs to reach the container engine, forwarded in
+/// addition to the usual isolated environment.
+const CROSS_ENV_VARS: &[&str] = &[
+    "HOME",
+    "USER",
+    "XDG_RUNTIME_DIR",
+    "DOCKER_HOST",
+    "DOCKER_CERT_PATH",
+    "DOCKER_TLS_VERIFY",
+    "CROSS_CONTAINER_ENGINE",
+];
+
+impl CrossBackend {
+    /// Compile for `target`, e.g. `"aarch64-unknown-linux-gnu"`.
+    pub fn new<S: Into<String>>(target: S) -> Self {
+        CrossBackend {
+            target: target.into(),
+            image: None,
+            cross: "cross".into(),
+        }
+    }
+
+    /// Use this container image instead of cross' default one for the
+    /// target.
+    pub fn image<S: Into<String>>(mut self, image: S) -> Self {
+        self.image = Some(image.into());
+        self
+    }
+
+    /// The cross executable to use. Defaults to `cross` on the `PATH`.
+    pub fn cross_program<S: AsRef<ffi::OsStr>>(mut self, cross: S) -> Self {
+        self.cross = cross.as_ref().to_owned();
+        self
+    }
+
+    /// The target triple the build crate is compiled for.
+    pub(crate) fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn cross(
+        &self,
+        subcommand: &str,
+        crate_dir: &path::Path,
+        cargo_args: &[&str],
+        forwarded_env: &[(String, ffi::OsString)],
+    ) -> process::Command {
+        let mut command = process::Command::new(&self.cross);
+        command
+            .arg(subcommand)
+            .arg("--target")
+            .arg(&self.target)
+            .args(cargo_args)
+            .current_dir(crate_dir)
+            .env_clear()
+            .envs(forwarded_env.iter().cloned());
+        for var in CROSS_ENV_VARS {
+            if let Some(value) = env::var_os(var) {
+                command.env(var, value);
+            }
+        }
+        if let Some(image) = &self.image {
+            command.env(self.image_env_var(), image);
+        }
+        command
+    }
+
+    /// `CROSS_TARGET_<TRIPLE>_IMAGE`, which overrides cross' image for the
+    /// target.
+    fn image_env_var(&self) -> String {
+        format!(
+            "CROSS_TARGET_{}_IMAGE",
+            self.target.to_uppercase().replace(['-', '.'], "_")
+        )
+    }
+
+    fn run_cross(&self, mut command: process::Command, what: &str) {
+        let status = command
+            .status()
+            .unwrap_or_else(|e| panic!("Failed to run cross to {}: {}", what, e));
+        assert!(
+            status.success(),
+            "Failed to {} for {} ({})",
+            what,
+            self.target,
+            status
+        );
+    }
+
+    /// `cross build --target <target>` in `crate_dir`.
+    pub(crate) fn compile(
+        &self,
+        crate_dir: &path::Path,
+        cargo_args: &[&str],
+        forwarded_env: &[(String, ffi::OsString)],
+    ) {
+        let command = self.cross("build", crate_dir, cargo_args, forwarded_env);
+        self.run_cross(command, "compile the build crate");
+    }
+
+    /// `cross run --target <target>` in `crate_dir`, for when there's no
+    /// runner to execute the compiled script with on this machine.
+    pub(crate) fn run(
+        &self,
+        crate_dir: &path::Path,
+        cargo_args: &[&str],
+        forwarded_env: &[(String, ffi::OsString)],
+    ) {
+        let command = self.cross("run", crate_dir, cargo_args, forwarded_env);
+        self.run_cross(command, "run the build crate");
+    }
+}
+
+/// The build crate's directory on the remote host, removed when dropped.
+pub(crate) struct RemoteDir<'a> {
+    backend: &'a SshBackend,
+    path: String,
+    keep: bool,
+}
+
+impl RemoteDir<'_> {
+    /// The directory, for the paths of the build crate's artifacts.
+    pub(crate) fn path(&self) -> &path::Path {
+        path::Path::new(&self.path)
+    }
+
+    /// Where the build script writes its generated files.
+    fn out_dir(&self) -> String {
+        format!("{}/out", self.path)
+    }
+
+    /// Compile the build crate, then run `audit_command` on it, returning
+    /// the name and remote path of each executable cargo reports building.
+    /// What it prints is logged to `output_log`, and `ssh` is killed if it's
+    /// still running after `timeout`, as the local cargo would be.
+    pub(crate) fn compile(
+        &self,
+        cargo_args: &[&str],
+        audit_command: Option<&[ffi::OsString]>,
+        output_log: Option<&Arc<OutputLog>>,
+        timeout: Option<time::Duration>,
+    ) -> Vec<(String, path::PathBuf)> {
+        let mut script = vec![
+            format!("cd {}", shell_quote(&self.path)),
+            format!(
+                "cargo build {} --message-format=json-render-diagnostics",
+                cargo_args
+                    .iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        ];
+        // Its report goes to stderr, out of the way of cargo's messages.
+        if let Some(audit_command) = audit_command {
+            let audit: Vec<_> = audit_command
+                .iter()
+                .map(|arg| shell_quote(&arg.to_string_lossy()))
+                .collect();
+            script.push(format!("{} >&2", audit.join(" ")));
+        }
+
+        // stderr only needs to pass through us to be logged.
+        let stderr = match output_log {
+            Some(_) => process::Stdio::piped(),
+            None => process::Stdio::inherit(),
+        };
+        let mut ssh = self
+            .backend
+            .ssh(&script.join(" && "))
+            .stdout(process::Stdio::piped())
+            .stderr(stderr)
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to run ssh to build the build crate: {}", e));
+        let _tracked = interrupt::track(&ssh);
+        let stderr_thread = ssh.stderr.take().map(|stderr| {
+            let mut stderr = output_log::tee(output_log, stderr, "cargo build", "stderr");
+            thread::spawn(move || io::copy(&mut stderr, &mut io::stderr()))
+        });
+        let stdout = output_log::tee(
+            output_log,
+            ssh.stdout.take().expect("stdout is piped"),
+            "cargo build",
+            "stdout",
+        );
+        let ssh = timeout::Watched::new(ssh, timeout);
+
+        let mut executables = Vec::new();
+        for line in io::BufReader::new(stdout).lines() {
+            let line = line.expect("Couldn't read cargo's output");
+            match Json::parse(&line) {
+                Ok(message) => executables.extend(crate::artifact_executable(&message)),
+                // Not one of cargo's messages; pass it on.
+                Err(_) => println!("{}", line),
+            }
+        }
+        if let Some(stderr_thread) = stderr_thread {
+            stderr_thread
+                .join()
+                .expect("stderr thread panicked")
+                .expect("Couldn't pass on cargo's stderr");
+        }
+        let status = ssh.wait();
+        interrupt::check();
+        let status = status.unwrap_or_else(|e| {
+            panic!(
+                "Couldn't build the build crate on {}: {}",
+                self.backend.host, e
+            )
+        });
+        assert!(
+            status.success(),
+            "Failed to build the build crate on {} ({})",
+            self.backend.host,
+            status
+        );
+        executables
+    }
+
+    /// The ssh command running `executable` with `args`, in this directory
+    /// and with `script_env`. Its `OUT_DIR` is always a remote one.
+    pub(crate) fn command(
+        &self,
+        executable: &path::Path,
+        script_env: &[(&str, ffi::OsString)],
+        args: &[ffi::OsString],
+    ) -> process::Command {
+        let out_dir = self.out_dir();
+        let mut run = vec![format!("OUT_DIR={}", shell_quote(&out_dir))];
+        for (var, value) in script_env {
+            if *var == "OUT_DIR" {
+                continue;
+            }
+            // The script sees the remote copy of the staging dir.
+            let value = if *var == STAGING_DIR_ENV {
+                self.path.clone()
+            } else {
+                value.to_string_lossy().into_owned()
+            };
+            run.push(format!("{}={}", var, shell_quote(&value)));
+        }
+        run.push(shell_quote(&executable.to_string_lossy()));
+        run.extend(args.iter().map(|arg| shell_quote(&arg.to_string_lossy())));
+
+        let script = [
+            format!("cd {}", shell_quote(&self.path)),
+            format!("mkdir -p {}", shell_quote(&out_dir)),
+            run.join(" "),
+        ];
+        self.backend.ssh(&script.join(" && "))
+    }
+
+    /// Copy what the build script wrote to its remote `OUT_DIR` back into
+    /// `out_dir`.
+    pub(crate) fn pull_out_dir(&self, out_dir: &path::Path) {
+        let remote_out_dir = self.out_dir();
+        println!(
+            "Copying {}:{} back to {}",
+            self.backend.host,
+            remote_out_dir,
+            out_dir.display()
+        );
+        self.backend.pull(&remote_out_dir, out_dir);
+    }
+}
+
+impl Drop for RemoteDir<'_> {
+    fn drop(&mut self) {
+        if self.keep {
+            println!(
+                "Keeping remote staging dir: {}:{}",
+                self.backend.host, self.path
+            );
+            return;
+        }
+
+        println!(
+            "Removing remote staging dir: {}:{}",
+            self.backend.host, self.path
+        );
+        let status = self
+            .backend
+            .ssh(&format!("rm -rf {}", shell_quote(&self.path)))
+            .status();
+        if !matches!(status, Ok(status) if status.success()) {
+            println!(
+                "cargo:warning=Couldn't remove {}:{}",
+                self.backend.host, self.path
+            );
+        }
+    }
+}
+
+/// Quote `s` for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:".contains(c))
+    {
+        return s.to_owned();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("-vv"), "-vv");
+        assert_eq!(shell_quote("/tmp/build-script-1"), "/tmp/build-script-1");
+        assert_eq!(shell_quote("has space"), "'has space'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_cross_image_env_var() {
+        let cross = CrossBackend::new("aarch64-unknown-linux-gnu");
+        assert_eq!(
+            cross.image_env_var(),
+            "CROSS_TARGET_AARCH64_UNKNOWN_LINUX_GNU_IMAGE"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_compile_timeout() {
+        // An ssh that hangs, whatever it's asked to run.
+        let backend = SshBackend::new("build-host")
+            .ssh_program("sh")
+            .ssh_arg("-c")
+            .ssh_arg("exec sleep 20");
+        let remote = RemoteDir {
+            backend: &backend,
+            path: "/nowhere".to_owned(),
+            keep: true,
+        };
+        let start = time::Instant::now();
+        let failed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            remote.compile(&[], None, None, Some(time::Duration::from_millis(200)))
+        }));
+        let message = failed.expect_err("ssh hung");
+        let message = crate::diagnostics::panic_message(&*message);
+        assert!(message.contains("timed out after 200ms"), "{}", message);
+        assert!(start.elapsed() < time::Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,120 @@
+//! Support for caching isolated builds between runs.
+
+use std::{env, fs, path, process};
+
+use crate::json::Json;
+
+/// Cargo's home dir, as the inner cargo will see it.
+pub(crate) fn cargo_home() -> Option<path::PathBuf> {
+    if let Some(cargo_home) = env::var_os("CARGO_HOME").filter(|h| !h.is_empty()) {
+        return Some(path::PathBuf::from(cargo_home));
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(path::Path::new(&home).join(".cargo"))
+}
+
+/// Write a file describing what a CI system should cache for this build
+/// crate, and under which key:
+///
+/// ```json
+/// {"key":"cargo-5730-build-script-<hash>","paths":["/home/ci/.cargo/registry", ...]}
+/// ```
+pub(crate) fn write_hints(hints_path: &path::Path, key: &str, paths: &[path::PathBuf]) {
+    let hints = Json::object(vec![
+        ("key", Json::string(key)),
+        (
+            "paths",
+            Json::Array(
+                paths
+                    .iter()
+                    .map(|p| Json::string(p.to_string_lossy()))
+                    .collect(),
+            ),
+        ),
+    ]);
+
+    if let Some(parent) = hints_path.parent() {
+        fs::create_dir_all(parent).unwrap_or_else(|e| {
+            panic!(
+                "Couldn't create cache hints dir {}: {}",
+                parent.display(),
+                e
+            )
+        });
+    }
+    fs::write(hints_path, hints.to_string() + "\n").unwrap_or_else(|e| {
+        panic!(
+            "Couldn't write cache hints to {}: {}",
+            hints_path.display(),
+            e
+        )
+    });
+}
+
+/// Where the build script for the build crate `name` with cache key `key`
+/// is kept in `cache_dir`. Named after the build crate whatever cargo called
+/// it, so it can be looked up before anything is compiled.
+fn cached_path(cache_dir: &path::Path, name: &str, key: &str) -> path::PathBuf {
+    cache_dir
+        .join(format!("{}-{}", name, key))
+        .join(format!("{}{}", name, env::consts::EXE_SUFFIX))
+}
+
+/// The build script an earlier run compiled from the same inputs, if it's
+/// still in `cache_dir`.
+pub(crate) fn cached_script(
+    cache_dir: &path::Path,
+    name: &str,
+    key: &str,
+) -> Option<path::PathBuf> {
+    let cached = cached_path(cache_dir, name, key);
+    if cached.is_file() {
+        Some(cached)
+    } else {
+        None
+    }
+}
+
+/// Keep the freshly `compiled` build script in `cache_dir` for later runs. A
+/// cache that can't be written to only costs a rebuild next time, so this
+/// warns rather than fails.
+pub(crate) fn store_script(cache_dir: &path::Path, name: &str, key: &str, compiled: &path::Path) {
+    let cached = cached_path(cache_dir, name, key);
+    let entry_dir = cached.parent().expect("Cache entries are in a dir");
+    // Copy under a name of our own and rename into place, so concurrent
+    // builds never see a half-written script.
+    let partial = entry_dir.join(format!(".partial-{}", process::id()));
+    let stored = fs::create_dir_all(entry_dir)
+        .and_then(|()| fs::copy(compiled, &partial))
+        .and_then(|_| fs::rename(&partial, &cached));
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&partial);
+        println!(
+            "cargo:warning=Couldn't cache the build script in {}: {}",
+            entry_dir.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_script_cache() {
+        let root = env::temp_dir().join(format!("cargo-5730-script-cache-{}", process::id()));
+        let compiled = root.join("target/debug/build-script");
+        fs::create_dir_all(compiled.parent().unwrap()).unwrap();
+        fs::write(&compiled, "binary").unwrap();
+        let cache_dir = root.join("cache");
+
+        assert_eq!(cached_script(&cache_dir, "build-script", "k1"), None);
+        store_script(&cache_dir, "build-script", "k1", &compiled);
+        let cached = cached_script(&cache_dir, "build-script", "k1").unwrap();
+        assert_eq!(fs::read_to_string(cached).unwrap(), "binary");
+        assert_eq!(cached_script(&cache_dir, "build-script", "k2"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,529 @@
+//! Passing the build script's output on to the outer build, within limits.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Arc;
+use std::{mem, path, process, thread, time};
+
+use crate::directives;
+use crate::interrupt;
+use crate::json::Json;
+use crate::output_log::{self, OutputLog};
+use crate::timeout;
+
+/// Caps how much of the build script's output is passed on to the outer
+/// build, which holds all of it in memory.
+///
+/// The first `head` and last `tail` bytes of each of stdout and stderr are
+/// kept, and a note of how much was dropped goes in between. `cargo:`
+/// directives are always passed on, whatever the limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct OutputLimit {
+    pub head: usize,
+    pub tail: usize,
+}
+
+impl OutputLimit {
+    pub fn new(head: usize, tail: usize) -> Self {
+        OutputLimit { head, tail }
+    }
+
+    /// Pass everything on.
+    pub(crate) fn unlimited() -> Self {
+        OutputLimit::new(usize::MAX, usize::MAX)
+    }
+}
+
+/// Output is read at most this much at a time, so a script printing one
+/// enormous line can't exhaust memory either.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+fn is_directive(line: &[u8]) -> bool {
+    line.starts_with(b"cargo:")
+}
+
+/// Copy `input` to `output`, dropping whatever doesn't fit in `limit`.
+/// Returns the number of bytes dropped.
+pub(crate) fn forward<R: BufRead, W: Write>(
+    mut input: R,
+    mut output: W,
+    limit: OutputLimit,
+) -> io::Result<u64> {
+    let mut head_left = limit.head;
+    let mut tail = VecDeque::new();
+    let mut tail_bytes = 0;
+    let mut dropped = 0;
+    // While in the middle of a line longer than a chunk, whether that line
+    // is a directive.
+    let mut continuing = None;
+    let mut chunk = Vec::new();
+
+    loop {
+        chunk.clear();
+        if (&mut input)
+            .take(CHUNK_SIZE)
+            .read_until(b'\n', &mut chunk)?
+            == 0
+        {
+            break;
+        }
+        let directive = continuing.unwrap_or_else(|| is_directive(&chunk));
+        continuing = if chunk.ends_with(b"\n") {
+            None
+        } else {
+            Some(directive)
+        };
+
+        if directive {
+            output.write_all(&chunk)?;
+            continue;
+        }
+
+        if chunk.len() <= head_left {
+            head_left -= chunk.len();
+            output.write_all(&chunk)?;
+            continue;
+        }
+
+        // Once the head is full, everything else goes through the tail, even
+        // if it would still fit, so the output stays in order.
+        head_left = 0;
+        tail_bytes += chunk.len();
+        tail.push_back(mem::take(&mut chunk));
+        while tail_bytes > limit.tail {
+            let oldest = tail.pop_front().expect("tail holds the bytes counted");
+            tail_bytes -= oldest.len();
+            dropped += oldest.len() as u64;
+        }
+    }
+
+    if dropped > 0 {
+        writeln!(output, "[cargo-5730: {} bytes of output dropped]", dropped)?;
+    }
+    for chunk in tail {
+        output.write_all(&chunk)?;
+    }
+    output.flush()?;
+    Ok(dropped)
+}
+
+/// What `run_limited` found in the command's output.
+pub(crate) struct Captured {
+    pub status: process::ExitStatus,
+    /// The last line that wasn't empty or a directive.
+    pub last_line: String,
+    /// The directives, if asked to collect them rather than pass them on.
+    pub directives: Vec<String>,
+    /// Everything it printed, if asked to record it.
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// How lines on the build script's stdout are rewritten on their way to the
+/// outer build.
+#[derive(Clone, Copy)]
+pub(crate) struct Rebase<'a> {
+    /// What relative paths in directives are relative to.
+    pub base: &'a path::Path,
+    /// The staged copy of `base` the script ran in, if it didn't run there.
+    pub staged: Option<&'a path::Path>,
+    /// Put in front of every other line, to tell the script's own output
+    /// apart from the outer build's.
+    pub label: &'a str,
+}
+
+/// Passes stdout through to `inner`, remembering the last line that isn't
+/// empty or a directive, and optionally holding back directives or
+/// rewriting them.
+struct StdoutTap<'a, W> {
+    inner: W,
+    collect_directives: bool,
+    rebase: Option<Rebase<'a>>,
+    current: Vec<u8>,
+    /// How much of `current` has been passed on already.
+    written: usize,
+    last: Vec<u8>,
+    directives: Vec<String>,
+}
+
+impl<'a, W: Write> StdoutTap<'a, W> {
+    fn new(inner: W, collect_directives: bool, rebase: Option<Rebase<'a>>) -> Self {
+        StdoutTap {
+            inner,
+            collect_directives,
+            rebase,
+            current: Vec::new(),
+            written: 0,
+            last: Vec::new(),
+            directives: Vec::new(),
+        }
+    }
+
+    /// Whether the line so far is, or may yet turn out to be, a directive.
+    fn may_be_directive(&self) -> bool {
+        const PREFIX: &[u8] = b"cargo:";
+        if self.current.len() < PREFIX.len() {
+            PREFIX.starts_with(&self.current)
+        } else {
+            is_directive(&self.current)
+        }
+    }
+
+    fn take(&mut self, piece: &[u8]) -> io::Result<()> {
+        self.current.extend_from_slice(piece);
+        let holding = (self.collect_directives || self.rebase.is_some()) && self.may_be_directive();
+        if !holding {
+            self.write_rest()?;
+            self.written = self.current.len();
+            // Only the start of the line is needed from here on; don't let
+            // one enormous line use up memory.
+            if self.current.len() > CHUNK_SIZE as usize {
+                self.current.truncate(CHUNK_SIZE as usize);
+                self.written = self.current.len();
+            }
+        }
+
+        if piece.ends_with(b"\n") {
+            self.end_line()?;
+        }
+        Ok(())
+    }
+
+    /// Pass on what's left of the current line, which isn't a directive,
+    /// labelled if it's the start of a line with something on it.
+    fn write_rest(&mut self) -> io::Result<()> {
+        if let (0, Some(rebase)) = (self.written, &self.rebase) {
+            if !matches!(&self.current[..], b"\n" | b"\r\n") {
+                self.inner.write_all(rebase.label.as_bytes())?;
+            }
+        }
+        self.inner.write_all(&self.current[self.written..])
+    }
+
+    fn end_line(&mut self) -> io::Result<()> {
+        let line = self.current.trim_ascii();
+        let directive = is_directive(line);
+        if !line.is_empty() && !directive {
+            self.last = line.to_vec();
+        }
+        if self.written < self.current.len() {
+            if directive {
+                let line = self.current.trim_ascii();
+                let mut line = String::from_utf8_lossy(line).into_owned();
+                if let Some(rebase) = &self.rebase {
+                    line = directives::rebase(&line, rebase.base, rebase.staged);
+                }
+                if self.collect_directives {
+                    self.directives.push(line);
+                } else {
+                    writeln!(self.inner, "{}", line)?;
+                }
+            } else {
+                // Held back on the chance it was a directive, but it wasn't.
+                self.write_rest()?;
+            }
+        }
+        self.current.clear();
+        self.written = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<(String, Vec<String>)> {
+        // The output may not end with a newline.
+        self.end_line()?;
+        self.inner.flush()?;
+        Ok((
+            String::from_utf8_lossy(&self.last).into_owned(),
+            self.directives,
+        ))
+    }
+}
+
+impl<W: Write> Write for StdoutTap<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for piece in buf.split_inclusive(|&byte| byte == b'\n') {
+            self.take(piece)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How `run_limited` passes on what the command prints.
+pub(crate) struct CaptureOptions<'a> {
+    pub limit: OutputLimit,
+    /// Send its stdout to our stderr instead.
+    pub redirect_stdout: bool,
+    /// Return the directives on its stdout rather than passing them on.
+    pub collect_directives: bool,
+    /// Rebase directives and label other lines on the way.
+    pub rebase: Option<Rebase<'a>>,
+    /// Where to record everything it prints, limits or not.
+    pub output_log: Option<&'a Arc<OutputLog>>,
+    /// Return a copy of everything it prints, limits or not.
+    pub record: bool,
+    /// Its stdout starts with the inner cargo's JSON messages, as from
+    /// `cargo run --message-format=json`, which aren't part of its output.
+    pub cargo_messages: bool,
+}
+
+/// Read the inner cargo's JSON messages off the start of `stdout`, up to the
+/// one saying the build's finished, writing the diagnostics among them to
+/// `diagnostics` as cargo would have. What follows is left to be read.
+fn show_cargo_messages<R: BufRead, W: Write>(stdout: &mut R, mut diagnostics: W) -> io::Result<()> {
+    let mut line = String::new();
+    while stdout.read_line(&mut line)? > 0 {
+        match Json::parse(line.trim_end()) {
+            Ok(message) => {
+                if let Some(rendered) = message
+                    .get("message")
+                    .and_then(|message| message.get("rendered"))
+                    .and_then(Json::as_str)
+                {
+                    diagnostics.write_all(rendered.as_bytes())?;
+                }
+                if message.get("reason").and_then(Json::as_str) == Some("build-finished") {
+                    return Ok(());
+                }
+            }
+            Err(_) => diagnostics.write_all(line.as_bytes())?,
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Reads through to `inner`, keeping a copy of what's read when asked to.
+struct Recording<R> {
+    inner: R,
+    copy: Option<Vec<u8>>,
+}
+
+impl<R: Read> Recording<R> {
+    fn new(inner: R, record: bool) -> Self {
+        Recording {
+            inner,
+            copy: record.then(Vec::new),
+        }
+    }
+}
+
+impl<R: Read> Read for Recording<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(copy) = &mut self.copy {
+            copy.extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// Run `command`, passing its stdout and stderr on to ours as `options`
+/// says. It's killed if it's still running after `timeout`.
+pub(crate) fn run_limited(
+    command: &mut process::Command,
+    options: CaptureOptions,
+    timeout: Option<time::Duration>,
+) -> io::Result<Captured> {
+    let CaptureOptions {
+        limit,
+        redirect_stdout,
+        collect_directives,
+        rebase,
+        output_log,
+        record,
+        cargo_messages,
+    } = options;
+    let mut child = command
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    let _tracked = interrupt::track(&child);
+    let stdout = output_log::tee(
+        output_log,
+        child.stdout.take().expect("stdout is piped"),
+        "build script",
+        "stdout",
+    );
+    let mut stdout = io::BufReader::new(stdout);
+    let stderr = output_log::tee(
+        output_log,
+        child.stderr.take().expect("stderr is piped"),
+        "build script",
+        "stderr",
+    );
+    let child = timeout::Watched::new(child, timeout);
+
+    let stderr_thread = thread::spawn(move || {
+        let mut stderr = Recording::new(stderr, record);
+        let dropped = forward(io::BufReader::new(&mut stderr), io::stderr(), limit)?;
+        Ok::<_, io::Error>((dropped, stderr.copy))
+    });
+    if cargo_messages {
+        show_cargo_messages(&mut stdout, io::stderr())?;
+    }
+    let mut stdout = Recording::new(stdout, record);
+    let output: Box<dyn Write> = if redirect_stdout {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    let mut output = StdoutTap::new(output, collect_directives, rebase);
+    let dropped = forward(io::BufReader::new(&mut stdout), &mut output, limit)?;
+    let (last_line, directives) = output.finish()?;
+    let (stderr_dropped, stderr) = stderr_thread.join().expect("stderr thread panicked")?;
+    if dropped + stderr_dropped > 0 {
+        println!(
+            "cargo:warning=Build script output exceeded its limit; dropped {} bytes of stdout and {} bytes of stderr",
+            dropped, stderr_dropped
+        );
+    }
+
+    // Its output has been passed on already, but a timeout's easier to make
+    // sense of with what it was doing at the time.
+    let status = child.wait().map_err(|e| match last_line.as_str() {
+        "" => e,
+        last_line => io::Error::new(
+            e.kind(),
+            format!("{}; the last thing it printed was: {}", e, last_line),
+        ),
+    })?;
+
+    Ok(Captured {
+        status,
+        last_line,
+        directives,
+        stdout: stdout.copy.unwrap_or_default(),
+        stderr: stderr.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_forward_keeps_head_tail_and_directives() {
+        let mut input = String::new();
+        for i in 0..100 {
+            input.push_str(&format!("line {:02}\n", i));
+            if i == 50 {
+                input.push_str("cargo:rustc-cfg=middle\n");
+            }
+        }
+
+        let mut output = Vec::new();
+        let dropped = forward(input.as_bytes(), &mut output, OutputLimit::new(16, 16)).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(dropped, 96 * 8);
+        assert_eq!(
+            output,
+            "line 00\nline 01\ncargo:rustc-cfg=middle\n\
+             [cargo-5730: 768 bytes of output dropped]\nline 98\nline 99\n"
+        );
+    }
+
+    #[test]
+    fn test_forward_within_limit_is_unchanged() {
+        let input = "hello\ncargo:warning=hi\nno trailing newline";
+        let mut output = Vec::new();
+        let dropped = forward(input.as_bytes(), &mut output, OutputLimit::new(1024, 0)).unwrap();
+        assert_eq!(dropped, 0);
+        assert_eq!(output, input.as_bytes());
+    }
+
+    #[test]
+    fn test_stdout_tap_last_line() {
+        let mut output = StdoutTap::new(Vec::new(), false, None);
+        output
+            .write_all(b"progress\n{\"answer\": 42}\ncargo:rustc-cfg=x\n\n")
+            .unwrap();
+        assert_eq!(output.finish().unwrap().0, "{\"answer\": 42}");
+
+        let mut output = StdoutTap::new(Vec::new(), false, None);
+        output.write_all(b"a\nno newline").unwrap();
+        assert_eq!(output.finish().unwrap().0, "no newline");
+    }
+
+    #[test]
+    fn test_stdout_tap_collects_directives() {
+        let mut passed_on = Vec::new();
+        let mut output = StdoutTap::new(&mut passed_on, true, None);
+        // Split mid-prefix, the way reads can arrive.
+        output.write_all(b"car").unwrap();
+        output.write_all(b"go:rustc-cfg=x\ncar\nhello\n").unwrap();
+        output.write_all(b"cargo::rustc-env=A=b").unwrap();
+        let (last_line, directives) = output.finish().unwrap();
+
+        assert_eq!(passed_on, b"car\nhello\n");
+        assert_eq!(last_line, "hello");
+        assert_eq!(directives, ["cargo:rustc-cfg=x", "cargo::rustc-env=A=b"]);
+    }
+
+    #[test]
+    fn test_stdout_tap_rebases_directives() {
+        let mut passed_on = Vec::new();
+        let rebase = Rebase {
+            base: path::Path::new("/src/build-script"),
+            staged: None,
+            label: "[build-script] ",
+        };
+        let mut output = StdoutTap::new(&mut passed_on, false, Some(rebase));
+        output
+            .write_all(
+                b"generating
+
+cargo:rerun",
+            )
+            .unwrap();
+        output
+            .write_all(
+                b"-if-changed=src/foo.c
+cargo:rustc-cfg=x
+car",
+            )
+            .unwrap();
+        output
+            .write_all(
+                b"ts
+",
+            )
+            .unwrap();
+        let (last_line, directives) = output.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(passed_on).unwrap(),
+            "[build-script] generating\n\n\
+             cargo:rerun-if-changed=/src/build-script/src/foo.c\n\
+             cargo:rustc-cfg=x\n\
+             [build-script] carts\n"
+        );
+        assert_eq!(last_line, "carts");
+        assert!(directives.is_empty());
+    }
+
+    #[test]
+    fn test_show_cargo_messages() {
+        let stdout = concat!(
+            r#"{"reason":"compiler-message","message":{"rendered":"warning: unused\n"}}"#,
+            "\n",
+            r#"{"reason":"compiler-artifact","executable":"/t/gen"}"#,
+            "\n",
+            r#"{"reason":"build-finished","success":true}"#,
+            "\n",
+            "{\"printed\": \"by the script\"}\n",
+        );
+        let mut stdout = io::BufReader::new(stdout.as_bytes());
+        let mut diagnostics = Vec::new();
+        show_cargo_messages(&mut stdout, &mut diagnostics).unwrap();
+        assert_eq!(diagnostics, b"warning: unused\n");
+        let mut rest = String::new();
+        stdout.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "{\"printed\": \"by the script\"}\n");
+    }
+}
@@ -0,0 +1,210 @@
+//! The cargo config the staged crate is built with, in its own `.cargo` dir,
+//! which cargo reads before anything else.
+
+use std::{fs, io, path};
+
+use crate::toml::{self, Table, Value};
+
+/// The staged crate's cargo config: whatever the build crate brought along in
+/// its `.cargo` dir, plus what this crate adds to it.
+pub(crate) struct StagedConfig {
+    path: path::PathBuf,
+    config: Value,
+    changed: bool,
+}
+
+impl StagedConfig {
+    pub(crate) fn read(staged_dir: &path::Path) -> Self {
+        let config_dir = staged_dir.join(".cargo");
+        // Cargo prefers a plain `config` when there are both.
+        let existing = ["config", "config.toml"]
+            .iter()
+            .map(|name| config_dir.join(name))
+            .find(|path| path.is_file());
+        let config = match &existing {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .unwrap_or_else(|e| panic!("Couldn't read {}: {}", path.display(), e));
+                toml::parse(&content)
+                    .unwrap_or_else(|e| panic!("Can't parse {}: {}", path.display(), e))
+            }
+            None => Value::Table(Table::new()),
+        };
+
+        StagedConfig {
+            path: existing.unwrap_or_else(|| config_dir.join("config.toml")),
+            config,
+            changed: false,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    pub(crate) fn get(&self, key: &[&str]) -> Option<&Value> {
+        self.config.get(key)
+    }
+
+    /// Set `key` to `value`, except for whatever the config already sets:
+    /// tables are merged, and existing values win.
+    pub(crate) fn fill(&mut self, key: &[&str], value: &Value) {
+        let mut table = &mut self.config;
+        for part in key {
+            let Value::Table(entries) = table else {
+                return;
+            };
+            table = entries
+                .entry((*part).to_owned())
+                .or_insert_with(|| Value::Table(Table::new()));
+        }
+        self.changed |= fill(table, value);
+    }
+
+    /// Pin the settings a cargo config above the staging dir could otherwise
+    /// slip in, where the build crate's own config doesn't set them. Cargo
+    /// has no way to unset the rest.
+    pub(crate) fn pin_defaults(&mut self) {
+        let no_flags = Value::Array(Vec::new());
+        self.fill(&["build", "rustflags"], &no_flags);
+        self.fill(&["build", "rustdocflags"], &no_flags);
+        // Relative to the staged crate, which the `.cargo` dir is in.
+        self.fill(
+            &["build", "target-dir"],
+            &Value::String("target".to_owned()),
+        );
+    }
+
+    /// Write the config back, if anything was added to it.
+    pub(crate) fn write(&self) {
+        if !self.changed {
+            return;
+        }
+        let config_dir = self.path.parent().expect("The config is in .cargo");
+        // Staging may have linked the build crate's own config back to the
+        // original, which mustn't be written through, so replace it.
+        let written = fs::create_dir_all(config_dir).and_then(|()| {
+            match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            fs::write(&self.path, self.config.to_document())
+        });
+        written.unwrap_or_else(|e| panic!("Couldn't write {}: {}", self.path.display(), e));
+    }
+}
+
+/// Merge `value` into `existing`, keeping what's already there. Returns
+/// whether anything was added.
+fn fill(existing: &mut Value, value: &Value) -> bool {
+    match (existing, value) {
+        (Value::Table(existing), Value::Table(table)) => {
+            let mut changed = false;
+            for (key, value) in table {
+                match existing.get_mut(key) {
+                    Some(existing) => changed |= fill(existing, value),
+                    None => {
+                        existing.insert(key.clone(), value.clone());
+                        changed = true;
+                    }
+                }
+            }
+            changed
+        }
+        // An empty table is only a placeholder left on the way to a key.
+        (existing @ Value::Table(_), value) if existing == &Value::Table(Table::new()) => {
+            *existing = value.clone();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The cargo configs in the directories above `dir`, nearest first.
+pub(crate) fn configs_above(dir: &path::Path) -> Vec<path::PathBuf> {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+    dir.ancestors()
+        .skip(1)
+        .flat_map(|ancestor| {
+            ["config", "config.toml"].map(|name| ancestor.join(".cargo").join(name))
+        })
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// The values of `keys` (dotted, like `net.git-fetch-with-cli`) in the cargo
+/// configs above `build_crate_dir`, which staging leaves behind, nearest
+/// first.
+pub(crate) fn inherited(build_crate_dir: &path::Path, keys: &[String]) -> Vec<(String, Value)> {
+    let mut configs = Vec::new();
+    for path in configs_above(build_crate_dir) {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| toml::parse(&content));
+        match parsed {
+            Ok(config) => configs.push(config),
+            Err(e) => println!(
+                "cargo:warning=Couldn't read cargo config {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    let mut values = Vec::new();
+    for key in keys {
+        let parts: Vec<&str> = key.split('.').collect();
+        for config in &configs {
+            if let Some(value) = config.get(&parts) {
+                values.push((key.clone(), value.clone()));
+            }
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_inherited_config() {
+        let root = env::temp_dir().join(format!("cargo-5730-cargo-config-{}", process::id()));
+        let build_crate = root.join("parent/build-script");
+        fs::create_dir_all(build_crate.join(".cargo")).unwrap();
+        fs::create_dir_all(root.join(".cargo")).unwrap();
+        fs::create_dir_all(root.join("parent/.cargo")).unwrap();
+        fs::write(
+            root.join(".cargo/config.toml"),
+            "[net]\ngit-fetch-with-cli = true\nretry = 5\n[http]\nproxy = \"outer\"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("parent/.cargo/config.toml"),
+            "[net]\nretry = 2\n[build]\ntarget = \"thumbv7em-none-eabi\"\n",
+        )
+        .unwrap();
+        fs::write(build_crate.join(".cargo/config.toml"), "[net]\nretry = 1\n").unwrap();
+
+        let keys = ["net".to_owned(), "http.proxy".to_owned()];
+        let mut config = StagedConfig::read(&build_crate);
+        for (key, value) in inherited(&build_crate, &keys) {
+            let key: Vec<&str> = key.split('.').collect();
+            config.fill(&key, &value);
+        }
+        config.pin_defaults();
+        config.write();
+
+        let written = toml::parse(&fs::read_to_string(config.path()).unwrap()).unwrap();
+        let expected = toml::parse(
+            "[net]\nretry = 1\ngit-fetch-with-cli = true\n[http]\nproxy = \"outer\"\n\
+             [build]\nrustflags = []\nrustdocflags = []\ntarget-dir = \"target\"\n",
+        )
+        .unwrap();
+        assert_eq!(written, expected);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
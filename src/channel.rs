@@ -0,0 +1,157 @@
+//! A two-way channel between the outer build script and the build crate's
+//! script while it runs.
+
+use std::io::{self, BufRead, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{env, net, panic, thread};
+
+/// Set for the build script to the address of the outer build's end of the
+/// channel, when `BuildCrateRunner::channel` is used.
+pub const CHANNEL_ENV: &str = "CARGO_5730_CHANNEL";
+
+/// One end of the channel between the outer build and the build script.
+///
+/// Messages are arbitrary strings, delivered whole and in order. The outer
+/// build gets its end in the handler passed to `BuildCrateRunner::channel`;
+/// the build script gets its end from `Channel::from_env`.
+pub struct Channel {
+    reader: io::BufReader<net::TcpStream>,
+    writer: net::TcpStream,
+}
+
+impl Channel {
+    fn new(stream: net::TcpStream) -> io::Result<Self> {
+        Ok(Channel {
+            reader: io::BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Connect to the outer build, from within the build script. Returns
+    /// `None` when the outer build didn't open a channel.
+    pub fn from_env() -> Option<Channel> {
+        let addr = env::var(CHANNEL_ENV).ok()?;
+        let stream = net::TcpStream::connect(&addr)
+            .unwrap_or_else(|e| panic!("Couldn't connect to channel at {}: {}", addr, e));
+        Some(Channel::new(stream).expect("Couldn't set up channel"))
+    }
+
+    /// Send a message to the other end.
+    pub fn send(&mut self, message: &str) -> io::Result<()> {
+        write!(self.writer, "{}\n{}", message.len(), message)?;
+        self.writer.flush()
+    }
+
+    /// Wait for the next message from the other end. Returns `None` once the
+    /// other end has gone away.
+    pub fn recv(&mut self) -> io::Result<Option<String>> {
+        let mut len = String::new();
+        if self.reader.read_line(&mut len)? == 0 {
+            return Ok(None);
+        }
+        let len: usize = len
+            .trim_end()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad message length"))?;
+        let mut message = vec![0; len];
+        self.reader.read_exact(&mut message)?;
+        String::from_utf8(message)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// What the outer build does with its end of the channel.
+pub(crate) type ChannelHandler = dyn Fn(&mut Channel) -> io::Result<()> + Send + Sync;
+
+/// The outer build's listening end, serving a single build script run.
+pub(crate) struct HostChannel {
+    addr: net::SocketAddr,
+    script_exited: Arc<AtomicBool>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+/// Start listening for the build script, handing its connection to `handler`
+/// on a separate thread.
+pub(crate) fn listen(handler: Arc<ChannelHandler>) -> io::Result<HostChannel> {
+    let listener = net::TcpListener::bind(("127.0.0.1", 0))?;
+    let addr = listener.local_addr()?;
+    let script_exited = Arc::new(AtomicBool::new(false));
+
+    let exited = script_exited.clone();
+    let thread = thread::spawn(move || {
+        let (stream, _) = listener.accept()?;
+        // `finish` connects to wake us up if the script never did.
+        if exited.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        handler(&mut Channel::new(stream)?)
+    });
+
+    Ok(HostChannel {
+        addr,
+        script_exited,
+        thread,
+    })
+}
+
+impl HostChannel {
+    pub(crate) fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the handler, once the build script has exited.
+    pub(crate) fn finish(self) {
+        self.script_exited.store(true, Ordering::SeqCst);
+        if !self.thread.is_finished() {
+            // Harmless if the script did connect: the handler is past
+            // `accept` and this connection is never looked at.
+            let _ = net::TcpStream::connect(self.addr);
+        }
+
+        match self.thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => panic!("Build script channel failed: {}", e),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_channel_round_trip() {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let host_replies = replies.clone();
+        let host = listen(Arc::new(move |channel: &mut Channel| {
+            channel.send("config\nwith a newline")?;
+            while let Some(message) = channel.recv()? {
+                host_replies.lock().unwrap().push(message);
+            }
+            Ok(())
+        }))
+        .unwrap();
+
+        let mut script = Channel::new(net::TcpStream::connect(host.addr()).unwrap()).unwrap();
+        assert_eq!(
+            script.recv().unwrap().as_deref(),
+            Some("config\nwith a newline")
+        );
+        script.send("progress: 50%").unwrap();
+        script.send("").unwrap();
+        drop(script);
+        host.finish();
+
+        assert_eq!(*replies.lock().unwrap(), ["progress: 50%", ""]);
+    }
+
+    #[test]
+    fn test_finish_without_connection() {
+        let host = listen(Arc::new(|_: &mut Channel| panic!("no script connected"))).unwrap();
+        host.finish();
+    }
+}
@@ -0,0 +1,171 @@
+//! Not holding up `cargo check` and rust-analyzer, which don't need the
+//! build crate's codegen, with a full isolated build and run.
+
+use std::{env, fs, io, path};
+
+use crate::fingerprint::Hasher;
+use crate::overrides::{env_flag, CHECK_ENV};
+
+/// What to do when the outer build only checks the code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CheckMode {
+    /// Build and run the build crate as usual.
+    #[default]
+    Run,
+    /// Don't run the build crate at all.
+    Skip,
+    /// Emit the directives and recreate the `OUT_DIR` files from the last
+    /// full run with the same sources, toolchain and options, falling back
+    /// to running the build crate if there was none. Every full run is
+    /// recorded for this.
+    Replay,
+}
+
+/// Whether the outer build is check-only. `CARGO_5730_CHECK` says so
+/// explicitly; otherwise rust-analyzer is recognized by its rustc wrapper, or
+/// by its target dir.
+pub(crate) fn is_check_only() -> bool {
+    if let Some(check) = env_flag(CHECK_ENV) {
+        return check;
+    }
+    if env::var_os("RA_RUSTC_WRAPPER").is_some() {
+        return true;
+    }
+    env::var_os("OUT_DIR").is_some_and(|out_dir| {
+        path::Path::new(&out_dir)
+            .components()
+            .any(|component| component.as_os_str() == "rust-analyzer")
+    })
+}
+
+/// Where the last full run of a build crate is recorded: the directives it
+/// emitted, and the files it generated under `out/`.
+pub(crate) struct Replay {
+    dir: path::PathBuf,
+}
+
+impl Replay {
+    /// The record for the build crate at `build_crate_dir` when built with
+    /// `cache_key`, kept under `staging_root`.
+    pub fn new(staging_root: &path::Path, build_crate_dir: &path::Path, cache_key: &str) -> Self {
+        let mut hasher = Hasher::new();
+        hasher.field("build_crate", build_crate_dir.to_string_lossy().as_bytes());
+        hasher.field("cache_key", cache_key.as_bytes());
+        Replay {
+            dir: staging_root.join("cargo-5730-replay").join(hasher.finish()),
+        }
+    }
+
+    /// Record `directives`, and the `files` the run generated under
+    /// `out_dir`.
+    pub fn save(
+        &self,
+        directives: &[String],
+        out_dir: Option<&path::Path>,
+        files: &[path::PathBuf],
+    ) {
+        let save = || -> io::Result<()> {
+            if self.dir.exists() {
+                fs::remove_dir_all(&self.dir)?;
+            }
+            let out = self.dir.join("out");
+            fs::create_dir_all(&out)?;
+            if let Some(out_dir) = out_dir {
+                for file in files {
+                    let relative = file.strip_prefix(out_dir).expect("generated under OUT_DIR");
+                    let saved = out.join(relative);
+                    if let Some(parent) = saved.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::copy(file, saved)?;
+                }
+            }
+            let mut content = directives.join("\n");
+            content.push('\n');
+            // Written last, so an interrupted save isn't mistaken for a record.
+            fs::write(self.dir.join("directives"), content)
+        };
+        if let Err(e) = save() {
+            println!(
+                "cargo:warning=Couldn't record the run for check builds in {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+
+    /// Recreate the recorded files in `out_dir` and return the recorded
+    /// directives, or `None` if nothing was recorded.
+    pub fn load(&self, out_dir: Option<&path::Path>) -> Option<Vec<String>> {
+        let content = fs::read_to_string(self.dir.join("directives")).ok()?;
+        if let Some(out_dir) = out_dir {
+            copy_tree(&self.dir.join("out"), out_dir).unwrap_or_else(|e| {
+                panic!(
+                    "Couldn't replay generated files into {}: {}",
+                    out_dir.display(),
+                    e
+                )
+            });
+        }
+        Some(
+            content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        )
+    }
+}
+
+fn copy_tree(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let to = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&to)?;
+            copy_tree(&entry.path(), &to)?;
+        } else {
+            fs::copy(entry.path(), to)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn test_replay() {
+        let root = env::temp_dir().join(format!("cargo-5730-replay-test-{}", process::id()));
+        let out_dir = root.join("out");
+        fs::create_dir_all(out_dir.join("nested")).unwrap();
+        fs::write(out_dir.join("nested").join("gen.rs"), "fn gen() {}").unwrap();
+
+        let build_crate = path::Path::new("/parent/build-script");
+        let replay = Replay::new(&root, build_crate, "key");
+        assert_eq!(replay.load(None), None);
+        let directives = vec!["cargo:rustc-cfg=generated".to_owned()];
+        replay.save(
+            &directives,
+            Some(&out_dir),
+            &[out_dir.join("nested").join("gen.rs")],
+        );
+
+        let check_out_dir = root.join("check-out");
+        fs::create_dir_all(&check_out_dir).unwrap();
+        assert_eq!(replay.load(Some(&check_out_dir)), Some(directives));
+        // A run with other sources or options isn't replayed.
+        assert_eq!(
+            Replay::new(&root, build_crate, "other key").load(None),
+            None
+        );
+        assert_eq!(
+            fs::read_to_string(check_out_dir.join("nested").join("gen.rs")).unwrap(),
+            "fn gen() {}"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
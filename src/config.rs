@@ -0,0 +1,393 @@
+//! Reading this crate's own options from the parent manifest's
+//! `[package.metadata.cargo-5730]`, so build.rs can be a single
+//! `run_configured()` call.
+
+use std::{env, fs, path, time};
+
+use crate::options::ErrorPolicy;
+use crate::overrides::{parse_duration, parse_profile, parse_verbosity};
+use crate::toml::Value;
+use crate::{
+    BuildCrateRunner, CheckMode, FeatureForwarding, IsolationMode, RunStrategy, StagingStrategy,
+    SymlinkPolicy, DEFAULT_BUILD_CRATE_DIR,
+};
+
+/// The parent manifest's `[package.metadata.<table>]`, if it has one. A
+/// manifest this crate can't parse is warned about and treated as having
+/// none.
+pub(crate) fn parent_metadata(table: &str) -> Option<Value> {
+    let manifest_dir =
+        env::var_os("CARGO_MANIFEST_DIR").expect("Can't get CARGO_MANIFEST_DIR from env");
+    let manifest = path::Path::new(&manifest_dir).join("Cargo.toml");
+    println!("cargo:rerun-if-changed={}", manifest.display());
+
+    let content = fs::read_to_string(&manifest)
+        .unwrap_or_else(|e| panic!("Couldn't read {}: {}", manifest.display(), e));
+    match crate::toml::parse(&content) {
+        Ok(manifest) => manifest.get(&["package", "metadata", table]).cloned(),
+        Err(e) => {
+            println!(
+                "cargo:warning=Couldn't read [package.metadata.{}] from {}: {}",
+                table,
+                manifest.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// A runner set up from `table`, with relative paths taken from
+/// `manifest_dir`. Keys this crate doesn't know are left for the build
+/// script, which gets the whole table.
+pub(crate) fn runner(table: &Value, manifest_dir: &path::Path) -> BuildCrateRunner {
+    let option = Options { table };
+
+    let build_crate = option
+        .string("build-crate")
+        .unwrap_or(DEFAULT_BUILD_CRATE_DIR);
+    let mut runner = BuildCrateRunner::new(manifest_dir.join(build_crate));
+
+    if let Some(profile) = option.choice("profile", parse_profile, "debug, release or outer") {
+        runner = runner.profile(profile);
+    }
+    if let Some(verbosity) = option.choice(
+        "verbosity",
+        parse_verbosity,
+        "quiet, normal, verbose or very-verbose",
+    ) {
+        runner = runner.verbosity(verbosity);
+    }
+    if let Some(bins) = option.strings("bins") {
+        runner = runner.bins(bins);
+    }
+    if let Some(strategy) = option.choice("staging", parse_staging, "copy, symlink-overlay or link")
+    {
+        runner = runner.staging_strategy(strategy);
+    }
+    if let Some(isolation) = option.choice("isolation", parse_isolation, "always, auto or in-place")
+    {
+        runner = runner.isolation(isolation);
+    }
+    if let Some(policy) = option.choice(
+        "error-policy",
+        parse_error_policy,
+        "fail-fast or collect-all",
+    ) {
+        runner = runner.error_policy(policy);
+    }
+    if let Some(check_mode) = option.choice("check", parse_check_mode, "run, skip or replay") {
+        runner = runner.check_mode(check_mode);
+    }
+    if let Some(staging_root) = option.string("staging-root") {
+        runner = runner.staging_root(manifest_dir.join(staging_root));
+    }
+    if let Some(policy) = option.choice("staging-symlinks", parse_symlinks, "follow or preserve") {
+        runner = runner.staging_symlinks(policy);
+    }
+    if let Some(includes) = option.strings("staging-includes") {
+        runner = runner.staging_includes(includes);
+    }
+    if let Some(excludes) = option.strings("staging-excludes") {
+        runner = runner.staging_excludes(excludes);
+    }
+    if let Some(build_cache) = option.string("build-cache") {
+        runner = runner.build_cache(manifest_dir.join(build_cache));
+    }
+    if let Some(target_dir) = option.string("target-dir") {
+        runner = runner.target_dir(manifest_dir.join(target_dir));
+    }
+    if let Some(cargo_home) = option.string("cargo-home") {
+        runner = runner.cargo_home(manifest_dir.join(cargo_home));
+    }
+    if let Some(edition) = option.string("edition") {
+        runner = runner.edition(edition);
+    }
+    if let Some(offline) = option.flag("offline") {
+        runner = runner.offline(offline);
+    }
+    if let Some(locked) = option.flag("locked") {
+        runner = runner.locked(locked);
+    }
+    if let Some(vendor_dir) = option.string("vendor-dir") {
+        runner = runner.vendor_dir(manifest_dir.join(vendor_dir));
+    }
+    if let Some(keys) = option.strings("inherit-config") {
+        runner = runner.inherit_config(keys);
+    }
+    if let Some(age) = option.choice(
+        "clean-stale-after",
+        parse_stale_after,
+        "a duration like 12h or 7d, or never",
+    ) {
+        runner = runner.clean_stale_after(age);
+    }
+    if let Some(persistent) = option.flag("persistent-staging") {
+        runner = runner.persistent_staging(persistent);
+    }
+    if let Some(keep) = option.flag("keep") {
+        runner = runner.keep(keep);
+    }
+    if let Some(keep_on_failure) = option.flag("keep-on-failure") {
+        runner = runner.keep_on_failure(keep_on_failure);
+    }
+    if let Some(preflight) = option.flag("preflight") {
+        runner = runner.preflight(preflight);
+    }
+    if let Some(features) = option.strings("features") {
+        runner = runner.features(features);
+    }
+    if let Some(no_default_features) = option.flag("no-default-features") {
+        runner = runner.no_default_features(no_default_features);
+    }
+    if let Some(all_features) = option.flag("all-features") {
+        runner = runner.all_features(all_features);
+    }
+    if let Some(forwarding) = option.choice(
+        "forward-features",
+        parse_feature_forwarding,
+        "off, features, env or both",
+    ) {
+        runner = runner.forward_features(forwarding);
+    }
+    if let Some(own_out_dir) = option.flag("own-out-dir") {
+        runner = runner.own_out_dir(own_out_dir);
+    }
+    if let Some(export) = option.flag("export-executable") {
+        runner = runner.export_executable(export);
+    }
+    if let Some(patterns) = option.strings("pass-env") {
+        runner = runner.pass_env(patterns);
+    }
+    if let Some(patterns) = option.strings("deny-env") {
+        runner = runner.deny_env(patterns);
+    }
+    if let Some(forward) = option.flag("forward-rustflags") {
+        runner = runner.forward_rustflags(forward);
+    }
+    if let Some(flags) = option.strings("rustflags") {
+        runner = runner.rustflags(flags);
+    }
+    if let Some(args) = option.strings("cargo-args") {
+        runner = runner.cargo_args(args);
+    }
+    if let Some(lint_args) = option.strings("clippy") {
+        runner = runner.clippy_gate(lint_args);
+    }
+    if let Some(stub) = option.strings("docs-rs-stub") {
+        runner = runner.docs_rs_stub(stub);
+    }
+    if let Some(timeout) = option.choice(
+        "compile-timeout",
+        parse_duration,
+        "a duration like 90s or 10m",
+    ) {
+        runner = runner.compile_timeout(timeout);
+    }
+    if let Some(timeout) =
+        option.choice("run-timeout", parse_duration, "a duration like 90s or 10m")
+    {
+        runner = runner.run_timeout(timeout);
+    }
+    if let Some(strategy) = option.choice(
+        "run-strategy",
+        parse_run_strategy,
+        "build-then-exec or cargo-run",
+    ) {
+        runner = runner.run_strategy(strategy);
+    }
+
+    runner
+}
+
+struct Options<'a> {
+    table: &'a Value,
+}
+
+impl<'a> Options<'a> {
+    fn invalid(&self, key: &str, expected: &str) -> ! {
+        panic!(
+            "Invalid `{}` in [package.metadata.{}]: expected {}",
+            key,
+            crate::DEFAULT_METADATA_TABLE,
+            expected
+        )
+    }
+
+    fn string(&self, key: &str) -> Option<&'a str> {
+        let value = self.table.get(&[key])?;
+        Some(
+            value
+                .as_str()
+                .unwrap_or_else(|| self.invalid(key, "a string")),
+        )
+    }
+
+    fn strings(&self, key: &str) -> Option<Vec<&'a str>> {
+        match self.table.get(&[key])? {
+            Value::Array(items) => Some(
+                items
+                    .iter()
+                    .map(|item| {
+                        item.as_str()
+                            .unwrap_or_else(|| self.invalid(key, "an array of strings"))
+                    })
+                    .collect(),
+            ),
+            _ => self.invalid(key, "an array of strings"),
+        }
+    }
+
+    fn flag(&self, key: &str) -> Option<bool> {
+        match self.table.get(&[key])? {
+            Value::Boolean(flag) => Some(*flag),
+            _ => self.invalid(key, "true or false"),
+        }
+    }
+
+    fn choice<T>(&self, key: &str, parse: fn(&str) -> Option<T>, expected: &str) -> Option<T> {
+        let value = self.string(key)?;
+        Some(parse(value).unwrap_or_else(|| self.invalid(key, expected)))
+    }
+}
+
+fn parse_stale_after(value: &str) -> Option<Option<time::Duration>> {
+    match value {
+        "never" => Some(None),
+        _ => parse_duration(value).map(Some),
+    }
+}
+
+fn parse_symlinks(value: &str) -> Option<SymlinkPolicy> {
+    match value {
+        "follow" => Some(SymlinkPolicy::Follow),
+        "preserve" => Some(SymlinkPolicy::Preserve),
+        _ => None,
+    }
+}
+
+fn parse_staging(value: &str) -> Option<StagingStrategy> {
+    match value {
+        "copy" => Some(StagingStrategy::Copy),
+        "symlink-overlay" => Some(StagingStrategy::SymlinkOverlay),
+        "link" => Some(StagingStrategy::Link),
+        _ => None,
+    }
+}
+
+fn parse_run_strategy(value: &str) -> Option<RunStrategy> {
+    match value {
+        "build-then-exec" => Some(RunStrategy::BuildThenExec),
+        "cargo-run" => Some(RunStrategy::CargoRun),
+        _ => None,
+    }
+}
+
+fn parse_isolation(value: &str) -> Option<IsolationMode> {
+    match value {
+        "always" => Some(IsolationMode::Always),
+        "auto" => Some(IsolationMode::Auto),
+        "in-place" => Some(IsolationMode::InPlace),
+        _ => None,
+    }
+}
+
+fn parse_check_mode(value: &str) -> Option<CheckMode> {
+    match value {
+        "run" => Some(CheckMode::Run),
+        "skip" => Some(CheckMode::Skip),
+        "replay" => Some(CheckMode::Replay),
+        _ => None,
+    }
+}
+
+fn parse_feature_forwarding(value: &str) -> Option<FeatureForwarding> {
+    match value {
+        "off" => Some(FeatureForwarding::Off),
+        "features" => Some(FeatureForwarding::Features),
+        "env" => Some(FeatureForwarding::Env),
+        "both" => Some(FeatureForwarding::Both),
+        _ => None,
+    }
+}
+
+fn parse_error_policy(value: &str) -> Option<ErrorPolicy> {
+    match value {
+        "fail-fast" => Some(ErrorPolicy::FailFast),
+        "collect-all" => Some(ErrorPolicy::CollectAll),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Profile, Verbosity};
+
+    #[test]
+    fn test_runner_from_metadata() {
+        let manifest = crate::toml::parse(
+            r#"
+[package.metadata.cargo-5730]
+build-crate = "generator"
+profile = "release"
+verbosity = "quiet"
+staging = "symlink-overlay"
+isolation = "auto"
+offline = true
+features = ["fast"]
+no-default-features = true
+forward-features = "env"
+pass-env = ["NIX_*"]
+cargo-args = ["--locked"]
+docs-rs-stub = ["cargo:rustc-cfg=stub"]
+run-timeout = "10m"
+run-strategy = "build-then-exec"
+bins = ["codegen", "pack-assets"]
+export-executable = true
+bindings = ["foo.h"]
+"#,
+        )
+        .unwrap();
+        let table = manifest
+            .get(&["package", "metadata", "cargo-5730"])
+            .unwrap();
+        let runner = runner(table, path::Path::new("/parent"));
+
+        assert_eq!(runner.build_crate_src, path::Path::new("/parent/generator"));
+        assert_eq!(runner.settings.profile, Profile::Release);
+        assert_eq!(runner.settings.verbosity, Verbosity::Quiet);
+        assert_eq!(runner.staging_strategy, StagingStrategy::SymlinkOverlay);
+        assert_eq!(runner.isolation, IsolationMode::Auto);
+        assert!(runner.settings.offline);
+        assert!(!runner.settings.keep);
+        assert_eq!(runner.settings.extra_cargo_args, ["--locked"]);
+        assert_eq!(runner.feature_forwarding, FeatureForwarding::Env);
+        assert_eq!(runner.pass_env, ["NIX_*"]);
+        assert_eq!(
+            runner.settings.cargo_args(),
+            [
+                "-q",
+                "--release",
+                "--offline",
+                "--features",
+                "fast",
+                "--no-default-features",
+                "--locked"
+            ]
+        );
+        assert_eq!(runner.docs_rs_stub, ["cargo:rustc-cfg=stub"]);
+        assert_eq!(runner.run_timeout, Some(time::Duration::from_secs(600)));
+        assert_eq!(runner.compile_timeout, None);
+        assert_eq!(runner.run_strategy, RunStrategy::BuildThenExec);
+        assert_eq!(runner.bins, ["codegen", "pack-assets"]);
+        assert!(runner.export_executable);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid `profile` in [package.metadata.cargo-5730]")]
+    fn test_invalid_option() {
+        let table = crate::toml::parse("profile = \"bench\"\n").unwrap();
+        runner(&table, path::Path::new("/parent"));
+    }
+}
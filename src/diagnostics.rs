@@ -0,0 +1,135 @@
+//! Extra context gathered when a phase fails, so the error says more than
+//! "cargo exited with 101".
+
+use std::{any, ffi, fs, path, process};
+
+use crate::Toolchain;
+
+/// A bundle of named sections of diagnostic output.
+#[derive(Debug, Default)]
+pub(crate) struct Diagnostics {
+    sections: Vec<(String, String)>,
+}
+
+impl Diagnostics {
+    pub fn add<S: Into<String>>(&mut self, title: &str, body: S) {
+        self.sections.push((title.to_owned(), body.into()));
+    }
+
+    pub fn render(&self) -> String {
+        let mut rendered = String::new();
+        for (title, body) in &self.sections {
+            rendered.push_str(&format!("--- {} ---\n{}\n", title, body.trim_end()));
+        }
+        rendered
+    }
+}
+
+/// Gather what's needed to make sense of a failed compile or run of the
+/// build crate in `crate_dir`.
+pub(crate) fn for_failed_build(
+    crate_dir: &path::Path,
+    cargo: &ffi::OsStr,
+    cargo_args: &[&str],
+    forwarded_env: &[(String, ffi::OsString)],
+    toolchain: &Toolchain,
+) -> Diagnostics {
+    let mut diagnostics = Diagnostics::default();
+
+    let manifest = crate_dir.join("Cargo.toml");
+    diagnostics.add(
+        "Staged manifest",
+        fs::read_to_string(&manifest)
+            .unwrap_or_else(|e| format!("couldn't read {}: {}", manifest.display(), e)),
+    );
+    diagnostics.add(
+        "Inner environment",
+        forwarded_env
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    );
+    diagnostics.add(
+        "Cargo",
+        format!("{} build {}", cargo.to_string_lossy(), cargo_args.join(" ")),
+    );
+    diagnostics.add(
+        "Dependency tree",
+        dependency_tree(crate_dir, cargo, cargo_args, forwarded_env),
+    );
+    diagnostics.add(
+        "Toolchain",
+        format!(
+            "{}\n{}",
+            toolchain
+                .cargo_version
+                .as_deref()
+                .unwrap_or("unknown cargo"),
+            toolchain
+                .rustc_version
+                .as_deref()
+                .unwrap_or("unknown rustc")
+        ),
+    );
+
+    diagnostics
+}
+
+/// What `cargo tree` says the isolated build of `crate_dir` resolves to, or
+/// why it couldn't say.
+pub(crate) fn dependency_tree(
+    crate_dir: &path::Path,
+    cargo: &ffi::OsStr,
+    cargo_args: &[&str],
+    forwarded_env: &[(String, ffi::OsString)],
+) -> String {
+    let mut command = process::Command::new(cargo);
+    command
+        .arg("tree")
+        .current_dir(crate_dir)
+        .env_clear()
+        .envs(forwarded_env.iter().cloned());
+    if cargo_args.contains(&"--offline") {
+        command.arg("--offline");
+    }
+
+    match command.output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        Ok(output) => format!(
+            "cargo tree failed ({}):\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => format!("couldn't run cargo tree: {}", e),
+    }
+}
+
+/// The message a panic was raised with, as far as it can be recovered.
+pub(crate) fn panic_message(payload: &(dyn any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown error"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.add("Staged manifest", "[package]\nname = \"x\"\n");
+        diagnostics.add("Cargo arguments", "build -vv");
+        assert_eq!(
+            diagnostics.render(),
+            "--- Staged manifest ---\n[package]\nname = \"x\"\n--- Cargo arguments ---\nbuild -vv\n"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Checking and merging the build script's `cargo:` directives, when they
+//! don't go straight from its stdout to cargo.
+
+use std::collections::{HashMap, HashSet};
+use std::{fs, io, path};
+
+/// Set for the build script to the file it should write its directives to,
+/// when using `DirectiveChannel::File`.
+pub const DIRECTIVES_FILE_ENV: &str = "CARGO_5730_DIRECTIVES_FILE";
+
+/// How the build script passes `cargo:` directives to the outer build.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirectiveChannel {
+    /// Printed to stdout, as by any build script.
+    #[default]
+    Stdout,
+    /// Written one per line to the file named by `CARGO_5730_DIRECTIVES_FILE`.
+    /// Once the script has exited they are checked and re-emitted. The
+    /// script's stdout is sent to stderr instead, so nothing it prints can be
+    /// mistaken for a directive.
+    File,
+}
+
+/// Check that `line` looks like a `cargo:` or `cargo::` directive.
+pub(crate) fn validate(line: &str) -> Result<(), String> {
+    let rest = line
+        .strip_prefix("cargo::")
+        .or_else(|| line.strip_prefix("cargo:"))
+        .ok_or("doesn't start with `cargo:`")?;
+    let (key, _) = rest.split_once('=').ok_or("has no `=`")?;
+    if key.is_empty() {
+        return Err("has an empty key".to_owned());
+    }
+    if !key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("has an invalid key `{}`", key));
+    }
+    Ok(())
+}
+
+/// Read and check the directives the build script wrote to `path`. A script
+/// that wrote no file emitted no directives.
+pub(crate) fn read(path: &path::Path) -> Vec<String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => panic!("Couldn't read directives from {}: {}", path.display(), e),
+    };
+
+    let lines: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+    let invalid: Vec<String> = lines
+        .iter()
+        .filter_map(|line| validate(line).err().map(|e| format!("  {:?} {}", line, e)))
+        .collect();
+    assert!(
+        invalid.is_empty(),
+        "Build script wrote invalid directives to {}:\n{}",
+        path.display(),
+        invalid.join("\n")
+    );
+
+    lines.into_iter().map(str::to_owned).collect()
+}
+
+/// `line` with a relative path in it made absolute against `base`, the build
+/// crate's source dir. Cargo would take it as relative to the parent crate
+/// instead. A script run in a staged copy, `staged`, resolved the path there;
+/// it's mapped back to `base` if it's there too, and left in the copy if not.
+/// Of the directives, only `rustc-link-search` and `rerun-if-changed` take
+/// paths.
+pub(crate) fn rebase(line: &str, base: &path::Path, staged: Option<&path::Path>) -> String {
+    let body = body(line);
+    let prefix = &line[..line.len() - body.len()];
+    let (key, value) = match body.split_once('=') {
+        Some(key_value) => key_value,
+        None => return line.to_owned(),
+    };
+    let (kind, path) = match (key, value.split_once('=')) {
+        ("rustc-link-search", Some((kind, path))) => (format!("{}=", kind), path),
+        ("rustc-link-search", None) | ("rerun-if-changed", _) => (String::new(), value),
+        _ => return line.to_owned(),
+    };
+    if path.is_empty() || path::Path::new(path).is_absolute() {
+        return line.to_owned();
+    }
+    let resolved = match staged {
+        Some(staged) if !base.join(path).exists() => staged.join(path),
+        _ => base.join(path),
+    };
+    format!("{}{}={}{}", prefix, key, kind, resolved.display())
+}
+
+/// The directive without its `cargo:` or `cargo::` prefix, so the two
+/// spellings compare equal.
+fn body(line: &str) -> &str {
+    line.strip_prefix("cargo::")
+        .or_else(|| line.strip_prefix("cargo:"))
+        .unwrap_or(line)
+}
+
+/// Directives from several build crates, merged so each is emitted once.
+#[derive(Debug, Default)]
+pub(crate) struct Merged {
+    lines: Vec<String>,
+    seen: HashSet<String>,
+    /// `rustc-env` variable to its value and the build crate that set it.
+    env: HashMap<String, (String, String)>,
+    conflicts: Vec<String>,
+}
+
+impl Merged {
+    /// Add a directive emitted by `build_crate`. Repeats of a directive that
+    /// was already added are dropped.
+    pub fn add(&mut self, build_crate: &str, line: &str) {
+        let body = body(line);
+        if !self.seen.insert(body.to_owned()) {
+            return;
+        }
+
+        if let Some((var, value)) = body
+            .strip_prefix("rustc-env=")
+            .and_then(|env| env.split_once('='))
+        {
+            match self.env.get(var) {
+                Some((other_value, other_crate)) if other_value != value => {
+                    self.conflicts.push(format!(
+                        "{}: {} sets {:?}, {} sets {:?}",
+                        var, other_crate, other_value, build_crate, value
+                    ));
+                    return;
+                }
+                Some(_) => {}
+                None => {
+                    self.env
+                        .insert(var.to_owned(), (value.to_owned(), build_crate.to_owned()));
+                }
+            }
+        }
+
+        self.lines.push(line.to_owned());
+    }
+
+    /// The merged directives, in the order they were first added, or the
+    /// `rustc-env` variables the build crates disagree on.
+    pub fn finish(self) -> Result<Vec<String>, Vec<String>> {
+        if self.conflicts.is_empty() {
+            Ok(self.lines)
+        } else {
+            Err(self.conflicts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("cargo:rustc-cfg=foo").is_ok());
+        assert!(validate("cargo::rustc-env=A=b c").is_ok());
+        assert!(validate("cargo:my_key=value").is_ok());
+        assert!(validate("rustc-cfg=foo").is_err());
+        assert!(validate("cargo:rustc-cfg").is_err());
+        assert!(validate("cargo:=foo").is_err());
+        assert!(validate("cargo:bad key=foo").is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut merged = Merged::default();
+        merged.add("a", "cargo:rustc-link-search=native=/opt/lib");
+        merged.add("a", "cargo:rustc-link-lib=ssl");
+        merged.add("a", "cargo:rustc-env=VERSION=1");
+        merged.add("b", "cargo::rustc-link-search=native=/opt/lib");
+        merged.add("b", "cargo:rustc-link-lib=ssl");
+        merged.add("b", "cargo:rustc-env=VERSION=1");
+        merged.add("b", "cargo:rustc-link-lib=z");
+        assert_eq!(
+            merged.finish().unwrap(),
+            [
+                "cargo:rustc-link-search=native=/opt/lib",
+                "cargo:rustc-link-lib=ssl",
+                "cargo:rustc-env=VERSION=1",
+                "cargo:rustc-link-lib=z",
+            ]
+        );
+
+        let mut merged = Merged::default();
+        merged.add("a", "cargo:rustc-env=VERSION=1");
+        merged.add("b", "cargo:rustc-env=VERSION=2");
+        assert_eq!(
+            merged.finish().unwrap_err(),
+            [r#"VERSION: a sets "1", b sets "2""#]
+        );
+    }
+
+    #[test]
+    fn test_rebase() {
+        let base = path::Path::new("/src/build-script");
+        assert_eq!(
+            rebase("cargo:rustc-link-search=native=lib", base, None),
+            "cargo:rustc-link-search=native=/src/build-script/lib"
+        );
+        assert_eq!(
+            rebase("cargo::rustc-link-search=lib", base, None),
+            "cargo::rustc-link-search=/src/build-script/lib"
+        );
+        assert_eq!(
+            rebase("cargo:rerun-if-changed=src/foo.c", base, None),
+            "cargo:rerun-if-changed=/src/build-script/src/foo.c"
+        );
+        assert_eq!(
+            rebase("cargo:rerun-if-changed=/abs/foo.c", base, None),
+            "cargo:rerun-if-changed=/abs/foo.c"
+        );
+        assert_eq!(
+            rebase("cargo:rustc-cfg=lib", base, None),
+            "cargo:rustc-cfg=lib"
+        );
+
+        // Run in a staged copy, only what the script made there stays there.
+        let root = env::temp_dir().join(format!("cargo-5730-rebase-test-{}", process::id()));
+        let (base, staged) = (root.join("src"), root.join("staged"));
+        fs::create_dir_all(base.join("lib")).unwrap();
+        fs::create_dir_all(staged.join("lib")).unwrap();
+        fs::create_dir_all(staged.join("generated")).unwrap();
+        assert_eq!(
+            rebase("cargo:rustc-link-search=lib", &base, Some(&staged)),
+            format!("cargo:rustc-link-search={}", base.join("lib").display())
+        );
+        assert_eq!(
+            rebase("cargo:rustc-link-search=generated", &base, Some(&staged)),
+            format!(
+                "cargo:rustc-link-search={}",
+                staged.join("generated").display()
+            )
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! Emitting directives from the outer build script without hand-writing
+//! `println!("cargo:...")` lines.
+//!
+//! Everything is emitted in the single-colon `cargo:` syntax, which every
+//! cargo understands, except `error`, which only exists as `cargo::error`.
+//! Values that can't be represented in a directive are rejected rather than
+//! silently producing a different one.
+
+use std::path;
+
+use crate::directives;
+
+/// Emit `line`, written in either the `cargo:` or the `cargo::` syntax, in
+/// the syntax every cargo understands. `cargo::metadata=KEY=VALUE` becomes
+/// `cargo:KEY=VALUE`.
+pub fn emit(line: &str) {
+    println!("{}", translate(line));
+}
+
+/// `cfg(name)` for the parent crate: `cargo:rustc-cfg=name`.
+pub fn emit_cfg(name: &str) {
+    println!("{}", cfg(name, None));
+}
+
+/// `cfg(name = "value")` for the parent crate, with the value escaped.
+pub fn emit_cfg_value(name: &str, value: &str) {
+    println!("{}", cfg(name, Some(value)));
+}
+
+/// Add `path` to the parent crate's library search path.
+pub fn emit_link_search<P: AsRef<path::Path>>(path: P) {
+    println!(
+        "{}",
+        directive("rustc-link-search", &path_value(path.as_ref()))
+    );
+}
+
+/// Link the parent crate against `name`, which may be prefixed with a kind
+/// such as `static=`.
+pub fn emit_link_lib(name: &str) {
+    println!("{}", directive("rustc-link-lib", name));
+}
+
+/// Set `var` to `value` when compiling the parent crate.
+pub fn emit_rustc_env(var: &str, value: &str) {
+    assert!(
+        !var.is_empty() && !var.contains('='),
+        "Invalid environment variable name {:?}",
+        var
+    );
+    println!("{}", directive("rustc-env", &format!("{}={}", var, value)));
+}
+
+/// Rerun the outer build script when `path` changes.
+pub fn emit_rerun_if_changed<P: AsRef<path::Path>>(path: P) {
+    println!(
+        "{}",
+        directive("rerun-if-changed", &path_value(path.as_ref()))
+    );
+}
+
+/// Rerun the outer build script when `var` changes.
+pub fn emit_rerun_if_env_changed(var: &str) {
+    println!("{}", directive("rerun-if-env-changed", var));
+}
+
+/// Show `message` as a warning. Each line of a multi-line message becomes a
+/// warning of its own, since a directive can't span lines.
+pub fn emit_warning(message: &str) {
+    for line in message.lines() {
+        println!("cargo:warning={}", line);
+    }
+}
+
+/// Pass `key` and `value` on to crates depending on the parent crate, as
+/// `DEP_<LINKS>_<KEY>`.
+pub fn emit_metadata(key: &str, value: &str) {
+    println!(
+        "{}",
+        translate(&format!("cargo::metadata={}={}", key, value))
+    );
+}
+
+fn directive(key: &str, value: &str) -> String {
+    assert!(
+        !value.contains('\n') && !value.contains('\r'),
+        "A cargo:{} value can't contain a line break: {:?}",
+        key,
+        value
+    );
+    format!("cargo:{}={}", key, value)
+}
+
+fn path_value(path: &path::Path) -> String {
+    path.to_str()
+        .unwrap_or_else(|| panic!("Path isn't valid UTF-8: {}", path.display()))
+        .to_owned()
+}
+
+fn cfg(name: &str, value: Option<&str>) -> String {
+    let is_ident = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    assert!(is_ident, "Invalid cfg name {:?}", name);
+
+    match value {
+        None => directive("rustc-cfg", name),
+        Some(value) => {
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+            directive("rustc-cfg", &format!("{}=\"{}\"", name, escaped))
+        }
+    }
+}
+
+fn translate(line: &str) -> String {
+    let validate = |line: &str| {
+        assert!(
+            !line.contains('\n') && !line.contains('\r'),
+            "A directive can't contain a line break: {:?}",
+            line
+        );
+        if let Err(e) = directives::validate(line) {
+            panic!("Invalid directive {:?}: {}", line, e);
+        }
+    };
+    validate(line);
+    match line.strip_prefix("cargo::") {
+        Some(body) if body.starts_with("error=") => line.to_owned(),
+        Some(body) => {
+            let body = body.strip_prefix("metadata=").unwrap_or(body);
+            let translated = format!("cargo:{}", body);
+            validate(&translated);
+            translated
+        }
+        None => line.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate("cargo::rustc-cfg=foo"), "cargo:rustc-cfg=foo");
+        assert_eq!(translate("cargo:rustc-cfg=foo"), "cargo:rustc-cfg=foo");
+        assert_eq!(
+            translate("cargo::metadata=include=/opt/include"),
+            "cargo:include=/opt/include"
+        );
+        assert_eq!(translate("cargo::error=no"), "cargo::error=no");
+    }
+
+    #[test]
+    fn test_cfg() {
+        assert_eq!(cfg("has_foo", None), "cargo:rustc-cfg=has_foo");
+        assert_eq!(
+            cfg("backend", Some(r#"say "hi" \ bye"#)),
+            r#"cargo:rustc-cfg=backend="say \"hi\" \\ bye""#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid cfg name")]
+    fn test_cfg_name() {
+        cfg("not-an-ident", None);
+    }
+}
@@ -0,0 +1,155 @@
+//! Failures as values, for build scripts that would rather report them their
+//! own way, or fall back to something else, than panic.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::{error, fmt, panic};
+
+use crate::diagnostics::panic_message;
+
+/// What went wrong, and in which part of the run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The environment or configuration was unusable, or preparing to
+    /// compile the staged crate failed, outside of copying, rewriting,
+    /// compiling and running.
+    Setup(String),
+    /// The build crate couldn't be copied into its staging dir.
+    Copy(String),
+    /// The staged `Cargo.toml` couldn't be rewritten.
+    ManifestRewrite(String),
+    /// The build crate didn't compile, or didn't pass a gate checked before
+    /// it's run.
+    Compile(String),
+    /// The build script failed, or what it left behind couldn't be handled.
+    Run(String),
+}
+
+impl Error {
+    /// The underlying message, without saying which part of the run failed.
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Setup(message)
+            | Error::Copy(message)
+            | Error::ManifestRewrite(message)
+            | Error::Compile(message)
+            | Error::Run(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self {
+            Error::Setup(_) => "Couldn't set up the build crate",
+            Error::Copy(_) => "Couldn't copy the build crate",
+            Error::ManifestRewrite(_) => "Couldn't rewrite the build crate's Cargo.toml",
+            Error::Compile(_) => "Couldn't compile the build crate",
+            Error::Run(_) => "The build script failed",
+        };
+        write!(f, "{}: {}", what, self.message())
+    }
+}
+
+impl error::Error for Error {}
+
+/// The part of a run in progress, to tell which `Error` a panic becomes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Setup,
+    Copy,
+    ManifestRewrite,
+    Compile,
+    Run,
+}
+
+thread_local! {
+    static PHASE: Cell<Phase> = const { Cell::new(Phase::Setup) };
+    /// Whether `catch` is running on this thread, so a panic in the caller's
+    /// own code is to be marked as such.
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Note that the run on this thread has moved on to `phase`.
+pub(crate) fn enter(phase: Phase) {
+    PHASE.with(|current| current.set(phase));
+}
+
+/// A panic raised in the caller's own code, which `catch` passes on as it
+/// was rather than turning it into an `Error`.
+struct ForeignPanic(Box<dyn Any + Send>);
+
+/// Run `f`, code the caller gave us, so that a panic in it is passed on by
+/// `catch` untouched.
+pub(crate) fn foreign<T, F: FnOnce() -> T>(f: F) -> T {
+    let catching = CATCHING.with(|current| current.replace(false));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
+    CATCHING.with(|current| current.set(catching));
+    result.unwrap_or_else(|payload| match catching {
+        true => panic::resume_unwind(Box::new(ForeignPanic(payload))),
+        false => panic::resume_unwind(payload),
+    })
+}
+
+/// Whether `payload` is from a panic in the caller's own code, which should
+/// be passed on untouched.
+pub(crate) fn is_foreign(payload: &(dyn Any + Send)) -> bool {
+    payload.is::<ForeignPanic>()
+}
+
+/// Run `f`, turning a panic into the `Error` for the phase it happened in.
+/// The panic is still printed by the panic hook, whichever is installed; the
+/// process-wide hook isn't ours to swap out. A panic in the caller's own
+/// code, run with `foreign`, is passed on instead.
+pub(crate) fn catch<T, F: FnOnce() -> T>(f: F) -> Result<T, Error> {
+    enter(Phase::Setup);
+    let catching = CATCHING.with(|current| current.replace(true));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
+    CATCHING.with(|current| current.set(catching));
+
+    result.map_err(|payload| {
+        let payload = match payload.downcast::<ForeignPanic>() {
+            Ok(foreign) => panic::resume_unwind(foreign.0),
+            Err(payload) => payload,
+        };
+        let message = panic_message(&*payload).to_owned();
+        match PHASE.with(Cell::get) {
+            Phase::Setup => Error::Setup(message),
+            Phase::Copy => Error::Copy(message),
+            Phase::ManifestRewrite => Error::ManifestRewrite(message),
+            Phase::Compile => Error::Compile(message),
+            Phase::Run => Error::Run(message),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_catch() {
+        assert_eq!(catch(|| 1), Ok(1));
+        let error = catch(|| {
+            enter(Phase::Compile);
+            panic!("cargo exited with 101");
+        })
+        .unwrap_err();
+        assert_eq!(error, Error::Compile("cargo exited with 101".to_owned()));
+        assert_eq!(
+            error.to_string(),
+            "Couldn't compile the build crate: cargo exited with 101"
+        );
+        assert_eq!(
+            catch(|| panic!("no CARGO")),
+            Err::<(), _>(Error::Setup("no CARGO".to_owned()))
+        );
+
+        // The caller's own panics aren't ours to report.
+        let payload =
+            panic::catch_unwind(|| catch(|| foreign(|| panic::panic_any(42_u32)))).unwrap_err();
+        assert_eq!(payload.downcast_ref::<u32>(), Some(&42));
+        assert_eq!(catch(|| foreign(|| 1)), Ok(1));
+    }
+}
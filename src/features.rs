@@ -0,0 +1,90 @@
+//! Telling the build crate which features the parent crate is built with,
+//! for the builds that want that cross-talk after all.
+
+use std::env;
+
+use crate::toml::{self, Value};
+
+/// Set for the build script to the parent's enabled features, comma
+/// separated, when forwarding them with `FeatureForwarding::Env`. Names are
+/// as cargo gives them in `CARGO_FEATURE_*`: lowercase, with `-` turned into
+/// `_`.
+pub const PARENT_FEATURES_ENV: &str = "CARGO_5730_PARENT_FEATURES";
+
+/// Whether, and how, the parent's enabled features reach the build crate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeatureForwarding {
+    /// The build crate doesn't hear about them.
+    #[default]
+    Off,
+    /// Enable the build crate's features with the same names as the
+    /// parent's enabled ones. Parent features the build crate doesn't have
+    /// are skipped.
+    Features,
+    /// List them in `CARGO_5730_PARENT_FEATURES` for the build script.
+    Env,
+    /// Both of the above.
+    Both,
+}
+
+impl FeatureForwarding {
+    pub(crate) fn features(self) -> bool {
+        matches!(self, FeatureForwarding::Features | FeatureForwarding::Both)
+    }
+
+    pub(crate) fn env(self) -> bool {
+        matches!(self, FeatureForwarding::Env | FeatureForwarding::Both)
+    }
+}
+
+/// The parent's enabled features, going by the `CARGO_FEATURE_*` variables
+/// cargo sets for its build script, sorted.
+pub(crate) fn parent_features() -> Vec<String> {
+    let mut features: Vec<String> = env::vars_os()
+        .filter_map(|(var, _)| {
+            let feature = var.to_str()?.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_ascii_lowercase())
+        })
+        .collect();
+    features.sort();
+    features
+}
+
+/// The features `manifest` declares that are among `enabled`, which are
+/// named the way `parent_features` names them.
+pub(crate) fn matching(manifest: &str, enabled: &[String]) -> Result<Vec<String>, String> {
+    let manifest = toml::parse(manifest)?;
+    let declared = match manifest.get(&["features"]) {
+        Some(Value::Table(declared)) => declared,
+        _ => return Ok(Vec::new()),
+    };
+    Ok(declared
+        .keys()
+        .filter(|feature| {
+            let normalized = feature.to_ascii_lowercase().replace('-', "_");
+            enabled.contains(&normalized)
+        })
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matching() {
+        let manifest = r#"
+[package]
+name = "build-script"
+
+[features]
+default = []
+fast-math = []
+Simd = []
+"#;
+        let enabled = ["fast_math".to_owned(), "simd".to_owned(), "std".to_owned()];
+        assert_eq!(matching(manifest, &enabled).unwrap(), ["Simd", "fast-math"]);
+        assert!(matching("[package]\n", &enabled).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+//! A deterministic hash of everything that goes into an isolated build, for
+//! use as a cache key.
+//!
+//! `std`'s hashers are explicitly allowed to change between releases, so
+//! this uses FNV-1a, which is trivial to implement and stable forever.
+
+use std::{fs, io, path};
+
+use crate::Toolchain;
+
+/// Bump whenever what goes into the key changes meaning.
+const KEY_VERSION: &str = "cargo-5730-cache-key-v2";
+
+/// Directories in the build crate that never affect its build.
+const IGNORED_DIRS: &[&str] = &["target", ".git"];
+
+/// FNV-1a, 128 bit variant.
+pub(crate) struct Hasher(u128);
+
+impl Hasher {
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013B;
+
+    pub fn new() -> Self {
+        Hasher(Self::OFFSET_BASIS)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u128::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    /// Hash a named, length-prefixed field, so that adjacent fields can't run
+    /// into each other and collide.
+    pub fn field(&mut self, name: &str, value: &[u8]) {
+        self.write(name.as_bytes());
+        self.write(&(value.len() as u64).to_le_bytes());
+        self.write(value);
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:032x}", self.0)
+    }
+}
+
+/// Hash every file under `dir`, in a platform-independent order, keyed by
+/// its `/`-separated path relative to `dir`.
+pub(crate) fn hash_tree(hasher: &mut Hasher, dir: &path::Path) -> io::Result<()> {
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    files.sort();
+
+    for (relative, file) in files {
+        hasher.field("path", relative.as_bytes());
+        hasher.field("content", &fs::read(file)?);
+    }
+    Ok(())
+}
+
+/// Every file under `dir` that goes into its hash, in the same order, for
+/// telling cargo to watch each of them.
+pub(crate) fn source_files(dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    files.sort();
+    Ok(files.into_iter().map(|(_, file)| file).collect())
+}
+
+fn collect_files(
+    root: &path::Path,
+    dir: &path::Path,
+    files: &mut Vec<(String, path::PathBuf)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_path = entry.path();
+        if fs::metadata(&file_path)?.is_dir() {
+            if dir == root && IGNORED_DIRS.iter().any(|d| entry.file_name() == *d) {
+                continue;
+            }
+            collect_files(root, &file_path, files)?;
+        } else {
+            let relative = file_path
+                .strip_prefix(root)
+                .expect("walked outside of the build crate")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((relative, file_path));
+        }
+    }
+    Ok(())
+}
+
+/// The cache key for building the build crate whose sources are in `trees`,
+/// its own dir first and then those of the crates it depends on by path,
+/// with `toolchain`. `options` are the (name, value) pairs of every setting
+/// that changes the build.
+pub(crate) fn cache_key(
+    trees: &[&path::Path],
+    toolchain: &Toolchain,
+    options: &[(&str, String)],
+) -> String {
+    let mut hasher = Hasher::new();
+    hasher.field("version", KEY_VERSION.as_bytes());
+
+    // Numbered rather than named by path, so the same sources checked out
+    // elsewhere get the same key.
+    for (index, tree) in trees.iter().enumerate() {
+        hasher.field("tree", &(index as u64).to_le_bytes());
+        hash_tree(&mut hasher, tree).unwrap_or_else(|e| {
+            panic!(
+                "Couldn't hash build crate sources in {}: {}",
+                tree.display(),
+                e
+            )
+        });
+    }
+
+    let versions = [&toolchain.cargo_version, &toolchain.rustc_version];
+    for version in versions.iter() {
+        hasher.field("tool", version.as_deref().unwrap_or("unknown").as_bytes());
+    }
+    for (name, value) in options {
+        hasher.field(name, value.as_bytes());
+    }
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_fnv1a_128() {
+        // Reference values for FNV-1a 128.
+        let mut hasher = Hasher::new();
+        assert_eq!(hasher.finish(), "6c62272e07bb014262b821756295c58d");
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), "d228cb696f1a8caf78912b704e4a8964");
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let dir = env::temp_dir().join(format!(
+            "cargo-5730-fingerprint-test-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(dir.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+
+        let toolchain = Toolchain::default();
+        let options = [("profile", "debug".to_owned())];
+        let key = cache_key(&[&dir], &toolchain, &options);
+        assert_eq!(key, cache_key(&[&dir], &toolchain, &options));
+
+        // Build output doesn't matter...
+        fs::write(dir.join("target").join("junk"), "junk").unwrap();
+        assert_eq!(key, cache_key(&[&dir], &toolchain, &options));
+
+        assert_eq!(
+            source_files(&dir).unwrap(),
+            [dir.join("Cargo.toml"), dir.join("src").join("main.rs")]
+        );
+
+        // ...but sources and options do.
+        let release = [("profile", "release".to_owned())];
+        assert_ne!(key, cache_key(&[&dir], &toolchain, &release));
+        fs::write(dir.join("src").join("main.rs"), "fn main() { }\n").unwrap();
+        assert_ne!(key, cache_key(&[&dir], &toolchain, &options));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//! Checks the build crate has to pass before its build script is allowed to
+//! run.
+
+use std::{ffi, path, process};
+
+/// Run `cargo clippy` on the build crate in `crate_dir`, in the same isolated
+/// environment as its build, with `lint_args` after the `--` to set lint
+/// levels. Fails if clippy reports any errors.
+pub(crate) fn run_clippy(
+    crate_dir: &path::Path,
+    target_dir: Option<&path::Path>,
+    cargo: &ffi::OsStr,
+    cargo_args: &[&str],
+    forwarded_env: &[(String, ffi::OsString)],
+    lint_args: &[String],
+) {
+    println!(
+        "Linting build crate with `cargo clippy -- {}`",
+        lint_args.join(" ")
+    );
+    let mut command = process::Command::new(cargo);
+    command.arg("clippy").args(cargo_args);
+    if let Some(target_dir) = target_dir {
+        command.arg("--target-dir").arg(target_dir);
+    }
+    let status = command
+        .arg("--")
+        .args(lint_args)
+        .env_clear()
+        .envs(forwarded_env.iter().map(|(k, v)| (k, v)))
+        .current_dir(crate_dir)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run cargo clippy: {}", e));
+
+    assert!(
+        status.success(),
+        "cargo clippy rejected the build crate at {} ({})",
+        crate_dir.display(),
+        status
+    );
+}
+
+/// Run `command` (program first, then its arguments) against the compiled
+/// build crate in `crate_dir`, whose `Cargo.lock` now holds the resolved
+/// dependencies. A `cargo` program is replaced with the inner cargo, so
+/// `cargo deny` and `cargo audit` run with the same toolchain as the build.
+///
+/// Unlike the build itself, this runs with the outer environment, since audit
+/// tools keep their advisory databases in the user's home dir.
+pub(crate) fn run_audit(command: &[ffi::OsString], crate_dir: &path::Path, cargo: &ffi::OsStr) {
+    let (program, args) = command
+        .split_first()
+        .expect("The audit command must at least name a program");
+    let program = if program == "cargo" {
+        cargo
+    } else {
+        program.as_os_str()
+    };
+    let description = command
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    println!("Auditing build crate dependencies with `{}`", description);
+    let status = process::Command::new(program)
+        .args(args)
+        .current_dir(crate_dir)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run audit command `{}`: {}", description, e));
+
+    assert!(
+        status.success(),
+        "Audit command `{}` rejected the build crate's dependencies ({})",
+        description,
+        status
+    );
+}
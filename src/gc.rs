@@ -0,0 +1,134 @@
+//! Removing the staging dirs that builds killed before they could clean up
+//! after themselves leave behind.
+
+use std::{fs, io, path, time};
+
+use crate::{lock, KEPT_MARKER, STAGING_MARKER};
+
+/// How long a staging dir goes unused before it's taken to be left over,
+/// unless told otherwise. Long enough for any build still using it to have
+/// finished.
+pub const DEFAULT_STALE_AFTER: time::Duration = time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Remove the staging dirs in `staging_root` that haven't been used for
+/// `older_than`, returning the ones removed. Only dirs this crate created, with
+/// its marker in them, are touched, and never ones kept with
+/// `BuildCrateRunner::keep` or `keep_on_failure`; those are for removing by
+/// hand.
+///
+/// A staging dir is in use from the moment it's created, or reused, until its
+/// build ends, so `older_than` needs to be longer than any build; with
+/// `Duration::ZERO`, the staging dirs of builds still running go too, bar
+/// persistent ones, which are locked while in use.
+pub fn clean<P: AsRef<path::Path>>(
+    staging_root: P,
+    older_than: time::Duration,
+) -> Vec<path::PathBuf> {
+    let staging_root = staging_root.as_ref();
+    let entries = match fs::read_dir(staging_root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            println!(
+                "cargo:warning=Couldn't look for stale staging dirs in {}: {}",
+                staging_root.display(),
+                e
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut removed = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with("cargo-5730-") && !name.starts_with("c5730-") {
+            continue;
+        }
+        let dir = entry.path();
+        if !is_stale(&dir, older_than) {
+            continue;
+        }
+
+        println!("Removing stale staging dir: {}", dir.display());
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => removed.push(dir),
+            Err(e) => println!(
+                "cargo:warning=Couldn't remove stale staging dir {}: {}",
+                dir.display(),
+                e
+            ),
+        }
+    }
+    removed
+}
+
+/// Whether `dir` is a staging dir whose marker is older than `older_than`,
+/// and that wasn't kept. The marker is touched whenever a persistent staging
+/// dir is reused, and locked while it's in use, however old.
+fn is_stale(dir: &path::Path, older_than: time::Duration) -> bool {
+    if dir.join(KEPT_MARKER).exists() || lock::is_locked(&dir.join(STAGING_MARKER)) {
+        return false;
+    }
+    let modified = fs::symlink_metadata(dir.join(STAGING_MARKER))
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let age = modified.and_then(|modified| modified.elapsed().ok());
+    age.is_some_and(|age| age >= older_than)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_clean() {
+        let root = env::temp_dir().join(format!("cargo-5730-gc-test-{}", process::id()));
+        let stale = root.join("cargo-5730-build-script-0123456789abcdef-1");
+        let fresh = root.join("cargo-5730-build-script-0123456789abcdef-2");
+        let unmarked = root.join("cargo-5730-replay");
+        let other = root.join("unrelated");
+        for dir in [&stale, &fresh, &unmarked, &other] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for dir in [&stale, &fresh, &other] {
+            fs::write(dir.join(STAGING_MARKER), "").unwrap();
+        }
+        let day = time::Duration::from_secs(24 * 60 * 60);
+        fs::File::options()
+            .write(true)
+            .open(stale.join(STAGING_MARKER))
+            .unwrap()
+            .set_modified(time::SystemTime::now() - 2 * day)
+            .unwrap();
+
+        // A dir kept with `keep`/`keep_on_failure` is never removed, however old.
+        let kept = root.join("cargo-5730-build-script-0123456789abcdef-3");
+        fs::create_dir_all(&kept).unwrap();
+        fs::write(kept.join(STAGING_MARKER), "").unwrap();
+        fs::write(kept.join(KEPT_MARKER), "").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(kept.join(STAGING_MARKER))
+            .unwrap()
+            .set_modified(time::SystemTime::now() - 2 * day)
+            .unwrap();
+
+        assert_eq!(clean(&root, day), [stale.as_path()]);
+        assert!(kept.is_dir());
+        assert!(!stale.exists());
+        assert!(fresh.is_dir() && unmarked.is_dir() && other.is_dir());
+        assert!(clean(root.join("missing"), day).is_empty());
+
+        // However old, a staging dir that's locked is in use.
+        let in_use = root.join("cargo-5730-build-script-0123456789abcdef-persistent");
+        fs::create_dir_all(&in_use).unwrap();
+        let lock = lock::exclusive(&in_use.join(STAGING_MARKER), &in_use);
+        assert_eq!(clean(&root, time::Duration::ZERO), [fresh.as_path()]);
+        assert!(in_use.is_dir());
+        drop(lock);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
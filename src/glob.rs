@@ -0,0 +1,61 @@
+//! Just enough glob matching to pick files out of the build crate by their
+//! `/`-separated path relative to it.
+
+/// Whether `path` matches `pattern`, in which `*` stands for any part of a
+/// name, `?` for any one character, and a `**` component for any number of
+/// directories, including none.
+pub(crate) fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|part| !part.is_empty()).collect();
+    let path: Vec<&str> = path.split('/').collect();
+    matches_components(&pattern, &path)
+}
+
+fn matches_components(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (Some(&"**"), _) => {
+            matches_components(&pattern[1..], path)
+                || (!path.is_empty() && matches_components(pattern, &path[1..]))
+        }
+        (Some(part), Some(name)) => {
+            let part: Vec<char> = part.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            matches_name(&part, &name) && matches_components(&pattern[1..], &path[1..])
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn matches_name(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (Some('*'), _) => {
+            matches_name(&pattern[1..], name)
+                || (!name.is_empty() && matches_name(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => matches_name(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) => p == n && matches_name(&pattern[1..], &name[1..]),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("target", "target"));
+        assert!(!matches("target", "src/target"));
+        assert!(matches("testdata/**", "testdata"));
+        assert!(matches("testdata/**", "testdata/a/b.bin"));
+        assert!(!matches("testdata/**", "src/testdata"));
+        assert!(matches("**/*.bin", "big.bin"));
+        assert!(matches("**/*.bin", "data/nested/big.bin"));
+        assert!(!matches("**/*.bin", "data/big.bin.txt"));
+        assert!(matches("Cargo.*", "Cargo.lock"));
+        assert!(matches("src/**", "src/bin/tool.rs"));
+        assert!(matches("src/ma?n.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/tool.rs"));
+    }
+}
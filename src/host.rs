@@ -0,0 +1,172 @@
+//! Compiling the build script for an architecture this machine can run, when
+//! the inner toolchain's host isn't the machine's own: an x64 toolchain on
+//! ARM64 Windows, or one running under Rosetta on an Apple silicon Mac.
+
+use std::{ffi, path, process};
+
+/// Which target the build crate is compiled for when it runs on this machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HostTarget {
+    /// The inner toolchain's host, unless this machine has a different
+    /// architecture and the toolchain has the standard library for it, in
+    /// which case the machine's own target.
+    #[default]
+    Auto,
+    /// Always the inner toolchain's host, as cargo itself would.
+    Toolchain,
+    /// This target triple.
+    Triple(String),
+}
+
+/// The architecture of the machine, as it appears in target triples, when
+/// it may differ from what the running process was compiled for. `None`
+/// where there's no emulation to see through.
+pub(crate) fn native_arch() -> Option<&'static str> {
+    if cfg!(windows) {
+        windows_arch(
+            std::env::var("PROCESSOR_ARCHITEW6432")
+                .or_else(|_| std::env::var("PROCESSOR_ARCHITECTURE"))
+                .ok()
+                .as_deref(),
+            std::env::var("PROCESSOR_IDENTIFIER").ok().as_deref(),
+        )
+    } else if cfg!(target_os = "macos") {
+        let output = process::Command::new("sysctl")
+            .args(["-n", "hw.optional.arm64"])
+            .output()
+            .ok()?;
+        if String::from_utf8_lossy(&output.stdout).trim() == "1" {
+            Some("aarch64")
+        } else {
+            Some("x86_64")
+        }
+    } else {
+        None
+    }
+}
+
+/// Windows reports an emulated process's architecture in
+/// `PROCESSOR_ARCHITECTURE`, but an ARM processor still shows in
+/// `PROCESSOR_IDENTIFIER`.
+fn windows_arch(architecture: Option<&str>, identifier: Option<&str>) -> Option<&'static str> {
+    if identifier.is_some_and(|id| id.starts_with("ARMv8")) {
+        return Some("aarch64");
+    }
+    match architecture? {
+        "ARM64" => Some("aarch64"),
+        "AMD64" => Some("x86_64"),
+        "x86" => Some("i686"),
+        _ => None,
+    }
+}
+
+/// `triple` with its architecture replaced by `arch`.
+fn with_arch(triple: &str, arch: &str) -> String {
+    match triple.split_once('-') {
+        Some((_, rest)) => format!("{}-{}", arch, rest),
+        None => triple.to_owned(),
+    }
+}
+
+/// Whether the standard library for `triple` is installed for `rustc`.
+pub(crate) fn target_installed(
+    rustc: &ffi::OsStr,
+    forwarded_env: &[(String, ffi::OsString)],
+    triple: &str,
+) -> bool {
+    process::Command::new(rustc)
+        .args(["--print", "target-libdir", "--target", triple])
+        .env_clear()
+        .envs(forwarded_env.iter().map(|(k, v)| (k, v)))
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .is_some_and(|output| {
+            path::Path::new(String::from_utf8_lossy(&output.stdout).trim()).is_dir()
+        })
+}
+
+/// The target to compile for, or `None` for the toolchain's host. Warns when
+/// the toolchain's host doesn't match this machine.
+pub(crate) fn choose<F>(
+    host_target: &HostTarget,
+    toolchain_host: Option<&str>,
+    native_arch: Option<&str>,
+    installed: F,
+) -> Option<String>
+where
+    F: Fn(&str) -> bool,
+{
+    let toolchain_host = match host_target {
+        HostTarget::Toolchain => return None,
+        HostTarget::Triple(triple) => return Some(triple.clone()),
+        HostTarget::Auto => toolchain_host?,
+    };
+    let native_arch = native_arch?;
+    if toolchain_host.split('-').next() == Some(native_arch) {
+        return None;
+    }
+
+    let native = with_arch(toolchain_host, native_arch);
+    if installed(&native) {
+        println!(
+            "cargo:warning=The inner toolchain's host is {}, but this machine is {}; compiling the build crate for {}",
+            toolchain_host, native_arch, native
+        );
+        Some(native)
+    } else {
+        println!(
+            "cargo:warning=The inner toolchain's host is {}, but this machine is {}; the build script will run emulated (install {} to avoid that)",
+            toolchain_host, native_arch, native
+        );
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_windows_arch() {
+        assert_eq!(windows_arch(Some("ARM64"), None), Some("aarch64"));
+        assert_eq!(
+            windows_arch(
+                Some("AMD64"),
+                Some("ARMv8 (64-bit) Family 8 Model 1 Revision 201, Qualcomm")
+            ),
+            Some("aarch64")
+        );
+        assert_eq!(
+            windows_arch(Some("AMD64"), Some("Intel64 Family 6")),
+            Some("x86_64")
+        );
+    }
+
+    #[test]
+    fn test_choose() {
+        let host = Some("x86_64-pc-windows-msvc");
+        let auto = HostTarget::Auto;
+        assert_eq!(choose(&auto, host, Some("x86_64"), |_| true), None);
+        assert_eq!(choose(&auto, host, None, |_| true), None);
+        assert_eq!(
+            choose(&auto, host, Some("aarch64"), |_| true).as_deref(),
+            Some("aarch64-pc-windows-msvc")
+        );
+        assert_eq!(choose(&auto, host, Some("aarch64"), |_| false), None);
+        assert_eq!(
+            choose(&HostTarget::Toolchain, host, Some("aarch64"), |_| true),
+            None
+        );
+        assert_eq!(
+            choose(
+                &HostTarget::Triple("i686-pc-windows-msvc".to_owned()),
+                host,
+                None,
+                |_| true
+            )
+            .as_deref(),
+            Some("i686-pc-windows-msvc")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Stopping cleanly when the build is interrupted.
+//!
+//! Left to the default handling, Ctrl-C or a `kill` ends this process on the
+//! spot: the staging dir is never removed, and a child killed by neither
+//! keeps running after it. Instead, the signal is noted, passed on to the
+//! children being waited for, and the run fails once they've exited, so the
+//! staging dir is dropped on the way out like after any other failure.
+
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Once;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The ids of the children being waited for, 0 in the free slots. A fixed
+/// set of atomics, as that's all a signal handler can safely look at.
+static CHILDREN: [AtomicU32; 8] = [const { AtomicU32::new(0) }; 8];
+
+/// Handle interruptions from now on, for the rest of the process.
+pub(crate) fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(imp::install);
+}
+
+/// Whether the build has been interrupted.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fail the run if the build has been interrupted, rather than starting on
+/// anything else.
+pub(crate) fn check() {
+    assert!(!interrupted(), "Interrupted");
+}
+
+/// Passes an interruption on to a child until it's dropped, which should be
+/// after the child has been waited for.
+pub(crate) struct Tracked(Option<&'static AtomicU32>);
+
+/// Pass an interruption on to `child` while the returned guard lives. Past
+/// the first few children, the rest are left to the signal reaching them
+/// some other way, as it does for Ctrl-C in a terminal.
+pub(crate) fn track(child: &process::Child) -> Tracked {
+    let slot = CHILDREN.iter().find(|slot| {
+        slot.compare_exchange(0, child.id(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    });
+    Tracked(slot)
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0 {
+            slot.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::c_int;
+    use std::sync::atomic::Ordering;
+
+    use super::{CHILDREN, INTERRUPTED};
+
+    // The same numbers on every Unix this builds for.
+    const SIGHUP: c_int = 1;
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    const SIG_IGN: usize = 1;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn kill(pid: i32, sig: c_int) -> c_int;
+    }
+
+    extern "C" fn on_signal(_: c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        for slot in &CHILDREN {
+            let pid = slot.load(Ordering::SeqCst);
+            if pid != 0 {
+                // Safety: kill is async-signal-safe, and the child hasn't
+                // been waited for yet, so its id can't have been reused.
+                unsafe { kill(pid as i32, SIGTERM) };
+            }
+        }
+    }
+
+    pub(super) fn install() {
+        for signum in [SIGHUP, SIGINT, SIGTERM] {
+            // Safety: the handler only touches atomics and calls kill.
+            unsafe {
+                // A signal ignored by whoever started us, as `nohup` does
+                // for SIGHUP, stays ignored.
+                if signal(signum, on_signal as extern "C" fn(c_int) as usize) == SIG_IGN {
+                    signal(signum, SIG_IGN);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::sync::atomic::Ordering;
+
+    use super::INTERRUPTED;
+
+    const CTRL_C_EVENT: u32 = 0;
+    const CTRL_BREAK_EVENT: u32 = 1;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<extern "system" fn(u32) -> i32>, add: i32) -> i32;
+    }
+
+    // Runs on a thread of its own. The children share the console, so they
+    // get the same event; all that's left is to not exit before they have.
+    extern "system" fn on_ctrl(ctrl_type: u32) -> i32 {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        matches!(ctrl_type, CTRL_C_EVENT | CTRL_BREAK_EVENT) as i32
+    }
+
+    pub(super) fn install() {
+        // Safety: the handler only touches an atomic.
+        if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) } == 0 {
+            println!("cargo:warning=Couldn't handle Ctrl-C; an interrupted build leaves its staging dir behind");
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    pub(super) fn install() {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_track() {
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let mut child = process::Command::new(cargo)
+            .arg("--version")
+            .stdout(process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let tracked = track(&child);
+        let slot = tracked.0.expect("a free slot");
+        assert_eq!(slot.load(Ordering::SeqCst), child.id());
+        child.wait().unwrap();
+        drop(tracked);
+        assert_eq!(slot.load(Ordering::SeqCst), 0);
+        check();
+    }
+}
@@ -0,0 +1,116 @@
+//! Deciding whether the build crate actually needs to be moved out of its
+//! parent's directory tree.
+
+use std::{fs, path};
+
+/// Whether to stage the build crate outside of the parent's directory tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IsolationMode {
+    /// Always stage the build crate in a temporary directory.
+    #[default]
+    Always,
+    /// Only stage the build crate if something in its ancestry would leak
+    /// into its build; otherwise build it where it is, with a dedicated
+    /// target dir. This skips the copy, and lets the build be incremental.
+    Auto,
+    /// Build the build crate where it is even with a cargo config above it,
+    /// for build crates too big to copy. The settings staging would pin are
+    /// passed as `--config` overrides instead, and a warning names each
+    /// config whose other settings still apply; `BuildCrateRunner::cargo_home`
+    /// scopes the user-level one. An enclosing workspace still needs staging.
+    InPlace,
+}
+
+/// Pinned for builds in place, as staging pins them in the staged crate's
+/// config.
+pub(crate) const PINNED_CONFIG_ARGS: &[&str] = &[
+    "--config",
+    "build.rustflags=[]",
+    "--config",
+    "build.rustdocflags=[]",
+];
+
+/// Returns a description of why the build crate at `build_crate_dir` can't be
+/// built in place, or `None` if nothing in its ancestry would affect it.
+pub(crate) fn isolation_needed(build_crate_dir: &path::Path) -> Option<String> {
+    let build_crate_dir = match build_crate_dir.canonicalize() {
+        Ok(dir) => dir,
+        Err(e) => {
+            return Some(format!(
+                "couldn't resolve {}: {}",
+                build_crate_dir.display(),
+                e
+            ))
+        }
+    };
+
+    // The crate's own .cargo/config and manifest come along when it's staged,
+    // so only its ancestors matter.
+    for ancestor in build_crate_dir.ancestors().skip(1) {
+        for config in &["config", "config.toml"] {
+            let config = ancestor.join(".cargo").join(config);
+            if config.is_file() {
+                return Some(format!("cargo config at {}", config.display()));
+            }
+        }
+    }
+    enclosing_workspace(&build_crate_dir)
+        .map(|manifest| format!("enclosing workspace at {}", manifest.display()))
+}
+
+/// The manifest of a workspace above `build_crate_dir` that cargo would take
+/// it to be part of, unless it's a workspace of its own.
+pub(crate) fn enclosing_workspace(build_crate_dir: &path::Path) -> Option<path::PathBuf> {
+    if declares_workspace(&build_crate_dir.join("Cargo.toml")) {
+        return None;
+    }
+    build_crate_dir
+        .ancestors()
+        .skip(1)
+        .map(|ancestor| ancestor.join("Cargo.toml"))
+        .find(|manifest| declares_workspace(manifest))
+}
+
+fn declares_workspace(manifest: &path::Path) -> bool {
+    fs::read_to_string(manifest)
+        .map(|content| {
+            content
+                .lines()
+                .any(|line| line.trim_start().starts_with("[workspace"))
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_isolation_needed() {
+        let root =
+            env::temp_dir().join(format!("cargo-5730-isolation-test-{}", std::process::id()));
+        let build_crate = root.join("parent").join("build-script");
+        fs::create_dir_all(&build_crate).unwrap();
+        fs::write(build_crate.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(root.join("parent").join("Cargo.toml"), "[package]\n").unwrap();
+
+        fs::create_dir_all(root.join("parent").join(".cargo")).unwrap();
+        fs::write(root.join("parent").join(".cargo").join("config.toml"), "").unwrap();
+        let reason = isolation_needed(&build_crate).unwrap();
+        assert!(reason.contains("cargo config"), "{}", reason);
+
+        fs::remove_dir_all(root.join("parent").join(".cargo")).unwrap();
+        fs::write(root.join("parent").join("Cargo.toml"), "[workspace]\n").unwrap();
+        let reason = isolation_needed(&build_crate).unwrap();
+        assert!(reason.contains("workspace"), "{}", reason);
+        assert_eq!(
+            enclosing_workspace(&build_crate),
+            Some(root.join("parent").join("Cargo.toml"))
+        );
+        fs::write(build_crate.join("Cargo.toml"), "[package]\n[workspace]\n").unwrap();
+        assert_eq!(enclosing_workspace(&build_crate), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Just enough JSON to write reports and read cargo's messages, without
+//! pulling in a dependency.
+
+use std::iter::Peekable;
+use std::{char, fmt, str};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<I, K>(fields: I) -> Json
+    where
+        I: IntoIterator<Item = (K, Json)>,
+        K: Into<String>,
+    {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn string<S: Into<String>>(s: S) -> Json {
+        Json::String(s.into())
+    }
+
+    pub fn optional_string<S: Into<String>>(s: Option<S>) -> Json {
+        s.map_or(Json::Null, Json::string)
+    }
+
+    /// Parse one JSON value, such as a line of cargo's
+    /// `--message-format=json` output.
+    pub fn parse(input: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            chars: input.chars().peekable(),
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected {:?} after the value", c)),
+        }
+    }
+
+    /// The value of `key`, if this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected {:?}, found {:?}", expected, c)),
+            None => Err(format!("expected {:?}, found the end", expected)),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+        for expected in keyword.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Json::String),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('n') => self.keyword("null", Json::Null),
+            Some('-' | '0'..='9') => self.number(),
+            Some(c) => Err(format!("unexpected {:?}", c)),
+            None => Err("unexpected end".to_owned()),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err("expected ',' or '}' in an object".to_owned()),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err("expected ',' or ']' in an array".to_owned()),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next().ok_or("unterminated string")? {
+                '"' => return Ok(s),
+                '\\' => match self.chars.next().ok_or("unterminated string")? {
+                    '"' => s.push('"'),
+                    '\\' => s.push('\\'),
+                    '/' => s.push('/'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'u' => {
+                        let unit = self.hex4()?;
+                        let c = if (0xd800..0xdc00).contains(&unit) {
+                            // A surrogate pair.
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex4()?;
+                            char::decode_utf16([unit as u16, low as u16])
+                                .next()
+                                .and_then(Result::ok)
+                        } else {
+                            char::from_u32(unit)
+                        };
+                        s.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    c => return Err(format!("invalid escape \\{}", c)),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self
+                .chars
+                .next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or("invalid \\u escape")?;
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut number = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9') {
+                break;
+            }
+            number.push(c);
+            self.chars.next();
+        }
+        if let Ok(integer) = number.parse() {
+            return Ok(Json::Integer(integer));
+        }
+        number
+            .parse()
+            .map(Json::Number)
+            .map_err(|_| format!("invalid number {}", number))
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Integer(i) => write!(f, "{}", i),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => f.write_str("null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let value = Json::object(vec![
+            ("name", Json::string("quote \" and \\ and \n")),
+            ("count", Json::Number(3.0)),
+            ("ratio", Json::Number(0.5)),
+            ("none", Json::Null),
+            ("flag", Json::Bool(true)),
+            ("big", Json::Integer(i64::MAX)),
+            ("list", Json::Array(vec![Json::string("\u{1}")])),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"quote \" and \\ and \n","count":3,"ratio":0.5,"none":null,"flag":true,"big":9223372036854775807,"list":["\u0001"]}"#
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let value = Json::parse(
+            r#" {"reason":"compiler-artifact","executable":"/t/a \"b\"","fresh":false,
+                "n":[1,-0.25e1,null],"u":"\u00e9\ud83d\ude00","empty":{}} "#,
+        )
+        .unwrap();
+        assert_eq!(
+            value.get("reason").and_then(Json::as_str),
+            Some("compiler-artifact")
+        );
+        assert_eq!(
+            value.get("executable").and_then(Json::as_str),
+            Some("/t/a \"b\"")
+        );
+        assert_eq!(value.get("fresh"), Some(&Json::Bool(false)));
+        assert_eq!(
+            value.get("n"),
+            Some(&Json::Array(vec![
+                Json::Integer(1),
+                Json::Number(-2.5),
+                Json::Null
+            ]))
+        );
+        assert_eq!(
+            value.get("u").and_then(Json::as_str),
+            Some("\u{e9}\u{1f600}")
+        );
+        assert_eq!(value.get("empty"), Some(&Json::Object(Vec::new())));
+        assert_eq!(value.get("missing"), None);
+
+        // Round trips through the writer.
+        assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+
+        assert!(Json::parse("Compiling foo").is_err());
+        assert!(Json::parse("{\"a\":1} x").is_err());
+        assert!(Json::parse("[1,").is_err());
+    }
+}
@@ -1,191 +1,3402 @@
-use std::io::Read;
-use std::{env, fs, path, process};
+use std::io::{self, BufRead};
+use std::sync::Arc;
+use std::{borrow, collections, env, ffi, fs, panic, path, process, thread, time};
+
+use channel::ChannelHandler;
+use error::Phase;
+use output_log::OutputLog;
+
+mod backend;
+mod cache;
+mod capture;
+mod cargo_config;
+mod channel;
+mod check;
+mod config;
+mod diagnostics;
+mod directives;
+mod emit;
+mod error;
+mod features;
+mod fingerprint;
+mod gates;
+mod gc;
+mod glob;
+mod host;
+mod interrupt;
+mod isolation;
+mod json;
+mod lock;
+mod lockfile;
+mod manifest;
+mod options;
+mod output_log;
+mod outputs;
+mod overlap;
+mod overrides;
+mod params;
+mod passthrough;
+mod paths;
+mod preflight;
+mod report;
+mod script_env;
+mod script_result;
+mod set;
+mod staging;
+pub mod test_support;
+mod timeout;
+mod toml;
+mod toolchain;
+mod vendor;
+
+pub use backend::{CrossBackend, ExecutionBackend, RunStrategy, SshBackend};
+pub use capture::OutputLimit;
+pub use channel::{Channel, CHANNEL_ENV};
+pub use check::CheckMode;
+pub use directives::{DirectiveChannel, DIRECTIVES_FILE_ENV};
+pub use emit::{
+    emit, emit_cfg, emit_cfg_value, emit_link_lib, emit_link_search, emit_metadata,
+    emit_rerun_if_changed, emit_rerun_if_env_changed, emit_rustc_env, emit_warning,
+};
+pub use error::Error;
+pub use features::{FeatureForwarding, PARENT_FEATURES_ENV};
+pub use gc::{clean, DEFAULT_STALE_AFTER};
+pub use host::HostTarget;
+pub use isolation::IsolationMode;
+pub use options::{ErrorPolicy, Profile, Verbosity};
+pub use overrides::{
+    BUILD_CACHE_ENV, CARGO_HOME_ENV, CHECK_ENV, JOBS_ENV, KEEP_ENV, LOCKED_ENV, OFFLINE_ENV,
+    PROFILE_ENV, STAGING_ROOT_ENV, TARGET_DIR_ENV, VERBOSITY_ENV,
+};
+pub use params::{read_script_params, PARAMS_FILE_ENV};
+pub use preflight::MissingTool;
+pub use report::{BuildReport, BuildScriptOutput, Toolchain};
+pub use script_env::{
+    METADATA_ENV, PARENT_MANIFEST_DIR_ENV, PARENT_PKG_NAME_ENV, PARENT_PKG_VERSION_ENV,
+    STAGING_DIR_ENV,
+};
+pub use script_result::RESULT_FILE_ENV;
+pub use set::{run_default_build_crates, BuildCrateSet, DEFAULT_BUILD_CRATES_DIR, INPUTS_ENV};
+pub use staging::{StagingStrategy, SymlinkPolicy, DEFAULT_STAGING_EXCLUDES};
+pub use toolchain::ToolchainOverride;
+
+/// Where the build crate lives, relative to the parent crate's manifest dir,
+/// unless told otherwise.
+pub const DEFAULT_BUILD_CRATE_DIR: &str = "build-script";
+
+/// The `[package.metadata]` table passed to the build script, unless told
+/// otherwise.
+pub const DEFAULT_METADATA_TABLE: &str = "cargo-5730";
+
+/// Set by docs.rs, which builds without network access.
+const DOCS_RS_ENV: &str = "DOCS_RS";
+
+/// Dropped into every staging dir we create, so we never remove a directory
+/// we didn't make.
+const STAGING_MARKER: &str = ".cargo-5730-staging";
+
+/// Dropped into a staging dir kept with `keep` or `keep_on_failure`, so it's
+/// never taken to be left behind by a killed build.
+const KEPT_MARKER: &str = ".cargo-5730-kept";
+
+/// Beside a shared target dir, locked by the build using it.
+const TARGET_LOCK: &str = ".cargo-5730-lock";
+
+/// A scoped wrapper for the directory where we'll compile and run the build script.
+struct BuildDir {
+    pub path: path::PathBuf,
+    /// The temp dir at the time the staging dir was created; `TMPDIR` and
+    /// friends may well have changed by the time we clean up.
+    root: path::PathBuf,
+    keep: bool,
+    /// Keep it if it's dropped while panicking, i.e. the build failed.
+    keep_on_failure: bool,
+    /// Held on the marker of a persistent staging dir while it's in use.
+    _lock: Option<lock::Lock>,
+}
+
+impl BuildDir {
+    /// A new staging dir in `root` for the build crate called `name`, whose
+    /// source is in `source_dir`. The name is unique to both the build crate
+    /// and this process, so concurrent builds sharing a temp dir never pick
+    /// the same one; a dir left over from an earlier process with a recycled
+    /// PID is skipped rather than reused.
+    fn new(root: path::PathBuf, name: &str, source_dir: &path::Path, keep: bool) -> Self {
+        let mut hasher = fingerprint::Hasher::new();
+        hasher.write(source_dir.to_string_lossy().as_bytes());
+        let prefix = staging_dir_name(name, &hasher.finish(), &process::id().to_string());
+
+        fs::create_dir_all(&root)
+            .unwrap_or_else(|e| panic!("Couldn't create {}: {}", root.display(), e));
+        let mut attempt = 0;
+        let dir = loop {
+            let dir = match attempt {
+                0 => root.join(&prefix),
+                _ => root.join(format!("{}-{}", prefix, attempt)),
+            };
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => panic!("Couldn't create build dir {}: {}", dir.display(), e),
+            }
+        };
+        fs::write(dir.join(STAGING_MARKER), "").unwrap_or_else(|e| {
+            panic!(
+                "Couldn't write staging marker into {}: {}",
+                dir.display(),
+                e
+            )
+        });
+
+        BuildDir {
+            path: dir,
+            root,
+            keep,
+            keep_on_failure: false,
+            _lock: None,
+        }
+    }
+
+    /// The staging dir in `root` that every build of the build crate called
+    /// `name`, from `source_dir`, for this outer build script reuses. Tied to
+    /// the outer build's `OUT_DIR`, which cargo never builds into twice at
+    /// once; it's locked while in use all the same, so that nothing else
+    /// touches it. Returns whether it was staged before.
+    fn persistent(
+        root: path::PathBuf,
+        name: &str,
+        source_dir: &path::Path,
+        out_dir: Option<&path::Path>,
+    ) -> (Self, bool) {
+        let mut hasher = fingerprint::Hasher::new();
+        hasher.write(source_dir.to_string_lossy().as_bytes());
+        if let Some(out_dir) = out_dir {
+            hasher.write(out_dir.to_string_lossy().as_bytes());
+        }
+        let dir = root.join(staging_dir_name(name, &hasher.finish(), "persistent"));
+
+        let staged = dir.join(STAGING_MARKER).is_file();
+        if staged {
+            // Touched, so it isn't taken to be stale while it's being used.
+            fs::File::options()
+                .write(true)
+                .open(dir.join(STAGING_MARKER))
+                .and_then(|marker| marker.set_modified(time::SystemTime::now()))
+                .unwrap_or_else(|e| panic!("Couldn't touch {}'s marker: {}", dir.display(), e));
+        } else {
+            assert!(
+                !dir.exists(),
+                "Not staging into {}: it's missing the staging marker file",
+                dir.display()
+            );
+            fs::create_dir_all(&dir)
+                .and_then(|()| fs::write(dir.join(STAGING_MARKER), ""))
+                .unwrap_or_else(|e| panic!("Couldn't create build dir {}: {}", dir.display(), e));
+        }
+
+        let lock = lock::exclusive(&dir.join(STAGING_MARKER), &dir);
+
+        let build_dir = BuildDir {
+            path: dir,
+            root,
+            keep: true,
+            keep_on_failure: false,
+            _lock: Some(lock),
+        };
+        (build_dir, staged)
+    }
+}
+
+/// The name of a staging dir for the build crate called `name`, whose source
+/// dir hashes to `hash`. Kept short on Windows, where every path in the staged
+/// crate, its target dir included, has to fit in `MAX_PATH` for the tools
+/// that aren't long path aware.
+fn staging_dir_name(name: &str, hash: &str, suffix: &str) -> String {
+    if cfg!(windows) {
+        format!("c5730-{}-{}", &hash[..8], suffix)
+    } else {
+        format!("cargo-5730-{}-{}-{}", name, &hash[..16], suffix)
+    }
+}
+
+impl BuildDir {
+    /// Mark the dir as kept to look into. Persistent staging dirs are kept to
+    /// be reused instead, and go once they're stale.
+    fn mark_kept(&self) {
+        if self._lock.is_some() {
+            return;
+        }
+        if let Err(e) = fs::write(self.path.join(KEPT_MARKER), "") {
+            println!(
+                "cargo:warning=Couldn't mark {} as kept, so it may be cleaned up as stale: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl Drop for BuildDir {
+    fn drop(&mut self) {
+        // some paranoia before running 'rm -rf'
+        assert!(paths::is_within(&self.path, &self.root));
+        if !self.path.join(STAGING_MARKER).is_file() {
+            println!(
+                "Not removing {}: it's missing the staging marker file",
+                self.path.display()
+            );
+            return;
+        }
+
+        // Being interrupted isn't the build crate failing.
+        let failed = thread::panicking() && !interrupt::interrupted();
+        if failed && (self.keep || self.keep_on_failure) {
+            self.mark_kept();
+            println!(
+                "cargo:warning=Keeping the staging dir of the failed build crate: {}",
+                self.path.display()
+            );
+            return;
+        }
+        if self.keep {
+            self.mark_kept();
+            println!("Keeping build crate staging dir: {}", self.path.display());
+            return;
+        }
+
+        println!("Removing build crate staging dir: {}", self.path.display());
+        fs::remove_dir_all(&self.path)
+            .unwrap_or_else(|_| panic!("Couldn't clean up build dir: {}", self.path.display()));
+    }
+}
+
+fn qualify_cargo_toml_paths_in_text(cargo_toml_content: &str, base_dir: &path::Path) -> String {
+    manifest::qualify_paths(cargo_toml_content, base_dir)
+        .unwrap_or_else(|e| panic!("Can't parse the build crate's Cargo.toml: {}", e))
+}
+
+/// Set `package.edition` in a manifest, replacing any existing value
+/// (including `edition.workspace = true`).
+fn set_package_edition(cargo_toml_content: &str, edition: &str) -> String {
+    let edition_line = format!("edition = \"{}\"", edition);
+    let mut lines = Vec::new();
+    let mut package_header = None;
+    let mut in_package = false;
+    let mut replaced = false;
+
+    for line in cargo_toml_content.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_package = trimmed == "[package]";
+            if in_package {
+                package_header = Some(lines.len());
+            }
+        } else if in_package && trimmed.split(['=', '.']).next().map(str::trim) == Some("edition") {
+            if !replaced {
+                lines.push(edition_line.clone());
+                replaced = true;
+            }
+            continue;
+        }
+        lines.push(line.to_owned());
+    }
+
+    if let (false, Some(header)) = (replaced, package_header) {
+        lines.insert(header + 1, edition_line);
+    }
+
+    let mut content = lines.join("\n");
+    if cargo_toml_content.ends_with('\n') {
+        content.push('\n');
+    }
+    content
+}
+
+/// Write the build crate's manifest at `source_path` to `cargo_toml_path` in
+/// the staging dir, fixed up to build there. Left alone if it's already that,
+/// so cargo doesn't see it as changed.
+fn qualify_cargo_toml_paths(
+    source_path: &path::Path,
+    cargo_toml_path: &path::Path,
+    base_dir: &path::Path,
+    edition: Option<&str>,
+) {
+    let cargo_toml = fs::read_to_string(source_path).unwrap_or_else(|_| {
+        panic!(
+            "Can't read Cargo.toml to stream from {}",
+            source_path.display()
+        )
+    });
+    let cargo_toml = manifest::inherit_from_workspace(&cargo_toml, base_dir)
+        .unwrap_or_else(|e| panic!("Can't inherit from the build crate's workspace: {}", e));
+    let mut cargo_toml = qualify_cargo_toml_paths_in_text(&cargo_toml, base_dir);
+    cargo_toml = manifest::detach_from_workspace(&cargo_toml)
+        .unwrap_or_else(|e| panic!("Can't parse the build crate's Cargo.toml: {}", e));
+    if let Some(edition) = edition {
+        cargo_toml = set_package_edition(&cargo_toml, edition);
+    }
+
+    if fs::read_to_string(cargo_toml_path).is_ok_and(|staged| staged == cargo_toml) {
+        return;
+    }
+    fs::write(cargo_toml_path, cargo_toml).unwrap_or_else(|_| {
+        panic!(
+            "Failed to write modified Cargo.toml at {}",
+            cargo_toml_path.display()
+        )
+    });
+}
+
+/// Tell cargo to rerun the build if any source file under `dir` changes.
+fn watch_sources(dir: &path::Path) {
+    match fingerprint::source_files(dir) {
+        Ok(files) => {
+            for file in files {
+                println!("cargo:rerun-if-changed={}", file.display());
+            }
+        }
+        Err(e) => println!(
+            "cargo:warning=Couldn't list the sources of {} to watch them: {}",
+            dir.display(),
+            e
+        ),
+    }
+}
+
+/// What cargo ends executables built for `target` with, or for this
+/// machine when there's no target.
+fn executable_suffix(target: Option<&str>) -> &'static str {
+    match target {
+        None => env::consts::EXE_SUFFIX,
+        Some(target) if target.contains("-windows") => ".exe",
+        Some(target) if target.starts_with("wasm") => ".wasm",
+        Some(_) => "",
+    }
+}
+
+/// The target the inner cargo builds for when told with `--target` in
+/// `cargo_args`, or `CARGO_BUILD_TARGET` in `forwarded_env`, if either.
+fn requested_target(
+    cargo_args: &[&str],
+    forwarded_env: &[(String, ffi::OsString)],
+) -> Option<String> {
+    let mut args = cargo_args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(target) = arg.strip_prefix("--target=") {
+            return Some(target.to_owned());
+        }
+        if *arg == "--target" {
+            return args.next().map(|target| target.to_string());
+        }
+    }
+    forwarded_env
+        .iter()
+        .find(|(var, _)| *var == "CARGO_BUILD_TARGET")
+        .map(|(_, target)| target.to_string_lossy().into_owned())
+}
+
+/// The target name and path of the executable a `compiler-artifact` message
+/// from cargo reports, if it's about one.
+fn artifact_executable(message: &json::Json) -> Option<(String, path::PathBuf)> {
+    if message.get("reason")?.as_str()? != "compiler-artifact" {
+        return None;
+    }
+    let executable = message.get("executable")?.as_str()?;
+    let name = message.get("target")?.get("name")?.as_str()?;
+    Some((name.to_owned(), path::PathBuf::from(executable)))
+}
+
+/// Compile the build crate, returning the name and path of each executable
+/// cargo reports building. Diagnostics are still rendered to stderr as
+/// usual; cargo's messages on stdout are only read for the executables.
+fn compile_build_crate(
+    crate_dir: &path::Path,
+    target_dir: Option<&path::Path>,
+    cargo: &ffi::OsStr,
+    cargo_args: &[&str],
+    forwarded_env: &[(String, ffi::OsString)],
+    output_log: Option<&Arc<OutputLog>>,
+    timeout: Option<time::Duration>,
+) -> Vec<(String, path::PathBuf)> {
+    let mut command = process::Command::new(cargo);
+    command
+        .arg("build")
+        .args(cargo_args)
+        .arg("--message-format=json-render-diagnostics");
+    if let Some(target_dir) = target_dir {
+        command.arg("--target-dir").arg(target_dir);
+    }
+
+    // stderr only needs to pass through us to be logged.
+    let stderr = match output_log {
+        Some(_) => process::Stdio::piped(),
+        None => process::Stdio::inherit(),
+    };
+    let mut child = command
+        .env_clear()
+        .envs(forwarded_env.iter().map(|(k, v)| (k, v)))
+        .current_dir(crate_dir)
+        .stdout(process::Stdio::piped())
+        .stderr(stderr)
+        .spawn()
+        .expect("failed to compile build-script crate");
+    let _tracked = interrupt::track(&child);
+    let stderr_thread = child.stderr.take().map(|stderr| {
+        let mut stderr = output_log::tee(output_log, stderr, "cargo build", "stderr");
+        thread::spawn(move || io::copy(&mut stderr, &mut io::stderr()))
+    });
+    let stdout = output_log::tee(
+        output_log,
+        child.stdout.take().expect("stdout is piped"),
+        "cargo build",
+        "stdout",
+    );
+    let child = timeout::Watched::new(child, timeout);
+
+    let mut executables = Vec::new();
+    for line in io::BufReader::new(stdout).lines() {
+        let line = line.expect("Couldn't read cargo's output");
+        match json::Json::parse(&line) {
+            Ok(message) => executables.extend(artifact_executable(&message)),
+            // Not one of cargo's messages; pass it on.
+            Err(_) => println!("{}", line),
+        }
+    }
+    if let Some(stderr_thread) = stderr_thread {
+        stderr_thread
+            .join()
+            .expect("stderr thread panicked")
+            .expect("Couldn't pass on cargo's stderr");
+    }
+    let status = child.wait();
+    interrupt::check();
+    let status = status.unwrap_or_else(|e| {
+        panic!(
+            "Couldn't compile build crate at {}: {}",
+            crate_dir.display(),
+            e
+        )
+    });
+
+    assert!(
+        status.success(),
+        "Failed to run compile build crate at {} ({})",
+        crate_dir.display(),
+        status
+    );
+    executables
+}
+
+/// How to run the compiled build script.
+struct ScriptInvocation<'a> {
+    exec_runner: Option<&'a [ffi::OsString]>,
+    output_limit: Option<OutputLimit>,
+    directives_file: Option<&'a path::Path>,
+    channel: Option<&'a Arc<ChannelHandler>>,
+    result_file: Option<&'a path::Path>,
+    collect_directives: bool,
+    rebase: Option<capture::Rebase<'a>>,
+    output_log: Option<&'a Arc<OutputLog>>,
+    env: &'a [(&'static str, ffi::OsString)],
+    /// Patterns for the inherited variables to leave out.
+    hidden_env: &'a [String],
+    timeout: Option<time::Duration>,
+    /// Compile and run the build crate with `cargo run`, rather than running
+    /// an executable already compiled.
+    cargo_run: Option<CargoRun<'a>>,
+    args: &'a [ffi::OsString],
+    /// A file holding what the script reads on its stdin.
+    stdin: Option<&'a path::Path>,
+    /// Keep a copy of everything the script prints.
+    record_output: bool,
+    /// Run it on a remote host instead, where it was compiled.
+    remote: Option<&'a backend::RemoteDir<'a>>,
+}
+
+/// `cargo run` of the build crate, with the inner cargo set up as for
+/// compiling it.
+struct CargoRun<'a> {
+    cargo: &'a ffi::OsStr,
+    crate_dir: &'a path::Path,
+    target_dir: Option<&'a path::Path>,
+    cargo_args: &'a [&'a str],
+    forwarded_env: &'a [(String, ffi::OsString)],
+    /// The binary to run, when the build crate has several.
+    bin: Option<&'a str>,
+    /// Have cargo keep quiet, bar its JSON messages ahead of the script's
+    /// stdout, so everything on stderr is the script's. Its own output
+    /// would otherwise be recorded as the script's, which it shares stderr
+    /// with.
+    messages_on_stdout: bool,
+}
+
+impl CargoRun<'_> {
+    fn command(&self) -> process::Command {
+        let mut command = process::Command::new(self.cargo);
+        command.arg("run");
+        if self.messages_on_stdout {
+            command.args(["--quiet", "--message-format=json"]).args(
+                self.cargo_args
+                    .iter()
+                    .filter(|arg| !Verbosity::is_flag(arg)),
+            );
+        } else {
+            command.args(self.cargo_args);
+        }
+        command
+            .arg("--manifest-path")
+            .arg(self.crate_dir.join("Cargo.toml"));
+        if let Some(bin) = self.bin {
+            command.arg("--bin").arg(bin);
+        }
+        if let Some(target_dir) = self.target_dir {
+            command.arg("--target-dir").arg(target_dir);
+        }
+        // Run where it's compiled, so that cargo finds the same config.
+        command
+            .env_clear()
+            .envs(self.forwarded_env.iter().map(|(k, v)| (k, v)))
+            .current_dir(self.crate_dir);
+        command
+    }
+}
+
+/// What was captured from a build script's stdout.
+#[derive(Default)]
+struct ScriptOutput {
+    /// The last line that wasn't empty or a directive, when a result file
+    /// was asked for, as a fallback for scripts that print their result
+    /// instead.
+    last_line: Option<String>,
+    /// The directives, when they're collected rather than passed on.
+    directives: Vec<String>,
+    status: process::ExitStatus,
+    /// Everything the script printed, when it was recorded.
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// The command running the build script on this machine.
+fn local_command(
+    build_script_path: &path::Path,
+    working_dir: &path::Path,
+    invocation: &ScriptInvocation,
+) -> process::Command {
+    let mut command = match (&invocation.cargo_run, invocation.exec_runner) {
+        (Some(cargo_run), _) => {
+            let mut command = cargo_run.command();
+            command.arg("--");
+            command
+        }
+        (None, Some([runner, runner_args @ ..])) => {
+            let mut command = process::Command::new(runner);
+            command.args(runner_args).arg(build_script_path);
+            command
+        }
+        _ => process::Command::new(build_script_path),
+    };
+    command.args(invocation.args);
+    match &invocation.cargo_run {
+        None => {
+            command.current_dir(working_dir);
+            for (var, _) in env::vars_os() {
+                if let Some(var) = var.to_str() {
+                    if passthrough::matches_any(invocation.hidden_env, var) {
+                        command.env_remove(var);
+                    }
+                }
+            }
+        }
+        // The script sees the outer build script's environment either way,
+        // bar what the inner cargo would take as its own configuration, and
+        // what it's been given already.
+        Some(cargo_run) => {
+            for (var, value) in env::vars_os() {
+                if let Some(var) = var.to_str() {
+                    if !passthrough::matches_any(invocation.hidden_env, var)
+                        && !passthrough::configures_cargo(var)
+                        && !cargo_run.forwarded_env.iter().any(|(seen, _)| seen == var)
+                    {
+                        command.env(var, value);
+                    }
+                }
+            }
+        }
+    }
+    command.envs(invocation.env.iter().cloned());
+    command
+}
+
+fn run_build_script(
+    build_script_path: &path::Path,
+    working_dir: &path::Path,
+    invocation: &ScriptInvocation,
+) -> ScriptOutput {
+    let mut command = match invocation.remote {
+        Some(remote) => remote.command(build_script_path, invocation.env, invocation.args),
+        None => local_command(build_script_path, working_dir, invocation),
+    };
+    if let Some(stdin) = invocation.stdin {
+        let stdin = fs::File::open(stdin)
+            .unwrap_or_else(|e| panic!("Couldn't open {}: {}", stdin.display(), e));
+        command.stdin(stdin);
+    }
+    if let Some(directives_file) = invocation.directives_file {
+        command.env(DIRECTIVES_FILE_ENV, directives_file);
+    }
+    if let Some(result_file) = invocation.result_file {
+        command.env(RESULT_FILE_ENV, result_file);
+    }
+    let host_channel = invocation.channel.map(|handler| {
+        let host_channel = channel::listen(handler.clone())
+            .unwrap_or_else(|e| panic!("Couldn't open build script channel: {}", e));
+        command.env(CHANNEL_ENV, host_channel.addr().to_string());
+        host_channel
+    });
+    // With directives coming from a file, nothing the script prints should
+    // reach cargo's stdout parsing.
+    let redirect_stdout = invocation.directives_file.is_some();
+
+    // Looking for a result or directives on stdout, rewriting it, or logging
+    // or recording it means capturing it.
+    let capture = invocation.result_file.is_some()
+        || invocation.collect_directives
+        || invocation.rebase.is_some()
+        || invocation.output_log.is_some()
+        || invocation.record_output;
+    let output_limit = match (invocation.output_limit, capture) {
+        (Some(limit), _) => Some(limit),
+        (None, true) => Some(OutputLimit::unlimited()),
+        (None, false) => None,
+    };
+    if let Some(limit) = output_limit {
+        let options = capture::CaptureOptions {
+            limit,
+            redirect_stdout,
+            collect_directives: invocation.collect_directives,
+            rebase: invocation.rebase,
+            output_log: invocation.output_log,
+            record: invocation.record_output,
+            cargo_messages: invocation
+                .cargo_run
+                .as_ref()
+                .is_some_and(|cargo_run| cargo_run.messages_on_stdout),
+        };
+        let captured = capture::run_limited(&mut command, options, invocation.timeout)
+            .unwrap_or_else(|e| {
+                panic!(
+                    "failed to run build script at {}: {}",
+                    build_script_path.display(),
+                    e
+                )
+            });
+        interrupt::check();
+        if let Some(host_channel) = host_channel {
+            host_channel.finish();
+        }
+        assert!(
+            captured.status.success(),
+            "Failed to run build script at {} ({})",
+            build_script_path.display(),
+            captured.status
+        );
+        return ScriptOutput {
+            last_line: Some(captured.last_line),
+            directives: captured.directives,
+            status: captured.status,
+            stdout: captured.stdout,
+            stderr: captured.stderr,
+        };
+    }
+
+    let stdout = if redirect_stdout {
+        io::stderr().into()
+    } else {
+        process::Stdio::inherit()
+    };
+    // Both streams go straight to ours, so the script's output shows up as
+    // it's printed.
+    let status = command
+        .stdout(stdout)
+        .stderr(process::Stdio::inherit())
+        .spawn()
+        .and_then(|child| {
+            let _tracked = interrupt::track(&child);
+            timeout::Watched::new(child, invocation.timeout).wait()
+        })
+        .unwrap_or_else(|e| {
+            panic!(
+                "failed to run build script at {}: {}",
+                build_script_path.display(),
+                e
+            )
+        });
+    interrupt::check();
+    if let Some(host_channel) = host_channel {
+        host_channel.finish();
+    }
+
+    assert!(
+        status.success(),
+        "Failed to run build script at {} ({})",
+        build_script_path.display(),
+        status
+    );
+    ScriptOutput {
+        status,
+        ..ScriptOutput::default()
+    }
+}
+
+/// `dir/name`, with anything left there by an earlier run removed. Building
+/// in place reuses the same directory across runs.
+fn fresh_file(dir: &path::Path, name: &str) -> path::PathBuf {
+    let path = dir.join(name);
+    if let Err(e) = fs::remove_file(&path) {
+        assert!(
+            e.kind() == io::ErrorKind::NotFound,
+            "Couldn't remove stale {}: {}",
+            path.display(),
+            e
+        );
+    }
+    path
+}
+
+/// Copy the compiled `script` to `dir/name`, returning the copy. It's copied
+/// under a name of its own and renamed into place, as the copy from an
+/// earlier run may still be running.
+fn export_executable(script: &path::Path, dir: &path::Path, name: &str) -> path::PathBuf {
+    let exported = dir.join(name);
+    let partial = dir.join(format!(".partial-{}", process::id()));
+    let copied = fs::create_dir_all(dir)
+        .and_then(|()| fs::copy(script, &partial))
+        .and_then(|_| fs::rename(&partial, &exported));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&partial);
+        panic!(
+            "Couldn't export the build script to {}: {}",
+            exported.display(),
+            e
+        );
+    }
+    exported
+}
+
+/// Locates the compiled build script, given the dir holding the `target/`
+/// dir it was built in and the name of the cargo profile it was built with.
+type ExecutableDiscovery = dyn Fn(&path::Path, &str) -> path::PathBuf;
+
+/// Stages, compiles and runs a build crate.
+///
+/// `run_build_crate` covers the common case; use this when the defaults need
+/// adjusting.
+pub struct BuildCrateRunner {
+    build_crate_src: path::PathBuf,
+    executable_discovery: Option<Box<ExecutableDiscovery>>,
+    bins: Vec<String>,
+    staging_strategy: StagingStrategy,
+    persistent_staging: bool,
+    clean_stale_after: Option<time::Duration>,
+    staging_symlinks: SymlinkPolicy,
+    staging_includes: Vec<String>,
+    staging_excludes: Vec<String>,
+    isolation: IsolationMode,
+    settings: options::Settings,
+    preflight: bool,
+    json_log: Option<path::PathBuf>,
+    output_log: Option<path::PathBuf>,
+    toolchain: Option<ToolchainOverride>,
+    edition: Option<String>,
+    error_policy: ErrorPolicy,
+    audit_command: Option<Vec<ffi::OsString>>,
+    cache_hints: Option<path::PathBuf>,
+    backend: ExecutionBackend,
+    run_strategy: RunStrategy,
+    script_args: Vec<ffi::OsString>,
+    script_stdin: Option<Vec<u8>>,
+    exec_runner: Option<Vec<ffi::OsString>>,
+    output_limit: Option<OutputLimit>,
+    compile_timeout: Option<time::Duration>,
+    run_timeout: Option<time::Duration>,
+    directive_channel: DirectiveChannel,
+    channel: Option<Arc<ChannelHandler>>,
+    print_dependency_tree: bool,
+    export_lockfile: bool,
+    lockfile_path: Option<path::PathBuf>,
+    update_lockfile: bool,
+    minimal_versions: bool,
+    vendor_dir: Option<path::PathBuf>,
+    inherit_config: Vec<String>,
+    check_overlap: bool,
+    collect_directives: bool,
+    rebase_directives: bool,
+    feature_forwarding: FeatureForwarding,
+    metadata_table: String,
+    extra_script_env: Vec<(&'static str, ffi::OsString)>,
+    script_params: collections::BTreeMap<String, String>,
+    hidden_script_env: Vec<String>,
+    own_out_dir: bool,
+    out_dir: Option<path::PathBuf>,
+    export_executable: bool,
+    pass_env: Vec<String>,
+    deny_env: Vec<String>,
+    forward_rustflags: bool,
+    rustflags: Vec<String>,
+    host_target: HostTarget,
+    docs_rs_stub: Vec<String>,
+    check_mode: CheckMode,
+    clippy: Option<Vec<String>>,
+}
+
+/// What a run produced besides its report.
+#[derive(Default)]
+pub(crate) struct Outcome {
+    /// The build script's result, for `run_and_parse`.
+    pub result: Option<String>,
+    /// The build script's directives, if they were collected rather than
+    /// passed on.
+    pub directives: Vec<String>,
+    /// What the build script printed, for `run_with_output`.
+    pub output: Option<BuildScriptOutput>,
+    /// Where the compiled build script was exported to.
+    pub executables: Vec<path::PathBuf>,
+    /// The `OUT_DIR` the build script ran with, for the build crates it
+    /// hands off to.
+    pub out_dir: Option<path::PathBuf>,
+}
+
+impl BuildCrateRunner {
+    pub fn new<P: AsRef<path::Path>>(build_crate_src: P) -> Self {
+        BuildCrateRunner {
+            build_crate_src: build_crate_src.as_ref().to_owned(),
+            executable_discovery: None,
+            bins: Vec::new(),
+            staging_strategy: StagingStrategy::default(),
+            persistent_staging: false,
+            clean_stale_after: Some(DEFAULT_STALE_AFTER),
+            staging_symlinks: SymlinkPolicy::default(),
+            staging_includes: Vec::new(),
+            staging_excludes: DEFAULT_STAGING_EXCLUDES
+                .iter()
+                .map(|exclude| exclude.to_string())
+                .collect(),
+            isolation: IsolationMode::default(),
+            settings: options::Settings::default(),
+            preflight: true,
+            json_log: None,
+            output_log: None,
+            toolchain: None,
+            edition: None,
+            error_policy: ErrorPolicy::default(),
+            audit_command: None,
+            cache_hints: None,
+            backend: ExecutionBackend::default(),
+            run_strategy: RunStrategy::default(),
+            script_args: Vec::new(),
+            script_stdin: None,
+            exec_runner: None,
+            output_limit: None,
+            compile_timeout: None,
+            run_timeout: None,
+            directive_channel: DirectiveChannel::default(),
+            channel: None,
+            print_dependency_tree: false,
+            export_lockfile: false,
+            lockfile_path: None,
+            update_lockfile: false,
+            minimal_versions: false,
+            vendor_dir: None,
+            inherit_config: Vec::new(),
+            check_overlap: false,
+            collect_directives: false,
+            rebase_directives: true,
+            feature_forwarding: FeatureForwarding::default(),
+            metadata_table: DEFAULT_METADATA_TABLE.to_owned(),
+            extra_script_env: Vec::new(),
+            script_params: collections::BTreeMap::new(),
+            hidden_script_env: Vec::new(),
+            own_out_dir: false,
+            out_dir: None,
+            export_executable: false,
+            pass_env: Vec::new(),
+            deny_env: Vec::new(),
+            forward_rustflags: false,
+            rustflags: Vec::new(),
+            host_target: HostTarget::default(),
+            docs_rs_stub: Vec::new(),
+            check_mode: CheckMode::default(),
+            clippy: None,
+        }
+    }
+
+    /// Override how the compiled build script is located. The closure
+    /// receives the dir holding the `target/` dir the build crate was
+    /// compiled in, and the profile name (e.g. "debug"), and returns the path
+    /// of the executable to run.
+    ///
+    /// That's the staging dir for a staged build, but the build crate's own
+    /// dir under `target_dir` when staged builds share one, a dir under the
+    /// outer `OUT_DIR` for a build in place, and the remote copy of the
+    /// staging dir over SSH.
+    ///
+    /// This is an escape hatch for build crates whose artifacts don't land in
+    /// `target/<profile>/<crate dir name>`, such as ones with a custom
+    /// `target-dir` or a renamed `[[bin]]`.
+    pub fn executable_discovery<F>(mut self, discovery: F) -> Self
+    where
+        F: Fn(&path::Path, &str) -> path::PathBuf + 'static,
+    {
+        self.executable_discovery = Some(Box::new(discovery));
+        self
+    }
+
+    /// Build only these of the build crate's binaries, and run each in turn,
+    /// rather than the one build script. For build crates with several
+    /// `[[bin]]`s, like a code generator and an asset packer. Their
+    /// directives all reach the outer build; `run_and_parse` reads the last
+    /// one's result. Compiled scripts aren't cached, and the build crate has
+    /// to run on this machine.
+    pub fn bins<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.bins = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Choose how the build crate is placed in the staging dir. Defaults to
+    /// `StagingStrategy::Copy`.
+    pub fn staging_strategy(mut self, strategy: StagingStrategy) -> Self {
+        self.staging_strategy = strategy;
+        self
+    }
+
+    /// Stage the build crate in the same dir on every build, rather than a
+    /// fresh one, and only bring the files that changed since up to date.
+    /// Its target dir is kept along with it, so warm rebuilds only compile
+    /// what changed.
+    pub fn persistent_staging(mut self, persistent: bool) -> Self {
+        self.persistent_staging = persistent;
+        self
+    }
+
+    /// Before staging, remove the staging dirs in the staging root that
+    /// haven't been used for `age`, as left behind by builds that were killed,
+    /// like `clean` does. Dirs kept with `keep` or `keep_on_failure` are left
+    /// alone. `DEFAULT_STALE_AFTER` unless given; `None` leaves them all be.
+    pub fn clean_stale_after(mut self, age: Option<time::Duration>) -> Self {
+        self.clean_stale_after = age;
+        self
+    }
+
+    /// Choose what staging does with symlinks in the build crate. Defaults
+    /// to `SymlinkPolicy::Follow`.
+    pub fn staging_symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.staging_symlinks = policy;
+        self
+    }
+
+    /// Leave the paths matching these globs, relative to the build crate and
+    /// `/`-separated, out of the staging dir, instead of
+    /// `DEFAULT_STAGING_EXCLUDES`. `*` matches within a name, and `**` any
+    /// number of dirs, as in `testdata/**` or `**/*.bin`. Its root `target`
+    /// dir is always left out.
+    pub fn staging_excludes<I, S>(mut self, globs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.staging_excludes = globs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Only stage the files matching these globs, like `src/**` or
+    /// `Cargo.*`, as for `staging_excludes`, which still apply. `Cargo.toml`
+    /// always is. Adds to any given before.
+    pub fn staging_includes<I, S>(mut self, globs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.staging_includes
+            .extend(globs.into_iter().map(Into::into));
+        self
+    }
+
+    /// Choose whether the build crate is always staged in a temporary
+    /// directory, or only when its surroundings would interfere with its
+    /// build. Defaults to `IsolationMode::Always`.
+    pub fn isolation(mut self, isolation: IsolationMode) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// How much the inner cargo prints. Defaults to `Verbosity::VeryVerbose`;
+    /// overridden by `CARGO_5730_VERBOSITY`.
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.settings.verbosity = verbosity;
+        self
+    }
+
+    /// The profile to compile the build crate with. Defaults to
+    /// `Profile::Debug`; `Profile::outer()` follows the outer build.
+    /// Overridden by `CARGO_5730_PROFILE`.
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.settings.profile = profile;
+        self
+    }
+
+    /// The directory to create staging dirs in. Defaults to the system temp
+    /// dir; overridden by `CARGO_5730_STAGING_ROOT`.
+    pub fn staging_root<P: AsRef<path::Path>>(mut self, staging_root: P) -> Self {
+        self.settings.staging_root = Some(staging_root.as_ref().to_owned());
+        self
+    }
+
+    /// Keep compiled build scripts in `build_cache`, keyed by `cache_key`, so
+    /// a later build with the same sources, toolchain and options runs the
+    /// cached script instead of compiling the build crate again. Off by
+    /// default; overridden by `CARGO_5730_BUILD_CACHE`. Only used for local
+    /// builds that don't export, update or compare lockfiles, since those
+    /// need the build crate resolved. The gates run only when the script is
+    /// compiled.
+    pub fn build_cache<P: AsRef<path::Path>>(mut self, build_cache: P) -> Self {
+        self.settings.build_cache = Some(build_cache.as_ref().to_owned());
+        self
+    }
+
+    /// Build staged copies of the build crate into a target dir under
+    /// `target_dir` that's kept between runs, so rebuilds only recompile what
+    /// changed instead of starting cold. Each build crate gets its own,
+    /// named after the parent crate and the build crate. Overridden by
+    /// `CARGO_5730_TARGET_DIR`. Crates built in place already keep theirs.
+    pub fn target_dir<P: AsRef<path::Path>>(mut self, target_dir: P) -> Self {
+        self.settings.target_dir = Some(target_dir.as_ref().to_owned());
+        self
+    }
+
+    /// Use `cargo_home` as the inner cargo's `CARGO_HOME`, rather than the
+    /// outer build's. Overridden by `CARGO_5730_CARGO_HOME`.
+    pub fn cargo_home<P: AsRef<path::Path>>(mut self, cargo_home: P) -> Self {
+        self.settings.cargo_home = Some(cargo_home.as_ref().to_owned());
+        self
+    }
+
+    /// Limit the inner cargo to `jobs` parallel jobs. It already shares the
+    /// outer cargo's jobserver, so this is only needed to go below that.
+    /// Overridden by `CARGO_5730_JOBS`.
+    pub fn jobs(mut self, jobs: u32) -> Self {
+        assert!(jobs > 0, "The inner cargo needs at least one job");
+        self.settings.jobs = Some(jobs.to_string());
+        self
+    }
+
+    /// Build with `--locked`, so the build crate's committed `Cargo.lock` is
+    /// used exactly, and fail rather than resolve anything differently. The
+    /// build crate must have a `Cargo.lock`, which can't be updated in the
+    /// same build. Overridden by `CARGO_5730_LOCKED`.
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.settings.locked = locked;
+        self
+    }
+
+    /// Pass `--offline` to the inner cargo. Overridden by
+    /// `CARGO_5730_OFFLINE`.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.settings.offline = offline;
+        self
+    }
+
+    /// Leave the staging dir in place once the build script has run.
+    /// Overridden by `CARGO_5730_KEEP`.
+    pub fn keep(mut self, keep: bool) -> Self {
+        self.settings.keep = keep;
+        self
+    }
+
+    /// Leave the staging dir in place if the build crate fails to build or
+    /// run, to look into why. Its path is printed as a warning. Overridden by
+    /// `CARGO_5730_KEEP=failure`.
+    pub fn keep_on_failure(mut self, keep_on_failure: bool) -> Self {
+        self.settings.keep_on_failure = keep_on_failure;
+        self
+    }
+
+    /// Enable these features of the build crate, e.g. depending on the
+    /// parent's own configuration. Adds to any given before.
+    pub fn features<I, S>(mut self, features: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.settings
+            .features
+            .extend(features.into_iter().map(Into::into));
+        self
+    }
+
+    /// Let the build crate know which features the parent crate is built
+    /// with, by enabling its own features of the same names, by telling the
+    /// build script in `CARGO_5730_PARENT_FEATURES`, or both. Defaults to
+    /// `FeatureForwarding::Off`, keeping the two apart as usual.
+    pub fn forward_features(mut self, forwarding: FeatureForwarding) -> Self {
+        self.feature_forwarding = forwarding;
+        self
+    }
+
+    /// Build the build crate without its default features.
+    pub fn no_default_features(mut self, no_default_features: bool) -> Self {
+        self.settings.no_default_features = no_default_features;
+        self
+    }
+
+    /// Build the build crate with all of its features.
+    pub fn all_features(mut self, all_features: bool) -> Self {
+        self.settings.all_features = all_features;
+        self
+    }
+
+    /// Extra arguments for the inner `cargo build`, such as `--locked`,
+    /// passed after the ones the other options add.
+    pub fn cargo_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.settings
+            .extra_cargo_args
+            .extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Forward these variables from the outer build script's environment to
+    /// the inner cargo, when they're set, on top of `PATH` and the few others
+    /// it gets by default. A trailing `*` matches any variable with that
+    /// prefix, e.g. `NIX_*`. The build script itself sees the whole outer
+    /// environment regardless.
+    pub fn pass_env<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.pass_env.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Never forward these variables to the inner cargo, even the ones it
+    /// gets by default. Patterns are as for `pass_env`.
+    pub fn deny_env<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.deny_env.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Build the build crate with the outer build's rustflags too, e.g. for
+    /// `-C target-cpu` or sanitizers. They're left out by default, since
+    /// they're meant for the parent's target rather than the build machine.
+    pub fn forward_rustflags(mut self, forward: bool) -> Self {
+        self.forward_rustflags = forward;
+        self
+    }
+
+    /// Pass these flags to rustc when building the build crate, and only
+    /// then, after any forwarded ones.
+    pub fn rustflags<I, S>(mut self, flags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.rustflags.extend(flags.into_iter().map(Into::into));
+        self
+    }
+
+    /// The rustflags the build crate is built with.
+    fn inner_rustflags(&self) -> Vec<String> {
+        let mut rustflags = Vec::new();
+        if self.forward_rustflags {
+            rustflags.extend(passthrough::outer_rustflags());
+        }
+        rustflags.extend(self.rustflags.iter().cloned());
+        rustflags
+    }
+
+    /// Check that cargo, rustc and the linker are reachable through the
+    /// forwarded `PATH` before compiling. Enabled by default.
+    pub fn preflight(mut self, preflight: bool) -> Self {
+        self.preflight = preflight;
+        self
+    }
+
+    /// Look for the tools the inner build will need, returning the ones that
+    /// can't be found. `run` does this itself unless preflight checks are
+    /// disabled.
+    pub fn check_tools(&self) -> Vec<MissingTool> {
+        let (cargo, forwarded_env) = self.inner_cargo();
+        self.check_tools_with(&cargo, &forwarded_env)
+    }
+
+    fn check_tools_with(
+        &self,
+        cargo: &ffi::OsStr,
+        forwarded_env: &[(String, ffi::OsString)],
+    ) -> Vec<MissingTool> {
+        let search_path = forwarded_env
+            .iter()
+            .find(|(key, _)| *key == "PATH")
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default();
+        preflight::check(cargo, &search_path, &self.build_crate_src)
+    }
+
+    /// Build the build crate with this toolchain, rather than the one running
+    /// the outer build. The toolchain must already be installed.
+    pub fn toolchain(mut self, toolchain: ToolchainOverride) -> Self {
+        self.toolchain = Some(toolchain);
+        self
+    }
+
+    /// Compile the staged crate with this Rust edition (e.g. `"2021"`),
+    /// whatever its manifest says. Useful for old build crates that need a
+    /// newer toolchain. Since the original manifest is never modified, this
+    /// always stages the crate.
+    pub fn edition<S: Into<String>>(mut self, edition: S) -> Self {
+        self.edition = Some(edition.into());
+        self
+    }
+
+    /// Whether a failing phase aborts straight away, or the runner first
+    /// gathers diagnostics (the staged manifest, inner environment and cargo
+    /// arguments) to include in the error. Defaults to
+    /// `ErrorPolicy::FailFast`.
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Run this command in the compiled build crate's directory before the
+    /// build script is executed, failing the build if it exits unsuccessfully.
+    /// For example `["cargo", "deny", "check"]` or `["cargo", "audit"]`;
+    /// build-time code is part of the supply chain too.
+    pub fn audit_command<I, S>(mut self, command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<ffi::OsStr>,
+    {
+        self.audit_command = Some(
+            command
+                .into_iter()
+                .map(|arg| arg.as_ref().to_owned())
+                .collect(),
+        );
+        self
+    }
+
+    /// A deterministic hash of everything that affects the isolated build:
+    /// the sources of the build crate and of the crates it depends on by
+    /// path, the vendored sources, the inherited cargo config, the inner
+    /// toolchain, and the runner's options. Suitable as a cache key for the
+    /// compiled build script or its target dir. Also available after a run
+    /// with a `build_cache` or `cache_hints` as `BuildReport::cache_key`.
+    pub fn cache_key(&self) -> String {
+        let settings = self.settings();
+        let (cargo, forwarded_env) = self.inner_cargo();
+        let toolchain = report::Toolchain::detect(&cargo, &self.build_crate_src, &forwarded_env);
+        self.cache_key_with(&settings, &toolchain)
+    }
+
+    fn cache_key_with(&self, settings: &options::Settings, toolchain: &Toolchain) -> String {
+        let mut options = vec![
+            ("cargo_args", settings.cargo_args().join(" ")),
+            ("edition", format!("{:?}", self.edition)),
+            ("toolchain", format!("{:?}", self.toolchain)),
+            ("target", format!("{:?}", self.cross_target())),
+            ("host_target", format!("{:?}", self.host_target)),
+            ("minimal_versions", self.minimal_versions.to_string()),
+            ("vendor_dir", format!("{:?}", self.vendor_dir)),
+            ("rustflags", self.inner_rustflags().join(" ")),
+            ("inherit_config", self.inherit_config.join(" ")),
+        ];
+        // The cargo configs above the build crate only matter for the keys
+        // inherited from them.
+        if !self.inherit_config.is_empty() {
+            for config in cargo_config::configs_above(&self.build_crate_src) {
+                let content = fs::read(&config).unwrap_or_default();
+                options.push((
+                    "cargo_config",
+                    String::from_utf8_lossy(&content).into_owned(),
+                ));
+            }
+        }
+
+        let dependencies = manifest::path_dependencies(&self.build_crate_src)
+            .unwrap_or_else(|e| panic!("Couldn't find the build crate's path dependencies: {}", e));
+        let mut trees: Vec<&path::Path> = vec![&self.build_crate_src];
+        trees.extend(dependencies.iter().map(path::PathBuf::as_path));
+        // A vendor dir inside the build crate is hashed along with it.
+        if let Some(vendor_dir) = &self.vendor_dir {
+            if !vendor_dir.starts_with(&self.build_crate_src) {
+                trees.push(vendor_dir);
+            }
+        }
+        fingerprint::cache_key(&trees, toolchain, &options)
+    }
+
+    /// After a successful run, write a small JSON file to this path naming the
+    /// directories worth caching between CI runs and the key to cache them
+    /// under, e.g. for `actions/cache`.
+    pub fn cache_hints<P: AsRef<path::Path>>(mut self, cache_hints: P) -> Self {
+        self.cache_hints = Some(cache_hints.as_ref().to_owned());
+        self
+    }
+
+    /// Which target to compile the build crate for when it runs on this
+    /// machine. Defaults to `HostTarget::Auto`, which picks the machine's own
+    /// architecture over an emulated toolchain's host when it can.
+    pub fn host_target(mut self, host_target: HostTarget) -> Self {
+        self.host_target = host_target;
+        self
+    }
+
+    /// Directives to emit instead of running the build crate on docs.rs,
+    /// e.g. `["cargo:rustc-cfg=stub_bindings"]`. docs.rs builds without
+    /// network access, so the build crate is never compiled or run there
+    /// (when `DOCS_RS` is set); by default nothing is emitted in its place.
+    pub fn docs_rs_stub<I, S>(mut self, directives: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.docs_rs_stub = directives.into_iter().map(Into::into).collect();
+        for directive in &self.docs_rs_stub {
+            if let Err(e) = directives::validate(directive) {
+                panic!("Invalid docs.rs stub directive {:?}: {}", directive, e);
+            }
+        }
+        self
+    }
+
+    /// What to do when the outer build is only `cargo check` or
+    /// rust-analyzer, which is recognized automatically or told by
+    /// `CARGO_5730_CHECK`. Defaults to `CheckMode::Run`.
+    pub fn check_mode(mut self, check_mode: CheckMode) -> Self {
+        self.check_mode = check_mode;
+        self
+    }
+
+    /// Run `cargo clippy` on the build crate before compiling it, failing the
+    /// build if clippy reports any errors. `lint_args` go after clippy's `--`
+    /// to set lint levels: `["-D", "warnings"]` denies every warning, and
+    /// `["-D", "clippy::pedantic"]` enforces the pedantic lints too. Clippy
+    /// always runs on this machine, whatever the backend.
+    pub fn clippy_gate<I, S>(mut self, lint_args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.clippy = Some(lint_args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Stage the build crate and run just the clippy gate on it, with
+    /// `-D warnings` unless `clippy_gate` set other lint levels. This is what
+    /// `cargo 5730 clippy` does, for checking build crates in CI without
+    /// running them.
+    pub fn run_clippy(&self) {
+        let settings = self.settings();
+        let (cargo, forwarded_env) = self.inner_cargo();
+
+        let mut cargo_args = settings.cargo_args();
+        let mut build_dir = None;
+        let (crate_dir, target_dir) = match self.in_place_dir() {
+            Some(in_place_dir) => {
+                cargo_args.extend(isolation::PINNED_CONFIG_ARGS);
+                (
+                    self.build_crate_src.clone(),
+                    Some(in_place_dir.join("target")),
+                )
+            }
+            None => {
+                let staged = build_dir.insert(self.stage(&settings, &self.base_dir()));
+                let target_dir = self
+                    .shared_target_root(&settings)
+                    .map(|root| root.join("target"));
+                (staged.path.clone(), target_dir)
+            }
+        };
+
+        let deny_warnings = ["-D".to_owned(), "warnings".to_owned()];
+        gates::run_clippy(
+            &crate_dir,
+            target_dir.as_deref(),
+            &cargo,
+            &cargo_args,
+            &forwarded_env,
+            self.clippy.as_deref().unwrap_or(&deny_warnings),
+        );
+    }
+
+    /// The build crate's absolute path. Relative paths are taken from the
+    /// parent crate's manifest dir.
+    fn base_dir(&self) -> path::PathBuf {
+        if self.build_crate_src.is_absolute() {
+            return paths::normalize(&self.build_crate_src);
+        }
+        let manifest_dir =
+            env::var_os("CARGO_MANIFEST_DIR").expect("Can't get CARGO_MANIFEST_DIR from env");
+        paths::normalize(&path::Path::new(&manifest_dir).join(&self.build_crate_src))
+    }
+
+    /// Where to compile and run the build crate. Defaults to
+    /// `ExecutionBackend::Local`. Any other backend always stages the crate.
+    pub fn backend(mut self, backend: ExecutionBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Choose how a build crate compiled on this machine is run. Defaults
+    /// to `RunStrategy::CargoRun`.
+    pub fn run_strategy(mut self, strategy: RunStrategy) -> Self {
+        self.run_strategy = strategy;
+        self
+    }
+
+    /// Pass `arg` on the build script's command line, after any given before.
+    pub fn arg<S: AsRef<ffi::OsStr>>(mut self, arg: S) -> Self {
+        self.script_args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Pass these on the build script's command line, after any given before.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<ffi::OsStr>,
+    {
+        self.script_args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Give the build script `input` to read on its stdin, rather than
+    /// nothing.
+    pub fn stdin<B: Into<Vec<u8>>>(mut self, input: B) -> Self {
+        self.script_stdin = Some(input.into());
+        self
+    }
+
+    /// Run the compiled build script through this command, with the script's
+    /// path appended, like cargo's `target.<triple>.runner`. For example
+    /// `["qemu-aarch64", "-L", "/usr/aarch64-linux-gnu"]` to run a build
+    /// script compiled by `CrossBackend`. Not used by `SshBackend`, which
+    /// runs the script on the remote host.
+    pub fn exec_runner<I, S>(mut self, command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<ffi::OsStr>,
+    {
+        self.exec_runner = Some(
+            command
+                .into_iter()
+                .map(|arg| arg.as_ref().to_owned())
+                .collect(),
+        );
+        self
+    }
+
+    /// Cap how much of the build script's output reaches the outer build,
+    /// keeping its head and tail and every `cargo:` directive. Unlimited by
+    /// default. Only applies to scripts run on this machine.
+    pub fn output_limit(mut self, limit: OutputLimit) -> Self {
+        self.output_limit = Some(limit);
+        self
+    }
+
+    /// Kill the inner cargo, and fail the build, if compiling the build crate
+    /// takes longer than `timeout`. No limit by default. Setting it compiles
+    /// the build crate before running it, as `RunStrategy::BuildThenExec`
+    /// does, so the two are limited separately. Over SSH, it's `ssh` that's
+    /// killed; `cross build` isn't limited.
+    pub fn compile_timeout(mut self, timeout: time::Duration) -> Self {
+        self.compile_timeout = Some(timeout);
+        self
+    }
+
+    /// Kill the build script, and fail the build, if it runs for longer than
+    /// `timeout`, as when it hangs waiting on the network. No limit by
+    /// default. Only applies to scripts run on this machine. With
+    /// `RunStrategy::CargoRun`, it covers compiling the build crate too.
+    pub fn run_timeout(mut self, timeout: time::Duration) -> Self {
+        self.run_timeout = Some(timeout);
+        self
+    }
+
+    /// How the build script passes its `cargo:` directives back. Defaults to
+    /// `DirectiveChannel::Stdout`. `DirectiveChannel::File` needs the script
+    /// to be run on this machine.
+    pub fn directive_channel(mut self, channel: DirectiveChannel) -> Self {
+        self.directive_channel = channel;
+        self
+    }
+
+    /// Talk to the build script while it runs. The script connects with
+    /// `Channel::from_env`, and `handler` is called on another thread with
+    /// this end of the channel, to send it requests and receive its progress
+    /// or results. `Channel::recv` returns `None` once the script exits.
+    /// Needs the script to be run on this machine.
+    pub fn channel<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&mut Channel) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.channel = Some(Arc::new(handler));
+        self
+    }
+
+    /// Print what `cargo tree` says the isolated build resolves to before
+    /// compiling it. It's always included in the diagnostics gathered by
+    /// `ErrorPolicy::CollectAll`.
+    pub fn print_dependency_tree(mut self, print: bool) -> Self {
+        self.print_dependency_tree = print;
+        self
+    }
+
+    /// Once the staged crate's dependencies have been resolved, copy its
+    /// `Cargo.lock` back beside the build crate's source, so the exact
+    /// dependency set can be reviewed and committed.
+    pub fn export_lockfile(mut self, export: bool) -> Self {
+        self.export_lockfile = export;
+        self
+    }
+
+    /// Run `cargo update` on the staged crate before compiling it, and write
+    /// the refreshed `Cargo.lock` back as `export_lockfile` does. Off by
+    /// default, so dependency bumps for build tooling are deliberate.
+    pub fn update_lockfile(mut self, update: bool) -> Self {
+        self.update_lockfile = update;
+        self
+    }
+
+    /// Resolve the staged crate's dependencies to the oldest versions its
+    /// requirements allow, with `cargo update -Z minimal-versions`, to check
+    /// those requirements are actually sufficient. Needs a nightly inner
+    /// cargo, e.g. via `toolchain`. The resulting lockfile is never exported.
+    pub fn minimal_versions(mut self, minimal_versions: bool) -> Self {
+        self.minimal_versions = minimal_versions;
+        self
+    }
+
+    /// Build the build crate's dependencies from `vendor_dir`, as made by
+    /// `cargo vendor`, rather than downloading them. The directory is staged
+    /// along with the crate unless it's inside it, sources are replaced with
+    /// it in the staged crate's cargo config, and the inner cargo runs
+    /// `--offline`. The build crate's `Cargo.lock` should match what was
+    /// vendored.
+    pub fn vendor_dir<P: AsRef<path::Path>>(mut self, vendor_dir: P) -> Self {
+        self.vendor_dir = Some(vendor_dir.as_ref().to_owned());
+        self
+    }
+
+    /// Keep these settings from the cargo configs above the build crate, e.g.
+    /// `net.git-fetch-with-cli` or `http.proxy`, which staging otherwise
+    /// leaves behind along with the rest. Each is a dotted key; naming a
+    /// table, like `registries`, keeps all of it. They're added to the
+    /// staged crate's `.cargo/config.toml` wherever its own config doesn't
+    /// set them. Relative paths in them aren't adjusted.
+    pub fn inherit_config<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.inherit_config.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Like `export_lockfile`, but copy the lockfile to `path` instead.
+    pub fn export_lockfile_to<P: AsRef<path::Path>>(mut self, path: P) -> Self {
+        self.export_lockfile = true;
+        self.lockfile_path = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Once the staged crate's dependencies are resolved, warn about every
+    /// crate that's also in the parent crate's `Cargo.lock`, and whether the
+    /// versions match. Shared crates are where this isolation is protecting
+    /// the parent from feature unification; with none, it may not be needed.
+    pub fn check_dependency_overlap(mut self, check: bool) -> Self {
+        self.check_overlap = check;
+        self
+    }
+
+    fn cross_target(&self) -> Option<&str> {
+        match &self.backend {
+            ExecutionBackend::Cross(cross) => Some(cross.target()),
+            _ => None,
+        }
+    }
+
+    /// The settings for this run: the runner's, with any overrides from the
+    /// environment applied and forwarded features added.
+    fn settings(&self) -> options::Settings {
+        let mut settings = overrides::apply(self.settings.clone());
+        if self.vendor_dir.is_some() {
+            settings.offline = true;
+        }
+        if self.feature_forwarding.features() {
+            let manifest_path = self.base_dir().join("Cargo.toml");
+            let manifest = fs::read_to_string(&manifest_path)
+                .unwrap_or_else(|e| panic!("Couldn't read {}: {}", manifest_path.display(), e));
+            let forwarded = features::matching(&manifest, &features::parent_features())
+                .unwrap_or_else(|e| panic!("Can't parse the build crate's Cargo.toml: {}", e));
+            settings.features.extend(forwarded);
+        }
+        settings
+    }
+
+    /// The cargo to run for the inner build, and the environment to run it in.
+    fn inner_cargo(&self) -> (ffi::OsString, Vec<(String, ffi::OsString)>) {
+        assert!(env::var_os("PATH").is_some(), "Can't get PATH from env");
+        let mut forwarded_env = passthrough::forwarded(&self.pass_env, &self.deny_env);
+        if let Some(cargo_home) = overrides::apply(self.settings.clone()).cargo_home {
+            forwarded_env.retain(|(var, _)| var != "CARGO_HOME");
+            forwarded_env.push(("CARGO_HOME".to_owned(), cargo_home.into()));
+        }
+        let rustflags = self.inner_rustflags();
+        if !rustflags.is_empty() {
+            forwarded_env.push((
+                "CARGO_ENCODED_RUSTFLAGS".to_owned(),
+                rustflags.join("\x1f").into(),
+            ));
+        }
+        let cargo = match &self.toolchain {
+            Some(toolchain) => toolchain::apply(toolchain, &mut forwarded_env),
+            None => env::var_os("CARGO").expect("Can't get CARGO from env"),
+        };
+        (cargo, forwarded_env)
+    }
+
+    /// Write the `BuildReport` for each run to this file, as JSON.
+    pub fn json_log<P: AsRef<path::Path>>(mut self, json_log: P) -> Self {
+        self.json_log = Some(json_log.as_ref().to_owned());
+        self
+    }
+
+    /// Record everything the inner `cargo build` and the build script print
+    /// to this file, one JSON object per line, with the time each line
+    /// arrived. stdout and stderr are interleaved in the order they were
+    /// printed, as far as it can be told. The output is still passed on as
+    /// usual, and the file is written even if the run fails. Over SSH, the
+    /// remote `cargo build` is logged as it streams back; what `cross`
+    /// prints isn't logged.
+    pub fn output_log<P: AsRef<path::Path>>(mut self, output_log: P) -> Self {
+        self.output_log = Some(output_log.as_ref().to_owned());
+        self
+    }
+
+    /// The build crate's dir name, which cargo names the executable after. A
+    /// name that isn't valid UTF-8 can't be a crate name anyway.
+    fn executable_name(&self) -> borrow::Cow<'_, str> {
+        self.build_crate_src
+            .file_name()
+            .map(|os_str| os_str.to_string_lossy())
+            .unwrap_or_else(|| {
+                panic!(
+                    "Couldn't get file name from build crate src dir: {}",
+                    self.build_crate_src.display(),
+                )
+            })
+    }
+
+    /// Where the compiled build script is, when compiled for `target` rather
+    /// than the toolchain's host.
+    fn build_script_path(
+        &self,
+        build_dir: &path::Path,
+        profile: &str,
+        target: Option<&str>,
+    ) -> path::PathBuf {
+        if let Some(discovery) = &self.executable_discovery {
+            return error::foreign(|| discovery(build_dir, profile));
+        }
 
-/// A scoped wrapper for the directory where we'll compile and run the build script.
-struct BuildDir {
-    pub path: path::PathBuf,
-}
+        let mut target_dir = build_dir.join("target");
+        if let Some(target) = target {
+            target_dir.push(target);
+        }
+        let file_name = format!("{}{}", self.executable_name(), executable_suffix(target));
+        target_dir.join(profile).join(file_name)
+    }
 
-impl BuildDir {
-    fn new() -> Self {
-        let mut random_data = [0u8; 16];
-        let mut file = fs::File::open("/dev/urandom").expect("failed to open /dev/urandom");
-        file.read_exact(&mut random_data)
-            .expect("failed to read /dev/urandom");
+    /// The dir holding the shared `target/` dir of this build crate, when
+    /// staged builds are to share one. Named after the parent crate too,
+    /// since build crates are usually all called `build-script`.
+    fn shared_target_root(&self, settings: &options::Settings) -> Option<path::PathBuf> {
+        if !matches!(self.backend, ExecutionBackend::Local) {
+            return None;
+        }
+        let target_dir = settings.target_dir.as_ref()?;
+        let namespace = match env::var("CARGO_PKG_NAME") {
+            Ok(parent) => format!("{}-{}", parent, self.executable_name()),
+            Err(_) => self.executable_name().into_owned(),
+        };
+        Some(target_dir.join(namespace))
+    }
 
-        let mut hex_str = String::new();
-        for digit in random_data.iter() {
-            hex_str = hex_str + &format!("{:x}", digit)
+    /// Which of the executables cargo reported building is `bin`, or without
+    /// one, the build script: the one named after the build crate's dir, or
+    /// else the only one. `expected` when there's no telling, or when
+    /// `executable_discovery` was given to say where the build script is.
+    fn pick_executable(
+        &self,
+        reported: &[(String, path::PathBuf)],
+        bin: Option<&str>,
+        expected: &path::Path,
+    ) -> path::PathBuf {
+        if let Some(bin) = bin {
+            let named = reported.iter().find(|(name, _)| name == bin);
+            return named.map_or_else(|| expected.to_owned(), |(_, path)| path.clone());
+        }
+        if self.executable_discovery.is_some() {
+            return expected.to_owned();
         }
+        let named = reported
+            .iter()
+            .position(|(name, _)| *name == self.executable_name());
+        match (named, reported.len()) {
+            (Some(i), _) => reported[i].1.clone(),
+            (None, 1) => reported[0].1.clone(),
+            _ => expected.to_owned(),
+        }
+    }
 
-        let mut dir = env::temp_dir();
-        dir.push(format!("build-script-{}", hex_str));
+    /// Whether to compile and run the build crate with `cargo run`, as asked,
+    /// given whether the compiled script is to be cached and whether the
+    /// build crate's lockfile is needed before it runs.
+    fn uses_cargo_run(&self, caching: bool, reading_lockfile: bool) -> bool {
+        if self.run_strategy != RunStrategy::CargoRun
+            || !matches!(self.backend, ExecutionBackend::Local)
+        {
+            return false;
+        }
 
-        BuildDir {
-            path: dir,
+        if caching {
+            println!("Compiling build crate before running it, to cache the compiled script");
+            return false;
+        }
+
+        if self.exec_runner.is_some() {
+            println!("Compiling build crate before running it with the exec runner");
+            return false;
+        }
+
+        if self.executable_discovery.is_some() {
+            println!(
+                "Compiling build crate before running it, to find it with executable_discovery"
+            );
+            return false;
+        }
+
+        if self.audit_command.is_some() || reading_lockfile {
+            println!("Compiling build crate before running it, for its lockfile");
+            return false;
+        }
+
+        if self.export_executable {
+            println!("Compiling build crate before running it, to export the compiled script");
+            return false;
+        }
+
+        if self.compile_timeout.is_some() {
+            println!("Compiling build crate before running it, to limit how long that takes");
+            return false;
         }
+
+        true
     }
-}
 
-impl Drop for BuildDir {
-    fn drop(&mut self) {
-        // some paranoia before running 'rm -rf'
-        assert!(self.path.starts_with(env::temp_dir()));
+    /// Where to build the crate without staging it, if that's allowed and safe.
+    fn in_place_dir(&self) -> Option<path::PathBuf> {
+        if self.isolation == IsolationMode::Always {
+            return None;
+        }
 
-        println!("Removing build crate staging dir: {}", self.path.display());
-        fs::remove_dir_all(&self.path).expect(&format!(
-            "Couldn't clean up build dir: {}",
-            self.path.display()
-        ));
+        if !matches!(self.backend, ExecutionBackend::Local) {
+            return None;
+        }
+
+        if self.edition.is_some() {
+            println!("Staging build crate to override its edition");
+            return None;
+        }
+
+        if self.minimal_versions {
+            println!("Staging build crate to resolve minimal versions");
+            return None;
+        }
+
+        if self.vendor_dir.is_some() {
+            println!("Staging build crate to build it from vendored sources");
+            return None;
+        }
+
+        if self.isolation == IsolationMode::InPlace {
+            let build_crate_dir = self.build_crate_src.canonicalize().ok()?;
+            if let Some(workspace) = isolation::enclosing_workspace(&build_crate_dir) {
+                println!(
+                    "Staging build crate because of enclosing workspace at {}",
+                    workspace.display()
+                );
+                return None;
+            }
+            for config in cargo_config::configs_above(&build_crate_dir) {
+                println!(
+                    "cargo:warning=Building {} in place, where the cargo config {} applies to it \
+                     beyond the settings pinned for it",
+                    build_crate_dir.display(),
+                    config.display()
+                );
+            }
+        } else if let Some(reason) = isolation::isolation_needed(&self.build_crate_src) {
+            println!("Staging build crate because of {}", reason);
+            return None;
+        }
+
+        let out_dir = self.outer_out_dir()?;
+        Some(out_dir.join("cargo-5730").join(&*self.executable_name()))
     }
-}
 
-fn cp_r(in_dir: &path::Path, out_dir: &path::Path) {
-    let res = process::Command::new("cp")
-        .arg("-r")
-        .arg(in_dir)
-        .arg(out_dir)
-        .stdout(process::Stdio::inherit())
-        .stderr(process::Stdio::inherit())
-        .output()
-        .expect(&format!(
-            "Failed to cp -r {} {}",
-            in_dir.display(),
-            out_dir.display()
-        ));
+    /// Stage, compile and run the build crate, returning a summary of what
+    /// happened.
+    pub fn run(&self) -> BuildReport {
+        self.execute(false, false).0
+    }
 
-    assert!(
-        res.status.success(),
-        "Failed to cp -r {} {} with {:?}",
-        in_dir.display(),
-        out_dir.display(),
-        res
-    );
-}
+    /// Like `run`, but a failure is returned, saying which part of the run
+    /// it happened in, rather than raised as a panic. Failures are caught as
+    /// they unwind, so this needs the build script built with
+    /// `panic = "unwind"`, the default; with `panic = "abort"` a failure
+    /// still aborts it. The panic is printed to stderr as usual on its way.
+    /// A panic in a closure given to the runner, such as
+    /// `executable_discovery`, is the caller's own, and isn't caught.
+    pub fn try_run(&self) -> Result<BuildReport, Error> {
+        error::catch(|| self.run())
+    }
+
+    /// Run the build crate for a value it computes, and parse it with
+    /// `parse`.
+    ///
+    /// The script writes its result to the file named by
+    /// `CARGO_5730_RESULT_FILE`. Failing that, the last line it printed that
+    /// wasn't empty or a `cargo:` directive is used. Needs the script to be
+    /// run on this machine.
+    pub fn run_and_parse<T, E, F>(&self, parse: F) -> Result<T, E>
+    where
+        F: Fn(&str) -> Result<T, E>,
+    {
+        let (_, outcome) = self.execute(true, false);
+        parse(
+            &outcome
+                .result
+                .expect("The build script wasn't run, so it has no result to parse"),
+        )
+    }
 
+    /// Like `run`, but also returning what the build script printed, for the
+    /// outer build script to make use of, e.g. to read back the path of a
+    /// file it generated. Its output and directives are passed on as usual,
+    /// though the directives only once it's finished. A build crate with
+    /// several binaries has their output in turn, and the status of the last.
+    /// Needs the script to be run on this machine. With
+    /// `RunStrategy::CargoRun`, the inner cargo is kept quiet, bar its
+    /// warnings and errors, so none of its own output is taken for the
+    /// script's.
+    pub fn run_with_output(&self) -> (BuildReport, BuildScriptOutput) {
+        let (report, outcome) = self.execute(false, true);
+        let output = outcome
+            .output
+            .expect("The build script wasn't run, so it has no output to return");
+        (report, output)
+    }
 
-fn qualify_cargo_toml_paths_in_text(cargo_toml_content: &str, base_dir: &path::Path) -> String {
-    // This is completely manual to avoid introducing any dependencies in this
-    // library, since the whole point is to work around dependency issues.
-
-    // Lacking a real parser due to constraints, look for a couple of common
-    // patterns. TODO: Roll a little parser for this.
-    let mut cargo_toml = cargo_toml_content.to_owned();
-    cargo_toml = cargo_toml.replace("path = \"", &format!("path = \"{}/", base_dir.display()));
-    cargo_toml = cargo_toml.replace("path=\"", &format!("path=\"{}/", base_dir.display()));
-    cargo_toml = cargo_toml.replace("path = '", &format!("path = '{}/", base_dir.display()));
-    cargo_toml = cargo_toml.replace("path='", &format!("path='{}/", base_dir.display()));
-    cargo_toml
+    /// Pass `[package.metadata.<table>]` from the parent's `Cargo.toml` to the
+    /// build script, as JSON in `CARGO_5730_METADATA`, so its configuration
+    /// can live in the manifest rather than in `build.rs`. Defaults to
+    /// `cargo-5730`; nothing is passed if the table doesn't exist.
+    pub fn metadata_table<S: Into<String>>(mut self, table: S) -> Self {
+        self.metadata_table = table.into();
+        self
+    }
+
+    /// Hold back the directives the build script emits, returning them in
+    /// `Outcome::directives` instead, so they can be merged with others.
+    /// Only applies to scripts run on this machine.
+    pub(crate) fn collect_directives(mut self, collect: bool) -> Self {
+        self.collect_directives = collect;
+        self
+    }
+
+    /// Make relative paths in the build script's `rustc-link-search` and
+    /// `rerun-if-changed` directives absolute, against the build crate's
+    /// source dir, since cargo would take them as relative to the parent
+    /// crate. Run in a staged copy, as with `RunStrategy::CargoRun`, a path
+    /// that's only in the copy is made absolute against that instead. Everything else it prints to stdout is labelled with
+    /// the build crate's name. On by default; turning it off passes stdout
+    /// through untouched. Only applies to scripts run on this machine.
+    pub fn rebase_directives(mut self, rebase: bool) -> Self {
+        self.rebase_directives = rebase;
+        self
+    }
+
+    /// Set `var` for the build script, on top of the usual `CARGO_5730_*`
+    /// variables, in place of any value it would inherit from the outer
+    /// build script.
+    pub fn script_env<V: Into<ffi::OsString>>(mut self, var: &'static str, value: V) -> Self {
+        self.extra_script_env.push((var, value.into()));
+        self
+    }
+
+    /// Give the build script the parameter `key`, replacing any given before.
+    /// The parameters are written to a file in the staging dir, as a JSON
+    /// object of strings, named by `CARGO_5730_PARAMS_FILE`; a build crate
+    /// that depends on this crate can read them with `read_script_params`.
+    /// Only applies to scripts run on this machine.
+    pub fn script_param<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.script_params.insert(key.into(), value.into());
+        self
+    }
+
+    /// Give the build script each of these parameters, as `script_param`
+    /// does.
+    pub fn script_params<I, K, V>(mut self, params: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.script_params
+            .extend(params.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Keep these variables from the outer build script's environment away
+    /// from the build script, which otherwise sees all of it: `TARGET`,
+    /// `OUT_DIR`, `CARGO_CFG_*` and the rest, so crates like `cc` work in it.
+    /// A trailing `*` matches any variable with that prefix, e.g. `DEP_*`.
+    /// Only applies to scripts run on this machine.
+    pub fn hide_script_env<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.hidden_script_env
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Give the build script a directory of its own under the outer
+    /// `OUT_DIR`, named after the build crate, as its `OUT_DIR`. The parent
+    /// crate then includes what it generates from there, e.g.
+    /// `include!(concat!(env!("OUT_DIR"), "/build-script/bindings.rs"))`.
+    pub fn own_out_dir(mut self, own_out_dir: bool) -> Self {
+        self.own_out_dir = own_out_dir;
+        self
+    }
+
+    /// Take `out_dir` as the outer build's `OUT_DIR`, rather than the one
+    /// cargo sets for the outer build script, and give it to the build script
+    /// as its own. For running the build crate from outside a build script,
+    /// e.g. from an xtask or a test.
+    pub fn out_dir<P: AsRef<path::Path>>(mut self, out_dir: P) -> Self {
+        self.out_dir = Some(out_dir.as_ref().to_owned());
+        self
+    }
+
+    /// The outer build's `OUT_DIR`, if there is one.
+    fn outer_out_dir(&self) -> Option<path::PathBuf> {
+        self.out_dir
+            .clone()
+            .or_else(|| env::var_os("OUT_DIR").map(path::PathBuf::from))
+    }
+
+    /// Copy the compiled build script to `cargo-5730-bin/` under the outer
+    /// `OUT_DIR`, where it outlives the staging dir, for the parent crate's
+    /// tests or a later build step to run again. The copies are listed in
+    /// `BuildReport::executables`. Needs the build crate compiled on this
+    /// machine.
+    pub fn export_executable(mut self, export: bool) -> Self {
+        self.export_executable = export;
+        self
+    }
+
+    /// The report and outcome when the build crate isn't built or run, with
+    /// `directives` emitted in its place.
+    fn skipped(
+        &self,
+        settings: &options::Settings,
+        start: time::Instant,
+        directives: Vec<String>,
+    ) -> (BuildReport, Outcome) {
+        let report = BuildReport {
+            build_crate: self.build_crate_src.clone(),
+            staging_dir: None,
+            profile: settings.profile,
+            cargo_args: Vec::new(),
+            toolchain: Toolchain::default(),
+            cache_key: String::new(),
+            duration: start.elapsed(),
+            executables: Vec::new(),
+        };
+        if self.collect_directives {
+            return (
+                report,
+                Outcome {
+                    directives,
+                    ..Outcome::default()
+                },
+            );
+        }
+        for directive in directives {
+            println!("{}", directive);
+        }
+        (report, Outcome::default())
+    }
+
+    pub(crate) fn execute(&self, want_result: bool, want_output: bool) -> (BuildReport, Outcome) {
+        let start = time::Instant::now();
+        let build_crate_src = self.build_crate_src.as_path();
+        println!("cargo:rerun-if-changed={}", build_crate_src.display());
+        // Cargo doesn't reliably notice edits inside a watched directory on
+        // every filesystem, so name each source file too. The directory still
+        // catches files being added.
+        watch_sources(build_crate_src);
+        // Edits to the crates it depends on by path change the build script
+        // as much as edits to its own sources do.
+        match manifest::path_dependencies(build_crate_src) {
+            Ok(dependencies) => {
+                for dependency in dependencies {
+                    println!("cargo:rerun-if-changed={}", dependency.display());
+                    watch_sources(&dependency);
+                }
+            }
+            Err(e) => println!(
+                "cargo:warning=Couldn't find the path dependencies of {} to watch them: {}",
+                build_crate_src.display(),
+                e
+            ),
+        }
+        for var in overrides::ALL {
+            println!("cargo:rerun-if-env-changed={}", var);
+        }
+        let settings = self.settings();
+        let mut cargo_args = settings.cargo_args();
+
+        if env::var_os(DOCS_RS_ENV).is_some() {
+            println!(
+                "Not running build crate {} on docs.rs",
+                build_crate_src.display()
+            );
+            return self.skipped(&settings, start, self.docs_rs_stub.clone());
+        }
+
+        // Whatever's started from here on is to be cleaned up if the build
+        // is interrupted.
+        interrupt::install();
+        interrupt::check();
+        let (cargo, mut forwarded_env) = self.inner_cargo();
+        let base_dir = self.base_dir();
+
+        let out_dir = self.outer_out_dir().filter(|out_dir| out_dir.is_dir());
+        // Recorded runs are keyed like the build cache, so a check never
+        // replays a run of different sources, toolchain or options.
+        let replay = match self.check_mode {
+            CheckMode::Replay => {
+                let toolchain =
+                    report::Toolchain::detect(&cargo, &self.build_crate_src, &forwarded_env);
+                Some(check::Replay::new(
+                    &settings.staging_root.clone().unwrap_or_else(env::temp_dir),
+                    &base_dir,
+                    &self.cache_key_with(&settings, &toolchain),
+                ))
+            }
+            CheckMode::Run | CheckMode::Skip => None,
+        };
+        if self.check_mode != CheckMode::Run && check::is_check_only() {
+            if self.check_mode == CheckMode::Skip {
+                println!(
+                    "Not running build crate {} for a check-only build",
+                    build_crate_src.display()
+                );
+                return self.skipped(&settings, start, Vec::new());
+            }
+            match replay
+                .as_ref()
+                .and_then(|replay| replay.load(out_dir.as_deref()))
+            {
+                Some(directives) => {
+                    println!(
+                        "Replaying the last run of build crate {} for a check-only build",
+                        build_crate_src.display()
+                    );
+                    return self.skipped(&settings, start, directives);
+                }
+                None => println!(
+                    "cargo:warning=Build crate {} hasn't run yet, so there's nothing to replay for this check; running it",
+                    build_crate_src.display()
+                ),
+            }
+        }
+
+        if self.preflight && matches!(self.backend, ExecutionBackend::Local) {
+            preflight::report(&self.check_tools_with(&cargo, &forwarded_env));
+        }
+
+        // Either build the crate where it is, or stage a copy of it.
+        // `build_dir` cleans up after itself, so it has to outlive the build.
+        let mut build_dir = None;
+        // Held until the build script has run, so another build sharing the
+        // target dir can't rebuild it in the meantime.
+        let mut _target_lock = None;
+        let (crate_dir, target_dir, artifacts_dir, target_root) = match self.in_place_dir() {
+            Some(in_place_dir) => {
+                println!(
+                    "Building build crate in place at {}, with target dir under {}",
+                    build_crate_src.display(),
+                    in_place_dir.display()
+                );
+                let target_dir = in_place_dir.join("target");
+                let target_root = in_place_dir.clone();
+                cargo_args.extend(isolation::PINNED_CONFIG_ARGS);
+                (
+                    build_crate_src.to_owned(),
+                    Some(target_dir),
+                    in_place_dir,
+                    target_root,
+                )
+            }
+            None => {
+                let staged = build_dir.insert(self.stage(&settings, &base_dir));
+                match self.shared_target_root(&settings) {
+                    Some(shared) => {
+                        fs::create_dir_all(&shared).unwrap_or_else(|e| {
+                            panic!("Couldn't create {}: {}", shared.display(), e)
+                        });
+                        _target_lock = Some(lock::exclusive(
+                            &shared.join(TARGET_LOCK),
+                            &shared.join("target"),
+                        ));
+                        println!(
+                            "Building build crate into {}",
+                            shared.join("target").display()
+                        );
+                        (
+                            staged.path.clone(),
+                            Some(shared.join("target")),
+                            staged.path.clone(),
+                            shared,
+                        )
+                    }
+                    None => (
+                        staged.path.clone(),
+                        None,
+                        staged.path.clone(),
+                        staged.path.clone(),
+                    ),
+                }
+            }
+        };
+
+        let toolchain = match &self.backend {
+            ExecutionBackend::Local | ExecutionBackend::Cross(_) => {
+                report::Toolchain::detect(&cargo, &crate_dir, &forwarded_env)
+            }
+            ExecutionBackend::Ssh(ssh) => ssh.detect_toolchain(),
+        };
+        println!(
+            "Inner toolchain: {}, {}",
+            toolchain
+                .cargo_version
+                .as_deref()
+                .unwrap_or("unknown cargo"),
+            toolchain
+                .rustc_version
+                .as_deref()
+                .unwrap_or("unknown rustc")
+        );
+
+        // Hashing every source is only worth it when something uses the key.
+        let cache_key = match settings.build_cache.is_some() || self.cache_hints.is_some() {
+            true => self.cache_key_with(&settings, &toolchain),
+            false => String::new(),
+        };
+
+        let host_target = match &self.backend {
+            ExecutionBackend::Local => host::choose(
+                &self.host_target,
+                toolchain.host.as_deref(),
+                host::native_arch(),
+                |triple| {
+                    host::target_installed(report::rustc(&forwarded_env), &forwarded_env, triple)
+                },
+            ),
+            _ => None,
+        };
+        if let Some(host_target) = &host_target {
+            forwarded_env.push(("CARGO_BUILD_TARGET".to_owned(), host_target.into()));
+        }
+
+        let runs_locally = match &self.backend {
+            ExecutionBackend::Local => true,
+            ExecutionBackend::Ssh(_) => false,
+            ExecutionBackend::Cross(_) => self.exec_runner.is_some(),
+        };
+        assert!(
+            runs_locally || self.directive_channel == DirectiveChannel::Stdout,
+            "DirectiveChannel::File needs the build script to run on this machine"
+        );
+        assert!(
+            runs_locally || self.channel.is_none(),
+            "A build script channel needs the build script to run on this machine"
+        );
+        assert!(
+            runs_locally || !want_result,
+            "run_and_parse needs the build script to run on this machine"
+        );
+        // Over SSH, what the script prints is streamed back as if it ran here.
+        assert!(
+            runs_locally || matches!(self.backend, ExecutionBackend::Ssh(_)) || !want_output,
+            "run_with_output needs the build script's output passed back"
+        );
+        assert!(
+            runs_locally
+                || (self.script_args.is_empty()
+                    && self.script_stdin.is_none()
+                    && self.script_params.is_empty()),
+            "Arguments, stdin and parameters for the build script need it to run on this machine"
+        );
+        assert!(
+            self.bins.is_empty() || matches!(self.backend, ExecutionBackend::Local),
+            "Naming the binaries to run needs the build crate compiled and run on this machine"
+        );
+        assert!(
+            !self.export_executable || !matches!(self.backend, ExecutionBackend::Ssh(_)),
+            "Exporting the compiled build script needs it compiled on this machine"
+        );
+
+        // Recording a run for check builds to replay, or returning its output,
+        // needs its directives.
+        let record_replay = self.check_mode == CheckMode::Replay && runs_locally;
+        let collect_directives = self.collect_directives || record_replay || want_output;
+
+        if self.minimal_versions {
+            assert!(
+                !matches!(self.backend, ExecutionBackend::Ssh(_)),
+                "Minimal-versions resolution isn't supported over SSH"
+            );
+            assert!(
+                !self.export_lockfile && !self.update_lockfile,
+                "A minimal-versions lockfile can't be exported or written back"
+            );
+            assert!(
+                toolchain.cargo_is_nightly(),
+                "Minimal-versions resolution needs a nightly cargo, not {}",
+                toolchain
+                    .cargo_version
+                    .as_deref()
+                    .unwrap_or("unknown cargo")
+            );
+        }
+
+        if settings.locked {
+            assert!(
+                !self.update_lockfile && !self.minimal_versions,
+                "A locked build can't update the build crate's lockfile"
+            );
+            lockfile::require(&crate_dir, build_crate_src);
+        }
+
+        let lockfile_dest = match (&self.backend, self.export_lockfile || self.update_lockfile) {
+            (_, false) => None,
+            (ExecutionBackend::Ssh(_), true) => {
+                println!(
+                    "cargo:warning=Can't update or export the lockfile of a build crate built over SSH"
+                );
+                None
+            }
+            (_, true) => Some(
+                self.lockfile_path
+                    .clone()
+                    .unwrap_or_else(|| base_dir.join("Cargo.lock")),
+            ),
+        };
+
+        let parent_lockfile = if self.check_overlap {
+            let manifest_dir =
+                env::var_os("CARGO_MANIFEST_DIR").expect("Can't get CARGO_MANIFEST_DIR from env");
+            let parent_lockfile = overlap::parent_lockfile(path::Path::new(&manifest_dir));
+            if parent_lockfile.is_none() {
+                println!("cargo:warning=No Cargo.lock found for the parent crate to compare dependencies with");
+            }
+            parent_lockfile
+        } else {
+            None
+        };
+
+        if self.print_dependency_tree {
+            println!(
+                "Build crate dependency tree:\n{}",
+                diagnostics::dependency_tree(&crate_dir, &cargo, &cargo_args, &forwarded_env)
+                    .trim_end()
+            );
+        }
+
+        let metadata = script_env::metadata(&self.metadata_table);
+        let mut script_vars = script_env::vars(&crate_dir, settings.profile, metadata.as_deref());
+        if let Some(out_dir) = &self.out_dir {
+            script_vars.push(("OUT_DIR", out_dir.into()));
+        }
+        script_vars.extend(self.extra_script_env.iter().cloned());
+        if !self.script_params.is_empty() {
+            let params_file = artifacts_dir.join("cargo-5730-params.json");
+            params::write(&params_file, &self.script_params);
+            script_vars.push((PARAMS_FILE_ENV, params_file.into()));
+        }
+        if let (true, Some(out_dir)) = (self.own_out_dir, &out_dir) {
+            let own_out_dir = out_dir.join(&*self.executable_name());
+            fs::create_dir_all(&own_out_dir).unwrap_or_else(|e| {
+                panic!(
+                    "Couldn't create the build script's OUT_DIR {}: {}",
+                    own_out_dir.display(),
+                    e
+                )
+            });
+            script_vars.push(("OUT_DIR", own_out_dir.into()));
+        }
+        if self.feature_forwarding.env() {
+            let parent_features = features::parent_features().join(",");
+            script_vars.push((PARENT_FEATURES_ENV, parent_features.into()));
+        }
+        // Later settings win, as they do for the child.
+        let script_out_dir = script_vars
+            .iter()
+            .rev()
+            .find(|(var, _)| *var == "OUT_DIR")
+            .map(|(_, out_dir)| path::PathBuf::from(out_dir))
+            .or_else(|| out_dir.clone());
+
+        // Where the compiled build script is expected to end up, unless cargo
+        // says otherwise.
+        let requested_target = requested_target(&cargo_args, &forwarded_env);
+        let build_script_path = self.build_script_path(
+            &target_root,
+            settings.profile.dir_name(),
+            self.cross_target().or(requested_target.as_deref()),
+        );
+        let build_cache = settings.build_cache.as_deref().filter(|_| {
+            matches!(self.backend, ExecutionBackend::Local)
+                && lockfile_dest.is_none()
+                && parent_lockfile.is_none()
+                && !self.minimal_versions
+                && self.bins.is_empty()
+        });
+        let cached_script = build_cache.and_then(|cache_dir| {
+            cache::cached_script(cache_dir, &self.executable_name(), &cache_key)
+        });
+        let cargo_run = cached_script.is_none()
+            && self.uses_cargo_run(
+                build_cache.is_some(),
+                lockfile_dest.is_some() || parent_lockfile.is_some(),
+            );
+
+        // The binaries to run, by name, or just the build script.
+        let bins: Vec<Option<&str>> = match self.bins.is_empty() {
+            true => vec![None],
+            false => self.bins.iter().map(|bin| Some(bin.as_str())).collect(),
+        };
+        let mut compile_args = cargo_args.clone();
+        for bin in &self.bins {
+            compile_args.extend(["--bin", bin]);
+        }
+
+        let output_log = self.output_log.as_deref().map(OutputLog::new);
+        let build_and_run = || {
+            error::enter(Phase::Compile);
+            if let Some(cached_script) = &cached_script {
+                println!(
+                    "Using the build script cached at {}",
+                    cached_script.display()
+                );
+            }
+            if let (Some(lint_args), None) = (&self.clippy, &cached_script) {
+                gates::run_clippy(
+                    &crate_dir,
+                    target_dir.as_deref(),
+                    &cargo,
+                    &cargo_args,
+                    &forwarded_env,
+                    lint_args,
+                );
+            }
+
+            let remote = match &self.backend {
+                ExecutionBackend::Ssh(ssh) => Some(ssh.stage(&crate_dir, settings.keep)),
+                _ => None,
+            };
+
+            if self.update_lockfile && lockfile_dest.is_some() {
+                lockfile::update(&crate_dir, &cargo, &cargo_args, &forwarded_env, false);
+            }
+            if self.minimal_versions {
+                lockfile::update(&crate_dir, &cargo, &cargo_args, &forwarded_env, true);
+            }
+
+            let scripts: Vec<(Option<&str>, path::PathBuf)> = match (&self.backend, &cached_script)
+            {
+                (_, Some(cached_script)) => vec![(None, cached_script.clone())],
+                // Compiled by `cargo run`, on its way to running them.
+                _ if cargo_run => bins
+                    .iter()
+                    .map(|&bin| (bin, build_script_path.clone()))
+                    .collect(),
+                (ExecutionBackend::Cross(cross), None) => {
+                    cross.compile(&crate_dir, &cargo_args, &forwarded_env);
+                    vec![(None, build_script_path.clone())]
+                }
+                (ExecutionBackend::Ssh(_), None) => {
+                    let remote = remote.as_ref().expect("staged on the remote host");
+                    let reported = remote.compile(
+                        &compile_args,
+                        self.audit_command.as_deref(),
+                        output_log.as_ref(),
+                        self.compile_timeout,
+                    );
+                    let expected = self.build_script_path(
+                        remote.path(),
+                        settings.profile.dir_name(),
+                        self.cross_target().or(requested_target.as_deref()),
+                    );
+                    bins.iter()
+                        .map(|&bin| (bin, self.pick_executable(&reported, bin, &expected)))
+                        .collect()
+                }
+                (_, None) => {
+                    let reported = compile_build_crate(
+                        &crate_dir,
+                        target_dir.as_deref(),
+                        &cargo,
+                        &compile_args,
+                        &forwarded_env,
+                        output_log.as_ref(),
+                        self.compile_timeout,
+                    );
+                    bins.iter()
+                        .map(|&bin| {
+                            let expected = match bin {
+                                Some(bin) => build_script_path.with_file_name(format!(
+                                    "{}{}",
+                                    bin,
+                                    executable_suffix(requested_target.as_deref())
+                                )),
+                                None => build_script_path.clone(),
+                            };
+                            (bin, self.pick_executable(&reported, bin, &expected))
+                        })
+                        .collect()
+                }
+            };
+
+            if let Some(lockfile_dest) = &lockfile_dest {
+                lockfile::export(&crate_dir, lockfile_dest);
+            }
+            if let Some(parent_lockfile) = &parent_lockfile {
+                overlap::report(&crate_dir, parent_lockfile);
+            }
+
+            // A remote build is audited where it's compiled.
+            if let (Some(audit_command), None, None) =
+                (&self.audit_command, &cached_script, &remote)
+            {
+                gates::run_audit(audit_command, &crate_dir, &cargo);
+            }
+            if let (Some(cache_dir), None) = (build_cache, &cached_script) {
+                cache::store_script(
+                    cache_dir,
+                    &self.executable_name(),
+                    &cache_key,
+                    &scripts[0].1,
+                );
+            }
+
+            let executables = match (self.export_executable, &out_dir) {
+                (false, _) => Vec::new(),
+                (true, None) => {
+                    println!("cargo:warning=No OUT_DIR to export the compiled build script to");
+                    Vec::new()
+                }
+                (true, Some(out_dir)) => scripts
+                    .iter()
+                    .map(|(bin, script)| {
+                        let name = format!(
+                            "{}{}",
+                            bin.map_or(self.executable_name(), borrow::Cow::Borrowed),
+                            executable_suffix(requested_target.as_deref())
+                        );
+                        export_executable(script, &out_dir.join("cargo-5730-bin"), &name)
+                    })
+                    .collect(),
+            };
+
+            error::enter(Phase::Run);
+            if let (ExecutionBackend::Cross(cross), None) = (&self.backend, &self.exec_runner) {
+                cross.run(&crate_dir, &cargo_args, &forwarded_env);
+                return Outcome {
+                    executables,
+                    out_dir: script_out_dir,
+                    ..Outcome::default()
+                };
+            }
+
+            let mut outcome = Outcome {
+                executables,
+                out_dir: script_out_dir.clone(),
+                ..Outcome::default()
+            };
+            for (bin, script) in &scripts {
+                if let Some(bin) = bin {
+                    println!(
+                        "Running {} from build crate {}",
+                        bin,
+                        build_crate_src.display()
+                    );
+                }
+                let directives_file = match self.directive_channel {
+                    DirectiveChannel::Stdout => None,
+                    DirectiveChannel::File => {
+                        Some(fresh_file(&artifacts_dir, "cargo-5730-directives"))
+                    }
+                };
+                let result_file = if want_result {
+                    Some(fresh_file(&artifacts_dir, "cargo-5730-result"))
+                } else {
+                    None
+                };
+                let stdin_file = self.script_stdin.as_ref().map(|input| {
+                    let stdin_file = fresh_file(&artifacts_dir, "cargo-5730-stdin");
+                    fs::write(&stdin_file, input).unwrap_or_else(|e| {
+                        panic!("Couldn't write {}: {}", stdin_file.display(), e)
+                    });
+                    stdin_file
+                });
+
+                // Built then run, the script runs in its source dir; with
+                // `cargo run`, in the staged crate, so relative paths it
+                // prints may only exist there.
+                let label = format!(
+                    "[{}] ",
+                    bin.map_or(self.executable_name(), borrow::Cow::Borrowed)
+                );
+                let rebase = if self.rebase_directives {
+                    Some(capture::Rebase {
+                        base: &base_dir,
+                        staged: cargo_run.then_some(crate_dir.as_path()),
+                        label: &label,
+                    })
+                } else {
+                    None
+                };
+                let invocation = ScriptInvocation {
+                    exec_runner: self.exec_runner.as_deref(),
+                    output_limit: self.output_limit,
+                    directives_file: directives_file.as_deref(),
+                    channel: self.channel.as_ref(),
+                    result_file: result_file.as_deref(),
+                    collect_directives,
+                    rebase,
+                    output_log: output_log.as_ref(),
+                    env: &script_vars,
+                    hidden_env: &self.hidden_script_env,
+                    timeout: self.run_timeout,
+                    cargo_run: cargo_run.then_some(CargoRun {
+                        cargo: &cargo,
+                        crate_dir: &crate_dir,
+                        target_dir: target_dir.as_deref(),
+                        cargo_args: &cargo_args,
+                        forwarded_env: &forwarded_env,
+                        bin: *bin,
+                        messages_on_stdout: want_output,
+                    }),
+                    args: &self.script_args,
+                    stdin: stdin_file.as_deref(),
+                    record_output: want_output,
+                    remote: remote.as_ref(),
+                };
+                let script = match cargo_run {
+                    true => &crate_dir,
+                    false => script,
+                };
+                let script_start = time::Instant::now();
+                let ScriptOutput {
+                    last_line,
+                    directives: mut collected,
+                    status,
+                    stdout,
+                    stderr,
+                } = run_build_script(script, build_crate_src, &invocation);
+                if want_output {
+                    let output = outcome.output.get_or_insert_with(Default::default);
+                    output.stdout.extend(stdout);
+                    output.stderr.extend(stderr);
+                    output.status = status;
+                    output.duration += script_start.elapsed();
+                }
+
+                if let Some(directives_file) = &directives_file {
+                    let mut directives = directives::read(directives_file);
+                    if self.rebase_directives {
+                        for directive in &mut directives {
+                            *directive = directives::rebase(
+                                directive,
+                                &base_dir,
+                                cargo_run.then_some(crate_dir.as_path()),
+                            );
+                        }
+                    }
+                    if collect_directives {
+                        collected.extend(directives);
+                    } else {
+                        for directive in directives {
+                            println!("{}", directive);
+                        }
+                    }
+                }
+                outcome.result =
+                    result_file.map(|result_file| script_result::read(&result_file, last_line));
+                outcome.directives.extend(collected);
+            }
+            if let (Some(remote), Some(out_dir)) = (&remote, &outcome.out_dir) {
+                remote.pull_out_dir(out_dir);
+            }
+            outcome
+        };
+
+        // Note what's in OUT_DIR now, to check what the script generates
+        // against other build crates' output afterwards.
+        let outputs_before = out_dir.as_deref().map(outputs::snapshot);
+
+        let mut outcome = match self.error_policy {
+            ErrorPolicy::FailFast => build_and_run(),
+            ErrorPolicy::CollectAll => panic::catch_unwind(panic::AssertUnwindSafe(build_and_run))
+                .unwrap_or_else(|payload| {
+                    if interrupt::interrupted() || error::is_foreign(&*payload) {
+                        panic::resume_unwind(payload);
+                    }
+                    let diagnostics = diagnostics::for_failed_build(
+                        &crate_dir,
+                        &cargo,
+                        &cargo_args,
+                        &forwarded_env,
+                        &toolchain,
+                    );
+                    panic!(
+                        "{}\n{}",
+                        diagnostics::panic_message(&*payload),
+                        diagnostics.render()
+                    );
+                }),
+        };
+
+        let generated = match (&out_dir, &outputs_before) {
+            (Some(out_dir), Some(outputs_before)) => outputs::record(
+                out_dir,
+                outputs_before,
+                &build_crate_src.display().to_string(),
+            ),
+            _ => Vec::new(),
+        };
+        if let (true, Some(replay)) = (record_replay, &replay) {
+            replay.save(&outcome.directives, out_dir.as_deref(), &generated);
+        }
+        if let Some(output) = &mut outcome.output {
+            output.directives = outcome.directives.clone();
+        }
+        if !self.collect_directives {
+            for directive in outcome.directives.drain(..) {
+                println!("{}", directive);
+            }
+        }
+
+        let report = BuildReport {
+            build_crate: self.build_crate_src.clone(),
+            staging_dir: build_dir.as_ref().map(|dir| dir.path.clone()),
+            profile: settings.profile,
+            cargo_args: cargo_args.iter().map(|arg| arg.to_string()).collect(),
+            toolchain,
+            cache_key,
+            duration: start.elapsed(),
+            executables: outcome.executables.clone(),
+        };
+        if let Some(cache_hints) = &self.cache_hints {
+            // The registry and git checkouts are what a cold inner build
+            // spends its time downloading; an in-place target dir makes
+            // rebuilds incremental.
+            let mut paths = Vec::new();
+            if let Some(cargo_home) = settings.cargo_home.clone().or_else(cache::cargo_home) {
+                paths.push(cargo_home.join("registry"));
+                paths.push(cargo_home.join("git"));
+            }
+            paths.extend(target_dir);
+            let key = format!("cargo-5730-{}-{}", self.executable_name(), report.cache_key);
+            cache::write_hints(cache_hints, &key, &paths);
+        }
+        if let Some(json_log) = &self.json_log {
+            fs::write(json_log, report.to_json() + "\n").unwrap_or_else(|e| {
+                panic!("Couldn't write JSON log to {}: {}", json_log.display(), e)
+            });
+        }
+        (report, outcome)
+    }
+
+    /// Copy the build crate into a staging dir and fix up its manifest.
+    fn stage(&self, settings: &options::Settings, base_dir: &path::Path) -> BuildDir {
+        let staging_root = settings.staging_root.clone().unwrap_or_else(env::temp_dir);
+        if let Some(age) = self.clean_stale_after {
+            gc::clean(&staging_root, age);
+        }
+        let (mut build_dir, staged) = match self.persistent_staging {
+            true => BuildDir::persistent(
+                staging_root,
+                &self.executable_name(),
+                base_dir,
+                self.outer_out_dir().as_deref(),
+            ),
+            false => {
+                let build_dir = BuildDir::new(
+                    staging_root,
+                    &self.executable_name(),
+                    base_dir,
+                    settings.keep,
+                );
+                (build_dir, false)
+            }
+        };
+        build_dir.keep_on_failure = settings.keep_on_failure;
+        // What staging adds beside the build crate's own files, which
+        // bringing a staged crate up to date leaves alone.
+        let preserved = [STAGING_MARKER, "Cargo.toml", vendor::STAGED_VENDOR_DIR];
+        let walk = staging::Walk::new(
+            self.staging_strategy,
+            self.staging_symlinks,
+            &self.staging_includes,
+            &self.staging_excludes,
+        );
+
+        // Copy the build crate into /tmp to avoid the influence of .cargo/config
+        // settings in the build crate's parent, which cargo gives us no way to
+        // ignore.
+        error::enter(Phase::Copy);
+        if staged {
+            println!(
+                "Updating build crate source from {} in {}",
+                self.build_crate_src.display(),
+                build_dir.path.display()
+            );
+            staging::sync(&self.build_crate_src, &build_dir.path, &walk, &preserved);
+        } else {
+            println!(
+                "Copying build crate source from {} to {}",
+                self.build_crate_src.display(),
+                build_dir.path.display()
+            );
+            staging::stage(&self.build_crate_src, &build_dir.path, &walk);
+        }
+
+        if cfg!(windows) {
+            let staged_files = fingerprint::source_files(&build_dir.path).unwrap_or_default();
+            if let Some(longest) = paths::past_max_path(&staged_files) {
+                println!(
+                    "cargo:warning=Paths in the staging dir {} run to {} characters, past the \
+                     260 that tools without long path support can take; set a shorter \
+                     staging_root, such as C:\\b, or enable long paths",
+                    build_dir.path.display(),
+                    longest
+                );
+            }
+        }
+
+        // Having copied the crate, we need to fix any relative paths that were in
+        // the Cargo.toml
+        error::enter(Phase::ManifestRewrite);
+        qualify_cargo_toml_paths(
+            &self.build_crate_src.join("Cargo.toml"),
+            &build_dir.path.join("Cargo.toml"),
+            base_dir,
+            self.edition.as_deref(),
+        );
+
+        let mut cargo_config = cargo_config::StagedConfig::read(&build_dir.path);
+        for (key, value) in cargo_config::inherited(&self.build_crate_src, &self.inherit_config) {
+            let key: Vec<&str> = key.split('.').collect();
+            cargo_config.fill(&key, &value);
+        }
+        if let Some(vendor_dir) = &self.vendor_dir {
+            // A vendor dir inside the build crate came along with it.
+            let staged_vendor_dir = match vendor_dir.strip_prefix(&self.build_crate_src) {
+                Ok(inside) => inside.to_owned(),
+                Err(_) => {
+                    println!("Copying vendored sources from {}", vendor_dir.display());
+                    let staged_vendor_dir = build_dir.path.join(vendor::STAGED_VENDOR_DIR);
+                    error::enter(Phase::Copy);
+                    let walk =
+                        staging::Walk::new(self.staging_strategy, self.staging_symlinks, &[], &[]);
+                    if staged_vendor_dir.is_dir() {
+                        staging::sync(vendor_dir, &staged_vendor_dir, &walk, &[]);
+                    } else {
+                        fs::create_dir(&staged_vendor_dir).unwrap_or_else(|e| {
+                            panic!("Couldn't create {}: {}", staged_vendor_dir.display(), e)
+                        });
+                        staging::stage(vendor_dir, &staged_vendor_dir, &walk);
+                    }
+                    path::PathBuf::from(vendor::STAGED_VENDOR_DIR)
+                }
+            };
+            let staged_vendor_dir =
+                paths::to_toml(&staged_vendor_dir).unwrap_or_else(|e| panic!("{}", e));
+            vendor::configure(&mut cargo_config, &build_dir.path, &staged_vendor_dir);
+        }
+        cargo_config.pin_defaults();
+        cargo_config.write();
+        let user_configs: Vec<_> = cache::cargo_home()
+            .into_iter()
+            .flat_map(|home| ["config", "config.toml"].map(|name| home.join(name)))
+            .filter_map(|path| path.canonicalize().ok())
+            .collect();
+        for config in cargo_config::configs_above(&build_dir.path) {
+            if !user_configs.contains(&config) {
+                println!(
+                    "cargo:warning=The staging dir {} is under the cargo config {}, which applies \
+                     to the build crate beyond the settings pinned in its staged config; stage \
+                     it elsewhere with staging_root",
+                    build_dir.path.display(),
+                    config.display()
+                );
+            }
+        }
+
+        error::enter(Phase::Setup);
+        build_dir
+    }
 }
 
-fn qualify_cargo_toml_paths(cargo_toml_path: &path::Path, base_dir: &path::Path) {
-    let cargo_toml = fs::read_to_string(cargo_toml_path).expect(&format!(
-        "Can't read Cargo.toml to stream from {}",
-        cargo_toml_path.display()
-    ));
-    let cargo_toml = qualify_cargo_toml_paths_in_text(&cargo_toml, &base_dir);
-
-    fs::write(cargo_toml_path, cargo_toml).expect(&format!(
-        "Failed to write modified Cargo.toml at {}",
-        cargo_toml_path.display()
-    ));
+pub fn run_build_crate<P: AsRef<path::Path>>(build_crate_src: P) {
+    BuildCrateRunner::new(build_crate_src).run();
 }
 
-fn compile_build_crate(build_dir: &BuildDir, cargo: &str, path: &str, ssh_auth_sock: &str, rustup_home: &str, rustup_toolchain: &str) {
-    let res = process::Command::new(cargo)
-        .args(&["build", "-vv"])
-        .env_clear()
-        .env("PATH", path)
-        .env("SSH_AUTH_SOCK", ssh_auth_sock)
-        .env("RUSTUP_HOME", rustup_home)
-        .env("RUSTUP_TOOLCHAIN", rustup_toolchain)
-        .current_dir(&build_dir.path)
-        .stdout(process::Stdio::inherit())
-        .stderr(process::Stdio::inherit())
-        .output()
-        .expect("failed to compile build-script crate");
+/// Like `run_build_crate`, but a failure is returned rather than raised as a
+/// panic. As with `BuildCrateRunner::try_run`, this needs the build script
+/// built with `panic = "unwind"`, the default:
+///
+/// ```no_run
+/// if let Err(e) = cargo_5730::try_run_build_crate("build-script") {
+///     println!("cargo:warning={}; using pregenerated bindings", e);
+/// }
+/// ```
+pub fn try_run_build_crate<P: AsRef<path::Path>>(build_crate_src: P) -> Result<(), Error> {
+    BuildCrateRunner::new(build_crate_src).try_run().map(drop)
+}
 
+/// Run the build crate in the conventional `build-script/` directory next to
+/// the parent crate's `Cargo.toml`.
+pub fn run_default_build_crate() {
+    let manifest_dir =
+        env::var_os("CARGO_MANIFEST_DIR").expect("Can't get CARGO_MANIFEST_DIR from env");
+    let build_crate_manifest = path::Path::new(&manifest_dir)
+        .join(DEFAULT_BUILD_CRATE_DIR)
+        .join("Cargo.toml");
     assert!(
-        res.status.success(),
-        "Failed to run compile build crate at {} with {:#?}",
-        build_dir.path.display(),
-        res
+        build_crate_manifest.is_file(),
+        "No build crate found: expected a Cargo.toml at {}",
+        build_crate_manifest.display()
     );
+
+    run_build_crate(DEFAULT_BUILD_CRATE_DIR);
 }
 
-fn run_build_script(build_dir: &BuildDir, executable_name: &str, working_dir: &path::Path) {
-    // run the build script
-    let build_script_path = build_dir
-        .path
-        .join("target")
-        .join("debug")
-        .join(executable_name);
-
-    let res = process::Command::new(&build_script_path)
-        .current_dir(&working_dir)
-        .stdout(process::Stdio::inherit())
-        .stderr(process::Stdio::inherit())
-        .output()
-        .expect(&format!(
-            "failed to run build script at {}",
-            build_script_path.display()
+/// Run the build crate the way the parent's `[package.metadata.cargo-5730]`
+/// describes, so build policy lives in the manifest:
+///
+/// ```toml
+/// [package.metadata.cargo-5730]
+/// build-crate = "build-script"
+/// profile = "release"
+/// staging = "symlink-overlay"
+/// ```
+///
+/// Recognized keys are `build-crate`, `profile`, `verbosity`, `staging`,
+/// `isolation`, `error-policy`, `check`, `staging-root`, `staging-symlinks`,
+/// `staging-includes`, `staging-excludes`, `build-cache`, `target-dir`,
+/// `cargo-home`, `edition`, `offline`, `locked`, `vendor-dir`,
+/// `inherit-config`, `keep`, `keep-on-failure`, `persistent-staging`,
+/// `clean-stale-after`, `preflight`, `features`, `no-default-features`,
+/// `all-features`, `forward-features`, `own-out-dir`, `export-executable`,
+/// `pass-env`, `deny-env`, `forward-rustflags`, `rustflags`, `cargo-args`,
+/// `clippy`, `docs-rs-stub`, `compile-timeout`, `run-timeout`, `run-strategy`
+/// and `bins`. The whole table is passed on to the build script as well, so
+/// it can hold the script's own configuration too. Without the table, this is
+/// `run_default_build_crate()`.
+pub fn run_configured() {
+    let manifest_dir =
+        env::var_os("CARGO_MANIFEST_DIR").expect("Can't get CARGO_MANIFEST_DIR from env");
+    match config::parent_metadata(DEFAULT_METADATA_TABLE) {
+        Some(table) => {
+            config::runner(&table, path::Path::new(&manifest_dir)).run();
+        }
+        None => run_default_build_crate(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_dir_requires_marker() {
+        let source_dir = path::Path::new("/src/build-script");
+        let build_dir = BuildDir::new(env::temp_dir(), "marker-test", source_dir, false);
+        let path = build_dir.path.clone();
+        assert!(path.join(STAGING_MARKER).is_file());
+
+        // A second staging dir for the same build crate in the same process
+        // doesn't clash with the first.
+        let other = BuildDir::new(env::temp_dir(), "marker-test", source_dir, false);
+        assert_ne!(other.path, path);
+        let prefix = match cfg!(windows) {
+            true => "c5730-",
+            false => "cargo-5730-marker-test-",
+        };
+        assert!(other
+            .path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(prefix));
+        drop(other);
+
+        fs::remove_file(path.join(STAGING_MARKER)).unwrap();
+        drop(build_dir);
+        assert!(path.is_dir());
+        fs::remove_dir_all(&path).unwrap();
+
+        let build_dir = BuildDir::new(env::temp_dir(), "marker-test", source_dir, false);
+        let path = build_dir.path.clone();
+        drop(build_dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_build_dir_kept_on_failure() {
+        let source_dir = path::Path::new("/src/build-script");
+        let mut build_dir = BuildDir::new(env::temp_dir(), "failure-test", source_dir, false);
+        build_dir.keep_on_failure = true;
+        let path = build_dir.path.clone();
+        let failed = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+            let _build_dir = build_dir;
+            panic!("the build crate failed");
+        }));
+        assert!(failed.is_err());
+        assert!(path.join(STAGING_MARKER).is_file());
+        // Marked, so it's never cleaned up as stale.
+        assert!(path.join(KEPT_MARKER).is_file());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compile_with_spaced_unicode_paths() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = env::temp_dir().join(format!(
+            "cargo-5730 spawn test \u{e9}\u{e8} {}",
+            process::id()
         ));
+        let tool_dir = root.join("tool dir \u{2603}");
+        let crate_dir = root.join("build crate \u{fc}");
+        fs::create_dir_all(&tool_dir).unwrap();
+        fs::create_dir_all(&crate_dir).unwrap();
 
-    assert!(
-        res.status.success(),
-        "Failed to run build script at {} with {:#?}",
-        build_script_path.display(),
-        res
-    );
+        // A stand-in for cargo that records how it was invoked.
+        let fake_cargo = tool_dir.join("fake cargo");
+        fs::write(
+            &fake_cargo,
+            "#!/bin/sh\nprintf '%s\\n' \"$PWD\" \"$@\" \"$PATH\" > invocation.txt\n\
+             echo 'not a message'\n\
+             echo '{\"reason\":\"compiler-artifact\",\"target\":{\"name\":\"gen\"},\"executable\":\"/t/gen\"}'\n\
+             echo '{\"reason\":\"build-finished\",\"success\":true}'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&fake_cargo, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let spaced_path = ffi::OsString::from(format!("{}:/bin:/usr/bin", tool_dir.display()));
+        let target_dir = root.join("target dir");
+        let executables = compile_build_crate(
+            &crate_dir,
+            Some(&target_dir),
+            fake_cargo.as_os_str(),
+            &["-vv"],
+            &[("PATH".to_owned(), spaced_path.clone())],
+            None,
+            None,
+        );
+
+        let invocation = fs::read_to_string(crate_dir.join("invocation.txt")).unwrap();
+        let lines: Vec<&str> = invocation.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                crate_dir.to_str().unwrap(),
+                "build",
+                "-vv",
+                "--message-format=json-render-diagnostics",
+                "--target-dir",
+                target_dir.to_str().unwrap(),
+                spaced_path.to_str().unwrap(),
+            ]
+        );
+        assert_eq!(
+            executables,
+            [("gen".to_owned(), path::PathBuf::from("/t/gen"))]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_requested_target() {
+        let env = [(
+            "CARGO_BUILD_TARGET".to_owned(),
+            ffi::OsString::from("x86_64-pc-windows-gnu"),
+        )];
+        assert_eq!(
+            requested_target(&["-vv", "--target", "aarch64-apple-darwin"], &env).as_deref(),
+            Some("aarch64-apple-darwin")
+        );
+        assert_eq!(
+            requested_target(&["--target=wasm32-wasip1"], &[]).as_deref(),
+            Some("wasm32-wasip1")
+        );
+        let target = requested_target(&["-vv"], &env);
+        assert_eq!(target.as_deref(), Some("x86_64-pc-windows-gnu"));
+        assert_eq!(executable_suffix(target.as_deref()), ".exe");
+        assert_eq!(executable_suffix(Some("wasm32-wasip1")), ".wasm");
+        assert_eq!(executable_suffix(Some("x86_64-unknown-linux-gnu")), "");
+        assert_eq!(requested_target(&["-vv"], &[]), None);
+    }
+
+    #[test]
+    fn test_finds_renamed_executable() {
+        let fixture = test_support::Fixture::new("renamed-bin")
+            .build_crate(
+                DEFAULT_BUILD_CRATE_DIR,
+                r#"fn main() {
+                    let package = std::env::var("CARGO_PKG_NAME").unwrap();
+                    println!("cargo:rustc-cfg=renamed_{}", package);
+                }"#,
+            )
+            .file(
+                "build-script/Cargo.toml",
+                "[package]\nname = \"codegen\"\nversion = \"0.1.0\"\n\n\
+                 [[bin]]\nname = \"generate\"\npath = \"src/main.rs\"\n",
+            );
+        // `cargo run` sets the build crate's own package variables.
+        let run = fixture.run(|runner| runner.preflight(false));
+        run.assert_directive("cargo:rustc-cfg=renamed_codegen");
+        let run = fixture.run(|runner| {
+            runner
+                .preflight(false)
+                .run_strategy(RunStrategy::BuildThenExec)
+        });
+        // Built then run, it sees the parent's, here the test's own.
+        run.assert_directive(&format!(
+            "cargo:rustc-cfg=renamed_{}",
+            env::var("CARGO_PKG_NAME").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_runs_named_bins() {
+        let fixture = test_support::Fixture::new("named-bins")
+            .build_crate(
+                DEFAULT_BUILD_CRATE_DIR,
+                r#"fn main() { panic!("not this one"); }"#,
+            )
+            .file(
+                "build-script/Cargo.toml",
+                "[package]\nname = \"build-script\"\nversion = \"0.1.0\"\n\n\
+                 [[bin]]\nname = \"build-script\"\npath = \"src/main.rs\"\n\n\
+                 [[bin]]\nname = \"codegen\"\npath = \"src/codegen.rs\"\n\n\
+                 [[bin]]\nname = \"pack-assets\"\npath = \"src/pack.rs\"\n",
+            )
+            .file(
+                "build-script/src/codegen.rs",
+                r#"fn main() { println!("cargo:rustc-cfg=codegen"); }"#,
+            )
+            .file(
+                "build-script/src/pack.rs",
+                r#"fn main() { println!("cargo:rustc-cfg=packed"); }"#,
+            );
+        let run = fixture.run(|runner| runner.preflight(false).bins(["codegen", "pack-assets"]));
+        run.assert_directive("cargo:rustc-cfg=codegen");
+        run.assert_directive("cargo:rustc-cfg=packed");
+    }
+
+    #[test]
+    fn test_script_args_stdin_and_params() {
+        let fixture = test_support::Fixture::new("args-stdin").build_crate(
+            DEFAULT_BUILD_CRATE_DIR,
+            r##"
+use std::io::Read;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).unwrap();
+    println!("cargo:rustc-cfg={}_{}", args.join("_"), input.trim());
+    let params = std::env::var("CARGO_5730_PARAMS_FILE").unwrap();
+    let params = std::fs::read_to_string(params).unwrap();
+    println!("cargo:rustc-cfg=params_{}", params.trim() == r#"{"mode":"fast"}"#);
 }
+"##,
+        );
+        let run = fixture.run(|runner| {
+            runner
+                .preflight(false)
+                .arg("schema")
+                .args(["v2"])
+                .stdin("from_stdin\n")
+                .script_param("mode", "fast")
+        });
+        run.assert_directive("cargo:rustc-cfg=schema_v2_from_stdin");
+        run.assert_directive("cargo:rustc-cfg=params_true");
+    }
 
-pub fn run_build_crate<P: AsRef<path::Path>>(build_crate_src: P) {
-    let build_crate_src = build_crate_src.as_ref();
-    println!("cargo:rerun-if-changed={}", build_crate_src.display());
+    #[test]
+    fn test_returns_script_output() {
+        let fixture = test_support::Fixture::new("script-output").build_crate(
+            DEFAULT_BUILD_CRATE_DIR,
+            r#"fn main() {
+                println!("generated bindings.rs");
+                println!("cargo:rerun-if-changed=wrapper.h");
+                eprintln!("a warning of its own");
+            }"#,
+        );
+        let run = fixture.run(|runner| runner.preflight(false));
+        let output = run.output.expect("the build script ran");
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout,
+            b"generated bindings.rs\ncargo:rerun-if-changed=wrapper.h\n"
+        );
+        assert_eq!(output.stderr, b"a warning of its own\n");
+        assert_eq!(output.directives, run.directives);
+        assert_eq!(output.directives.len(), 1);
+        assert!(output.directives[0].ends_with("wrapper.h"));
+        assert!(output.duration <= run.report.duration);
+    }
+
+    #[test]
+    fn test_own_out_dir() {
+        let fixture = test_support::Fixture::new("own-out-dir").build_crate(
+            DEFAULT_BUILD_CRATE_DIR,
+            r#"fn main() {
+                let out_dir = std::env::var("OUT_DIR").unwrap();
+                std::fs::write(format!("{}/generated.rs", out_dir), "").unwrap();
+            }"#,
+        );
+        fixture.run(|runner| runner.preflight(false));
+        assert!(fixture.out_dir().join("generated.rs").is_file());
+        fixture.run(|runner| runner.preflight(false).own_out_dir(true));
+        assert!(fixture
+            .out_dir()
+            .join(DEFAULT_BUILD_CRATE_DIR)
+            .join("generated.rs")
+            .is_file());
+    }
+
+    #[test]
+    fn test_compile_timeout() {
+        let fixture = test_support::Fixture::new("compile-timeout")
+            .build_crate(DEFAULT_BUILD_CRATE_DIR, "fn main() {}\n")
+            .file(
+                "build-script/build.rs",
+                "fn main() { std::thread::sleep(std::time::Duration::from_secs(20)); }\n",
+            );
+        let start = time::Instant::now();
+        let failed = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            fixture.run(|runner| {
+                runner
+                    .preflight(false)
+                    .compile_timeout(time::Duration::from_secs(1))
+            })
+        }));
+        let message = failed.expect_err("the compile hung");
+        let message = message
+            .downcast_ref::<String>()
+            .expect("failed with a message");
+        assert!(message.contains("timed out after 1s"), "{}", message);
+        assert!(start.elapsed() < time::Duration::from_secs(15));
+    }
+
+    #[test]
+    fn test_cache_key_covers_path_dependencies() {
+        let fixture = test_support::Fixture::new("cache-key")
+            .build_crate(DEFAULT_BUILD_CRATE_DIR, "fn main() {}\n")
+            .file(
+                "build-script/Cargo.toml",
+                "[package]\nname = \"build-script\"\nversion = \"0.1.0\"\n\n\
+                 [dependencies]\nshared = { path = \"../shared\" }\n",
+            )
+            .file(
+                "shared/Cargo.toml",
+                "[package]\nname = \"shared\"\nversion = \"0.1.0\"\n",
+            )
+            .file("shared/src/lib.rs", "pub const ANSWER: u32 = 42;\n");
+        let runner = BuildCrateRunner::new(fixture.path().join(DEFAULT_BUILD_CRATE_DIR));
+        let key = runner.cache_key();
+        assert_eq!(key, runner.cache_key());
+
+        fs::write(
+            fixture.path().join("shared/src/lib.rs"),
+            "pub const ANSWER: u32 = 43;\n",
+        )
+        .unwrap();
+        assert_ne!(key, runner.cache_key());
+    }
+
+    #[test]
+    fn test_export_executable() {
+        let fixture = test_support::Fixture::new("export-executable").build_crate(
+            DEFAULT_BUILD_CRATE_DIR,
+            r#"fn main() { println!("exported"); }"#,
+        );
+        let run = fixture.run(|runner| runner.preflight(false).export_executable(true));
+        let exported = fixture
+            .out_dir()
+            .join("cargo-5730-bin")
+            .join(format!("build-script{}", env::consts::EXE_SUFFIX));
+        assert_eq!(run.report.executables, [exported.as_path()]);
+        let output = process::Command::new(&exported).output().unwrap();
+        assert_eq!(output.stdout, b"exported\n");
+    }
+
+    #[test]
+    fn test_hand_off_own_out_dir() {
+        let fixture = test_support::Fixture::new("hand-off")
+            .build_crate(
+                "build-scripts/producer",
+                r#"fn main() {
+                    let out_dir = std::env::var("OUT_DIR").unwrap();
+                    std::fs::write(format!("{}/schema.json", out_dir), "{}").unwrap();
+                    let result = std::env::var("CARGO_5730_RESULT_FILE").unwrap();
+                    std::fs::write(result, "done").unwrap();
+                }"#,
+            )
+            .build_crate(
+                "build-scripts/consumer",
+                r#"fn main() {
+                    let inputs = std::env::var("CARGO_5730_INPUTS").unwrap();
+                    let out_dir = inputs.split("\"out_dir\":\"").nth(1).unwrap();
+                    let out_dir = &out_dir[..out_dir.find('"').unwrap()];
+                    let schema = std::path::Path::new(out_dir).join("schema.json");
+                    assert!(schema.is_file(), "{} is missing", schema.display());
+                }"#,
+            );
+        fs::create_dir_all(fixture.out_dir()).unwrap();
+
+        let staging_root = fixture.path().join("staging");
+        let out_dir = fixture.out_dir();
+        BuildCrateSet::discover(fixture.path().join("build-scripts"))
+            .configure(move |runner| {
+                runner
+                    .preflight(false)
+                    .staging_root(&staging_root)
+                    .out_dir(&out_dir)
+                    .own_out_dir(true)
+            })
+            .order(vec!["producer"])
+            .hand_off("producer", "consumer")
+            .run();
+        assert!(fixture.out_dir().join("producer/schema.json").is_file());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ssh_backend() {
+        use std::os::unix::fs::PermissionsExt;
 
-    let build_dir = BuildDir::new();
+        let fixture = test_support::Fixture::new("ssh")
+            .build_crate(
+                DEFAULT_BUILD_CRATE_DIR,
+                r#"fn main() {
+                    let out_dir = std::env::var("OUT_DIR").unwrap();
+                    std::fs::write(format!("{}/generated.rs", out_dir), "").unwrap();
+                    println!("cargo:rerun-if-changed=wrapper.h");
+                }"#,
+            )
+            .file(
+                "build-script/Cargo.toml",
+                "[package]\nname = \"codegen\"\nversion = \"0.1.0\"\n\n\
+                 [[bin]]\nname = \"generate\"\npath = \"src/main.rs\"\n",
+            )
+            // Runs the remote command here, whatever the host.
+            .file("ssh", "#!/bin/sh\nshift\nexec sh -c \"$1\"\n");
+        let ssh = fixture.path().join("ssh");
+        fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(fixture.out_dir()).unwrap();
 
-    let executable_name = build_crate_src
-        .file_name()
-        .and_then(|os_str| os_str.to_str())
-        .expect(&format!(
-            "Couldn't get file name from build crate src dir: {}",
-            build_crate_src.display(),
+        let backend = SshBackend::new("build-host")
+            .ssh_program(&ssh)
+            .remote_root(fixture.path().join("remote").to_str().unwrap());
+        let run = fixture.run(|runner| {
+            runner
+                .preflight(false)
+                .rebase_directives(true)
+                .backend(ExecutionBackend::Ssh(backend))
+        });
+        let base = fixture.path().join(DEFAULT_BUILD_CRATE_DIR);
+        run.assert_directive(&format!(
+            "cargo:rerun-if-changed={}",
+            base.join("wrapper.h").display()
         ));
+        assert!(fixture.out_dir().join("generated.rs").is_file());
+    }
 
-    let cargo = env::var("CARGO").expect("Can't get CARGO from env");
-    let path = env::var("PATH").expect("Can't get PATH from env");
-    let ssh_auth_sock = env::var("SSH_AUTH_SOCK").unwrap_or_default();
-    let base_dir = env::var("CARGO_MANIFEST_DIR").expect("Can't get CARGO_MANIFEST_DIR from env");
-    let base_dir = path::Path::new(&base_dir).join("build-script");
-
-    let rustup_home = env::var("RUSTUP_HOME").unwrap_or_default();
-    let rustup_toolchain = env::var("RUSTUP_TOOLCHAIN").unwrap_or_default();
-
-    // Copy the build crate into /tmp to avoid the influence of .cargo/config
-    // settings in the build crate's parent, which cargo gives us no way to
-    // ignore.
-    println!(
-        "Copying build crate source from {} to {}",
-        &build_crate_src.display(),
-        build_dir.path.display()
-    );
-    cp_r(build_crate_src, &build_dir.path);
+    #[test]
+    fn test_set_package_edition() {
+        let input = r#"[package]
+name = "build-script"
+edition = "2015"
 
-    // Having copied the crate, we need to fix any relative paths that were in
-    // the Cargo.toml
-    qualify_cargo_toml_paths(&build_dir.path.join("Cargo.toml"), &base_dir);
+[dependencies]
+edition = "1"
+"#;
+        let expected = r#"[package]
+name = "build-script"
+edition = "2021"
 
-    compile_build_crate(&build_dir, &cargo, &path, &ssh_auth_sock, &rustup_home, &rustup_toolchain);
+[dependencies]
+edition = "1"
+"#;
+        assert_eq!(set_package_edition(input, "2021"), expected);
 
-    // Run the build script with its original source directory as the working
-    // dir.
-    run_build_script(&build_dir, &executable_name, &build_crate_src);
-}
+        let input = "[package]\nname = \"build-script\"\n";
+        let expected = "[package]\nedition = \"2021\"\nname = \"build-script\"\n";
+        assert_eq!(set_package_edition(input, "2021"), expected);
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        let input = "[package]\nedition.workspace = true\n";
+        let expected = "[package]\nedition = \"2018\"\n";
+        assert_eq!(set_package_edition(input, "2018"), expected);
+    }
 
     #[test]
     fn test_path_fixup_1() {
@@ -254,5 +3465,4 @@ lib-crate = { path='/basedir/../../lib-crate' }
             expected.to_string()
         );
     }
-
 }
@@ -0,0 +1,181 @@
+//! Keeping builds running at the same time, as cargo runs the build scripts
+//! of a workspace in parallel, out of each other's way in the dirs they share.
+
+use std::{fs, io, path};
+
+/// An exclusive lock on a file, held until it's dropped, when the file is
+/// closed. Advisory, so it only keeps out other builds that take it too.
+pub(crate) struct Lock {
+    _file: fs::File,
+}
+
+/// Lock `path`, creating it if need be, and waiting for whichever build
+/// holds it to finish with `what`.
+pub(crate) fn exclusive(path: &path::Path, what: &path::Path) -> Lock {
+    let file =
+        open(path).unwrap_or_else(|e| panic!("Couldn't open lock file {}: {}", path.display(), e));
+    match imp::lock(&file, false) {
+        Ok(true) => {}
+        Ok(false) => {
+            println!(
+                "Waiting for another build to finish with {}",
+                what.display()
+            );
+            imp::lock(&file, true)
+                .unwrap_or_else(|e| panic!("Couldn't lock {}: {}", path.display(), e));
+        }
+        Err(e) => panic!("Couldn't lock {}: {}", path.display(), e),
+    }
+    Lock { _file: file }
+}
+
+/// Whether some build holds the lock on `path`. A file that can't be opened
+/// isn't locked.
+pub(crate) fn is_locked(path: &path::Path) -> bool {
+    match fs::File::options().read(true).write(true).open(path) {
+        // Taken only to see if it can be, and let go again on closing.
+        Ok(file) => matches!(imp::lock(&file, false), Ok(false)),
+        Err(_) => false,
+    }
+}
+
+fn open(path: &path::Path) -> io::Result<fs::File> {
+    fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::c_int;
+    use std::os::unix::io::AsRawFd;
+    use std::{fs, io};
+
+    // The same numbers on every Unix this builds for.
+    const LOCK_EX: c_int = 2;
+    const LOCK_NB: c_int = 4;
+
+    extern "C" {
+        fn flock(fd: c_int, operation: c_int) -> c_int;
+    }
+
+    /// Take an exclusive lock on `file`, waiting for it if `wait`. Returns
+    /// whether it was taken, which it always is when waiting.
+    pub(super) fn lock(file: &fs::File, wait: bool) -> io::Result<bool> {
+        let operation = if wait { LOCK_EX } else { LOCK_EX | LOCK_NB };
+        loop {
+            // Safety: the descriptor is open for as long as `file` lives.
+            if unsafe { flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(true);
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock => return Ok(false),
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ffi::c_void;
+    use std::os::windows::io::AsRawHandle;
+    use std::{fs, io, ptr};
+
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 2;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut c_void,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            file: *mut c_void,
+            flags: u32,
+            reserved: u32,
+            bytes_low: u32,
+            bytes_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+    }
+
+    /// Take an exclusive lock on `file`, waiting for it if `wait`. Returns
+    /// whether it was taken, which it always is when waiting.
+    pub(super) fn lock(file: &fs::File, wait: bool) -> io::Result<bool> {
+        let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+        if !wait {
+            flags |= LOCKFILE_FAIL_IMMEDIATELY;
+        }
+        let mut overlapped = Overlapped {
+            internal: 0,
+            internal_high: 0,
+            offset: 0,
+            offset_high: 0,
+            event: ptr::null_mut(),
+        };
+        // Safety: the handle is open for as long as `file` lives, and the
+        // file isn't opened for overlapped I/O, so the call is synchronous.
+        let locked = unsafe {
+            LockFileEx(
+                file.as_raw_handle(),
+                flags,
+                0,
+                u32::MAX,
+                u32::MAX,
+                &mut overlapped,
+            )
+        };
+        if locked != 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(ERROR_LOCK_VIOLATION) => Ok(false),
+            _ => Err(e),
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::{fs, io};
+
+    /// Nothing to lock with here, so builds sharing dirs aren't kept apart.
+    pub(super) fn lock(_: &fs::File, _: bool) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_exclusive() {
+        let root = env::temp_dir().join(format!("cargo-5730-lock-test-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("lock");
+
+        assert!(!is_locked(&path));
+        let lock = exclusive(&path, &root);
+        assert!(is_locked(&path));
+        drop(lock);
+        assert!(!is_locked(&path));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
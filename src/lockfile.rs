@@ -0,0 +1,96 @@
+//! Keeping the build crate's `Cargo.lock` in step with the isolated build.
+
+use std::{ffi, fs, path, process};
+
+/// Copy the staged crate's `Cargo.lock` to `dest`. It's only written when its
+/// contents change, so a lockfile inside the build crate doesn't trigger its
+/// `rerun-if-changed` on every build.
+pub(crate) fn export(staged_dir: &path::Path, dest: &path::Path) {
+    let staged = staged_dir.join("Cargo.lock");
+    let lockfile =
+        fs::read(&staged).unwrap_or_else(|e| panic!("Couldn't read {}: {}", staged.display(), e));
+    if fs::read(dest).ok().as_ref() == Some(&lockfile) {
+        return;
+    }
+
+    println!("Writing resolved lockfile to {}", dest.display());
+    fs::write(dest, lockfile)
+        .unwrap_or_else(|e| panic!("Couldn't write lockfile to {}: {}", dest.display(), e));
+}
+
+/// Check that the staged crate in `staged_dir` has a `Cargo.lock` to build
+/// with `--locked`, since cargo would otherwise just say it needs updating.
+pub(crate) fn require(staged_dir: &path::Path, build_crate_src: &path::Path) {
+    assert!(
+        staged_dir.join("Cargo.lock").is_file(),
+        "Build crate {} has no Cargo.lock, which a locked build needs; \
+         run `cargo generate-lockfile` in it and commit the result",
+        build_crate_src.display()
+    );
+}
+
+/// Run `cargo update` in `crate_dir`, refreshing its `Cargo.lock` to the
+/// newest compatible versions, or with `minimal_versions` to the oldest ones
+/// its version requirements allow.
+pub(crate) fn update(
+    crate_dir: &path::Path,
+    cargo: &ffi::OsStr,
+    cargo_args: &[&str],
+    forwarded_env: &[(String, ffi::OsString)],
+    minimal_versions: bool,
+) {
+    let mut command = process::Command::new(cargo);
+    command.arg("update");
+    if minimal_versions {
+        command.args(["-Z", "minimal-versions"]);
+    }
+    command
+        .current_dir(crate_dir)
+        .env_clear()
+        .envs(forwarded_env.iter().cloned());
+    if cargo_args.contains(&"--offline") {
+        command.arg("--offline");
+    }
+
+    let status = command
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run cargo update: {}", e));
+    assert!(
+        status.success(),
+        "cargo update failed in {} ({})",
+        crate_dir.display(),
+        status
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process, time};
+
+    #[test]
+    #[should_panic(expected = "has no Cargo.lock, which a locked build needs")]
+    fn test_require_missing_lockfile() {
+        let staged = env::temp_dir().join(format!("cargo-5730-no-lockfile-{}", process::id()));
+        require(&staged, path::Path::new("build-script"));
+    }
+
+    #[test]
+    fn test_export_leaves_unchanged_lockfile_alone() {
+        let root = env::temp_dir().join(format!("cargo-5730-lockfile-test-{}", process::id()));
+        let staged = root.join("staged");
+        fs::create_dir_all(&staged).unwrap();
+        fs::write(staged.join("Cargo.lock"), "version = 3\n").unwrap();
+        let dest = root.join("Cargo.lock");
+
+        export(&staged, &dest);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "version = 3\n");
+
+        let modified = fs::metadata(&dest).unwrap().modified().unwrap();
+        std::thread::sleep(time::Duration::from_millis(20));
+        export(&staged, &dest);
+        assert_eq!(fs::metadata(&dest).unwrap().modified().unwrap(), modified);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+//! `cargo 5730`, for working with build crates outside of a build.
+//!
+//! ```text
+//! cargo 5730 clippy [BUILD_CRATE_DIR]... [-- LINT_ARGS...]
+//! ```
+//!
+//! stages each build crate and runs `cargo clippy` on it, denying warnings
+//! unless lint arguments are given. Without directories, it checks
+//! `build-script/`, or every build crate under `build-scripts/`.
+
+use std::{env, path, process};
+
+use cargo_5730::{
+    BuildCrateRunner, BuildCrateSet, DEFAULT_BUILD_CRATES_DIR, DEFAULT_BUILD_CRATE_DIR,
+};
+
+const USAGE: &str = "Usage: cargo 5730 clippy [BUILD_CRATE_DIR]... [-- LINT_ARGS...]";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Run as `cargo 5730`, cargo passes the subcommand's name along.
+    if args.first().map(String::as_str) == Some("5730") {
+        args.remove(0);
+    }
+
+    match args.first().map(String::as_str) {
+        Some("clippy") => clippy(&args[1..]),
+        Some("-h") | Some("--help") => println!("{}", USAGE),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+fn clippy(args: &[String]) {
+    let (dirs, lint_args) = match args.iter().position(|arg| arg == "--") {
+        Some(separator) => (&args[..separator], Some(&args[separator + 1..])),
+        None => (args, None),
+    };
+
+    // cargo tells its subcommands where it is; run directly, use the one on
+    // PATH.
+    if env::var_os("CARGO").is_none() {
+        env::set_var("CARGO", "cargo");
+    }
+    let cwd = env::current_dir().expect("Can't get the current dir");
+
+    let build_crates: Vec<path::PathBuf> = if !dirs.is_empty() {
+        dirs.iter().map(|dir| cwd.join(dir)).collect()
+    } else if cwd
+        .join(DEFAULT_BUILD_CRATE_DIR)
+        .join("Cargo.toml")
+        .is_file()
+    {
+        vec![cwd.join(DEFAULT_BUILD_CRATE_DIR)]
+    } else {
+        let root = cwd.join(DEFAULT_BUILD_CRATES_DIR);
+        BuildCrateSet::discover(&root)
+            .crates()
+            .into_iter()
+            .map(|name| root.join(name))
+            .collect()
+    };
+
+    for build_crate in build_crates {
+        let mut runner = BuildCrateRunner::new(build_crate);
+        if let Some(lint_args) = lint_args {
+            runner = runner.clippy_gate(lint_args);
+        }
+        runner.run_clippy();
+    }
+}
@@ -0,0 +1,645 @@
+//! Rewriting the staged build crate's `Cargo.toml`, changing only the values
+//! that need it and leaving everything else as it was written.
+
+use std::{fs, mem, path};
+
+use crate::paths;
+use crate::toml::{self, StringSpan, Table, Value};
+
+/// The tables dependencies are declared in, below the root or a
+/// `[target.<cfg>]` table.
+const DEPENDENCY_TABLES: &[&str] = &["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// Whether `key` is a dependency's `path`, whichever of the inline table,
+/// dotted key or `[dependencies.<name>]` forms declared it. Dependencies
+/// overridden with `[patch.<registry>]` or `[replace]` count too.
+fn is_dependency_path(key: &[String]) -> bool {
+    let key: Vec<&str> = key.iter().map(String::as_str).collect();
+    match key.as_slice() {
+        ["patch", _, _, "path"] | ["replace", _, "path"] => true,
+        [table, _, "path"] | ["target", _, table, _, "path"] => DEPENDENCY_TABLES.contains(table),
+        _ => false,
+    }
+}
+
+/// Whether `path` is absolute on any platform: `/opt/foo`, as well as
+/// `C:\foo`, `C:/foo` and `\\server\share`, whichever platform is reading
+/// the manifest.
+fn is_absolute(path: &str) -> bool {
+    let mut chars = path.chars();
+    let drive_letter = matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(letter), Some(':'), Some('\\' | '/')) if letter.is_ascii_alphabetic()
+    );
+    drive_letter || path.starts_with(['/', '\\'])
+}
+
+/// Make the relative dependency paths in `manifest` relative to `base_dir`,
+/// which is where the build crate was before it was staged. Absolute paths
+/// are left alone, as are paths elsewhere, such as `[lib] path`, which are
+/// relative to the crate itself.
+pub(crate) fn qualify_paths(manifest: &str, base_dir: &path::Path) -> Result<String, String> {
+    let spans: Vec<StringSpan> = toml::strings(manifest)?
+        .into_iter()
+        .filter(|span| is_dependency_path(&span.key) && !is_absolute(&span.value))
+        .collect();
+
+    let chars: Vec<char> = manifest.chars().collect();
+    let mut rewritten = String::with_capacity(manifest.len());
+    let mut pos = 0;
+    for span in spans {
+        rewritten.extend(&chars[pos..span.chars.start]);
+        let literal = chars[span.chars.start] == '\'';
+        let qualified = joined(base_dir, &span.value)?;
+        rewritten.push_str(&toml::quote(&qualified, literal));
+        pos = span.chars.end;
+    }
+    rewritten.extend(&chars[pos..]);
+    Ok(rewritten)
+}
+
+/// `relative`, a path from a manifest, as a path in `dir`.
+fn joined(dir: &path::Path, relative: &str) -> Result<String, String> {
+    Ok(format!("{}/{}", paths::to_toml(dir)?, relative))
+}
+
+/// `manifest` with what it inherits from its workspace written out in full,
+/// since the workspace isn't there to inherit from once the crate is staged:
+/// dependencies (`dep.workspace = true`), package fields
+/// (`version.workspace = true`) and `lints`. `crate_dir` is where the crate
+/// was before it was staged.
+pub(crate) fn inherit_from_workspace(
+    manifest: &str,
+    crate_dir: &path::Path,
+) -> Result<String, String> {
+    let parsed = toml::parse(manifest)?;
+    let dependencies = inherited_dependencies(&parsed);
+    let fields = inherited_fields(&parsed);
+    let first = match (dependencies.first(), fields.first()) {
+        (Some((key, _)), _) | (None, Some(key)) => key.join("."),
+        (None, None) => return Ok(manifest.to_owned()),
+    };
+    let (workspace_dir, workspace) = find_workspace(crate_dir)?.ok_or_else(|| {
+        format!(
+            "{} inherits from the workspace, but there's no workspace above {}",
+            first,
+            crate_dir.display()
+        )
+    })?;
+    let missing = |key: &[String], from: &str| {
+        format!(
+            "{} inherits from the workspace, but {} has no {}",
+            key.join("."),
+            workspace_dir.join("Cargo.toml").display(),
+            from
+        )
+    };
+
+    let mut specs = Vec::new();
+    for (key, own) in dependencies {
+        let name = key.last().expect("dependency keys aren't empty");
+        let spec = workspace
+            .get(&["workspace", "dependencies", name])
+            .ok_or_else(|| missing(&key, &format!("workspace.dependencies.{}", name)))?;
+        let merged = merge(spec, own, &workspace_dir)
+            .map_err(|e| format!("workspace.dependencies.{}: {}", name, e))?;
+        specs.push((key, Value::Table(merged), false));
+    }
+    for key in fields {
+        let mut from = vec!["workspace"];
+        from.extend(key.iter().map(String::as_str));
+        let mut value = workspace
+            .get(&from)
+            .ok_or_else(|| missing(&key, &from.join(".")))?
+            .clone();
+        // Paths to files are relative to the workspace.
+        if let (["package", "readme" | "license-file"], Value::String(path)) =
+            (&from[1..], &mut value)
+        {
+            if !is_absolute(path) {
+                *path = joined(&workspace_dir, path)?;
+            }
+        }
+        specs.push((key, value, false));
+    }
+
+    // Each inherited key's first line is replaced with its full value, in
+    // the same form; any further lines declaring it are dropped.
+    let chars: Vec<char> = manifest.chars().collect();
+    let mut rewritten = String::with_capacity(manifest.len());
+    let mut pos = 0;
+    for statement in toml::statements(manifest)? {
+        let (key, value, written) = match specs
+            .iter_mut()
+            .find(|(key, _, _)| statement.key.starts_with(key))
+        {
+            Some(spec) => spec,
+            None => continue,
+        };
+        rewritten.extend(&chars[pos..statement.chars.start]);
+        pos = statement.chars.end;
+        if mem::replace(written, true) {
+            continue;
+        }
+        if statement.header_len < key.len() {
+            let relative: Vec<String> = key[statement.header_len..]
+                .iter()
+                .map(|part| toml::quote_key(part))
+                .collect();
+            rewritten.push_str(&format!(
+                "{} = {}",
+                relative.join("."),
+                value.to_inline_toml()
+            ));
+        } else {
+            // Under the key's own `[<key>]` header.
+            let table = match value {
+                Value::Table(table) => table,
+                _ => {
+                    return Err(format!(
+                        "{} inherits a value from the workspace that can't go under [{}]",
+                        key.join("."),
+                        key.join(".")
+                    ))
+                }
+            };
+            let lines: Vec<String> = table
+                .iter()
+                .map(|(key, value)| {
+                    format!("{} = {}", toml::quote_key(key), value.to_inline_toml())
+                })
+                .collect();
+            rewritten.push_str(&lines.join("\n"));
+        }
+    }
+    rewritten.extend(&chars[pos..]);
+    Ok(rewritten)
+}
+
+/// Whether `value` is `{ workspace = true }`, inherited from the workspace.
+fn is_inherited(value: &Value) -> bool {
+    matches!(value, Value::Table(table) if table.get("workspace") == Some(&Value::Boolean(true)))
+}
+
+/// The package fields `manifest` inherits from its workspace, and `lints` if
+/// it inherits those too, as keys from the root.
+fn inherited_fields(manifest: &Value) -> Vec<Vec<String>> {
+    let mut inherited = Vec::new();
+    if let Some(Value::Table(package)) = manifest.get(&["package"]) {
+        for (field, value) in package {
+            if is_inherited(value) {
+                inherited.push(vec!["package".to_owned(), field.clone()]);
+            }
+        }
+    }
+    if manifest.get(&["lints"]).is_some_and(is_inherited) {
+        inherited.push(vec!["lints".to_owned()]);
+    }
+    inherited
+}
+
+/// The dependencies `manifest` inherits from its workspace: the key each is
+/// declared under, and what the crate adds to the workspace's spec.
+fn inherited_dependencies(manifest: &Value) -> Vec<(Vec<String>, &Table)> {
+    let mut tables = Vec::new();
+    for table in DEPENDENCY_TABLES {
+        tables.push((vec![table.to_string()], manifest.get(&[table])));
+    }
+    if let Some(Value::Table(targets)) = manifest.get(&["target"]) {
+        for (cfg, target) in targets {
+            for table in DEPENDENCY_TABLES {
+                let key = vec!["target".to_owned(), cfg.clone(), table.to_string()];
+                tables.push((key, target.get(&[table])));
+            }
+        }
+    }
+
+    let mut inherited = Vec::new();
+    for (key, table) in tables {
+        let dependencies = match table {
+            Some(Value::Table(dependencies)) => dependencies,
+            _ => continue,
+        };
+        for (name, spec) in dependencies {
+            if let (true, Value::Table(spec)) = (is_inherited(spec), spec) {
+                let mut key = key.clone();
+                key.push(name.clone());
+                inherited.push((key, spec));
+            }
+        }
+    }
+    inherited
+}
+
+/// The nearest manifest at or above `crate_dir` with a `[workspace]` table,
+/// and the directory it's in.
+fn find_workspace(crate_dir: &path::Path) -> Result<Option<(path::PathBuf, Value)>, String> {
+    for dir in crate_dir.ancestors() {
+        let manifest = dir.join("Cargo.toml");
+        let content = match fs::read_to_string(&manifest) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        let parsed = toml::parse(&content).map_err(|e| format!("{}: {}", manifest.display(), e))?;
+        if parsed.get(&["workspace"]).is_some() {
+            return Ok(Some((dir.to_owned(), parsed)));
+        }
+    }
+    Ok(None)
+}
+
+/// The workspace's `spec` for a dependency, with the `features` the crate
+/// adds to it and any other keys it sets, such as `optional`. A relative
+/// `path` in it is relative to `workspace_dir`.
+fn merge(spec: &Value, own: &Table, workspace_dir: &path::Path) -> Result<Table, String> {
+    let mut merged = match spec {
+        Value::String(version) => {
+            let mut merged = Table::new();
+            merged.insert("version".to_owned(), Value::String(version.clone()));
+            merged
+        }
+        Value::Table(spec) => spec.clone(),
+        _ => return Err("expected a version or a table".to_owned()),
+    };
+    if let Some(Value::String(path)) = merged.get_mut("path") {
+        if !is_absolute(path) {
+            *path = joined(workspace_dir, path)?;
+        }
+    }
+
+    for (key, value) in own {
+        match (key.as_str(), value) {
+            ("workspace", _) => {}
+            ("features", Value::Array(features)) => {
+                let entry = merged
+                    .entry("features".to_owned())
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(merged_features) = entry {
+                    for feature in features {
+                        if !merged_features.contains(feature) {
+                            merged_features.push(feature.clone());
+                        }
+                    }
+                }
+            }
+            _ => {
+                merged.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// The crates the one in `crate_dir` depends on by path, directly or through
+/// each other, workspace-inherited and patched ones included. Crates under
+/// `crate_dir` itself, and paths that don't exist, are left out.
+pub(crate) fn path_dependencies(crate_dir: &path::Path) -> Result<Vec<path::PathBuf>, String> {
+    let root = crate_dir
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", crate_dir.display(), e))?;
+    let mut found: Vec<path::PathBuf> = Vec::new();
+    let mut pending = vec![root.clone()];
+    while let Some(dir) = pending.pop() {
+        let manifest_path = dir.join("Cargo.toml");
+        let manifest = fs::read_to_string(&manifest_path)
+            .map_err(|e| format!("{}: {}", manifest_path.display(), e))?;
+        let manifest = inherit_from_workspace(&manifest, &dir)
+            .map_err(|e| format!("{}: {}", manifest_path.display(), e))?;
+        let strings =
+            toml::strings(&manifest).map_err(|e| format!("{}: {}", manifest_path.display(), e))?;
+        for string in strings {
+            if !is_dependency_path(&string.key) {
+                continue;
+            }
+            let dependency = match dir.join(&string.value).canonicalize() {
+                Ok(dependency) => dependency,
+                Err(_) => continue,
+            };
+            if dependency.starts_with(&root) || found.contains(&dependency) {
+                continue;
+            }
+            found.push(dependency.clone());
+            pending.push(dependency);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// `manifest` with an empty `[workspace]` table added, unless it has one
+/// already, so the staged crate builds on its own rather than as part of
+/// whatever workspace encloses the staging dir.
+pub(crate) fn detach_from_workspace(manifest: &str) -> Result<String, String> {
+    if toml::parse(manifest)?.get(&["workspace"]).is_some() {
+        return Ok(manifest.to_owned());
+    }
+    let mut detached = manifest.to_owned();
+    if !detached.is_empty() && !detached.ends_with('\n') {
+        detached.push('\n');
+    }
+    detached.push_str("\n[workspace]\n");
+    Ok(detached)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_only_dependency_paths() {
+        let input = r#"
+[package]
+name = "build-script"
+description = 'uses path = "x" in prose'
+
+[lib]
+path = "src/lib.rs"
+
+[[bin]]
+name = "gen"
+path = "src/gen.rs"
+
+[dependencies]
+a = { path = "../a", version = "1" }
+b.path = '../b'
+c = "1"
+
+[dependencies.d]
+path = "../d" # comment
+
+[target.'cfg(unix)'.build-dependencies]
+e = { path = "../e" }
+"#;
+        let expected = r#"
+[package]
+name = "build-script"
+description = 'uses path = "x" in prose'
+
+[lib]
+path = "src/lib.rs"
+
+[[bin]]
+name = "gen"
+path = "src/gen.rs"
+
+[dependencies]
+a = { path = "/basedir/../a", version = "1" }
+b.path = '/basedir/../b'
+c = "1"
+
+[dependencies.d]
+path = "/basedir/../d" # comment
+
+[target.'cfg(unix)'.build-dependencies]
+e = { path = "/basedir/../e" }
+"#;
+        assert_eq!(
+            qualify_paths(input, path::Path::new("/basedir")).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_dotted_and_table_dependencies() {
+        let input = r#"
+dependencies.a.path = "../a"
+dev-dependencies."b".path = "../b"
+
+[dependencies]
+c.path = "../c"
+c.version = "1"
+"d" . path = "../d"
+
+[build-dependencies.e]
+version = "1"
+path = "../e"
+
+[target."cfg(windows)".dependencies.f]
+path = "../f"
+
+[target.x86_64-unknown-linux-gnu.dependencies]
+g.path = "../g"
+"#;
+        let expected = input.replace(r#""../"#, r#""/basedir/../"#);
+        assert_eq!(
+            qualify_paths(input, path::Path::new("/basedir")).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_patch_and_replace() {
+        let input = r#"
+[patch.crates-io]
+a = { path = "../a" }
+
+[patch."https://github.com/example/b"]
+b.path = "../b"
+
+[replace]
+"c:0.1.0" = { path = "../c" }
+"#;
+        let expected = input.replace(r#""../"#, r#""/basedir/../"#);
+        assert_eq!(
+            qualify_paths(input, path::Path::new("/basedir")).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_absolute_paths() {
+        let input = r#"
+[dependencies]
+a = { path = "/opt/vendored/a" }
+b = { path = 'C:\vendored\b' }
+c = { path = "D:/vendored/c" }
+d = { path = '\\server\share\d' }
+e = { path = "c/d" }
+"#;
+        let expected = input.replace(r#""c/d""#, r#""/basedir/c/d""#);
+        assert_eq!(
+            qualify_paths(input, path::Path::new("/basedir")).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_quoted_base_dir() {
+        let input = "[dependencies]\na = { path = \"../a\" }\nb = { path = '../b' }\n";
+        let base_dir = path::Path::new("/my \"odd\" dir/it's");
+        let qualified = qualify_paths(input, base_dir).unwrap();
+        let parsed = toml::parse(&qualified).unwrap();
+        for name in ["a", "b"] {
+            assert_eq!(
+                parsed.get(&["dependencies", name, "path"]),
+                Some(&Value::String(format!("/my \"odd\" dir/it's/../{}", name)))
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_base_dir() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let input = "[dependencies]\na = { path = \"../a\" }\n";
+        let base_dir = path::Path::new(std::ffi::OsStr::from_bytes(b"/tmp/\xff"));
+        let error = qualify_paths(input, base_dir).unwrap_err();
+        assert!(error.contains("isn't valid UTF-8"), "{}", error);
+    }
+
+    #[test]
+    fn test_detach_from_workspace() {
+        assert_eq!(
+            detach_from_workspace("[package]\nname = \"a\"").unwrap(),
+            "[package]\nname = \"a\"\n\n[workspace]\n"
+        );
+        let root = "[package]\nname = \"a\"\n\n[workspace]\nmembers = [\"b\"]\n";
+        assert_eq!(detach_from_workspace(root).unwrap(), root);
+    }
+
+    #[test]
+    fn test_inherit_workspace_dependencies() {
+        let root =
+            std::env::temp_dir().join(format!("cargo-5730-inherit-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("build-script")).unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            r#"
+[workspace]
+members = ["build-script"]
+
+[workspace.dependencies]
+log = "0.4"
+serde = { version = "1", features = ["derive"] }
+local = { path = "crates/local" }
+"#,
+        )
+        .unwrap();
+
+        let input = r#"
+[dependencies]
+log.workspace = true
+serde = { workspace = true, features = ["rc", "derive"], optional = true }
+other = "1"
+
+[target.'cfg(unix)'.dependencies.local]
+workspace = true
+"#;
+        let expected = format!(
+            r#"
+[dependencies]
+log = {{ version = "0.4" }}
+serde = {{ features = ["derive", "rc"], optional = true, version = "1" }}
+other = "1"
+
+[target.'cfg(unix)'.dependencies.local]
+path = "{}/crates/local"
+"#,
+            root.display()
+        );
+        assert_eq!(
+            inherit_from_workspace(input, &root.join("build-script")).unwrap(),
+            expected
+        );
+
+        let missing = "[dependencies]\nrand.workspace = true\n";
+        let error = inherit_from_workspace(missing, &root.join("build-script"));
+        assert!(error
+            .unwrap_err()
+            .contains("no workspace.dependencies.rand"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_inherit_package_fields_and_lints() {
+        let root = std::env::temp_dir().join(format!(
+            "cargo-5730-inherit-fields-test-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("build-script")).unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            r#"
+[workspace]
+members = ["build-script"]
+
+[workspace.package]
+version = "1.2.3"
+authors = ["Someone"]
+readme = "README.md"
+
+[workspace.lints.rust]
+unsafe_code = "forbid"
+"#,
+        )
+        .unwrap();
+
+        let input = r#"
+[package]
+name = "build-script"
+version.workspace = true
+authors = { workspace = true }
+readme.workspace = true
+edition = "2021"
+
+[lints]
+workspace = true
+"#;
+        let expected = format!(
+            r#"
+[package]
+name = "build-script"
+version = "1.2.3"
+authors = ["Someone"]
+readme = "{}/README.md"
+edition = "2021"
+
+[lints]
+rust = {{ unsafe_code = "forbid" }}
+"#,
+            root.display()
+        );
+        let crate_dir = root.join("build-script");
+        assert_eq!(inherit_from_workspace(input, &crate_dir).unwrap(), expected);
+
+        let dotted = "lints.workspace = true\n";
+        assert_eq!(
+            inherit_from_workspace(dotted, &crate_dir).unwrap(),
+            "lints = { rust = { unsafe_code = \"forbid\" } }\n"
+        );
+
+        let missing = "[package]\nlicense.workspace = true\n";
+        let error = inherit_from_workspace(missing, &crate_dir);
+        assert!(error.unwrap_err().contains("no workspace.package.license"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_path_dependencies() {
+        let root =
+            std::env::temp_dir().join(format!("cargo-5730-path-deps-test-{}", std::process::id()));
+        let write = |dir: &str, manifest: &str| {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("Cargo.toml"), manifest).unwrap();
+        };
+        write(
+            "build-script",
+            "[dependencies]\nlib = { path = \"../lib\" }\nown = { path = \"own\" }\n\
+             missing = { path = \"../missing\" }\n",
+        );
+        write("build-script/own", "[dependencies]\n");
+        write("lib", "[dependencies]\nutil.path = \"../util\"\n");
+        write("util", "[dependencies]\nlib = { path = \"../lib\" }\n");
+
+        let root = root.canonicalize().unwrap();
+        assert_eq!(
+            path_dependencies(&root.join("build-script")).unwrap(),
+            [root.join("lib"), root.join("util")]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
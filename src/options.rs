@@ -0,0 +1,137 @@
+//! Knobs for the inner cargo invocation.
+
+use std::{env, path};
+
+/// How chatty the inner cargo is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    /// `cargo build -q`
+    Quiet,
+    /// `cargo build`
+    Normal,
+    /// `cargo build -v`
+    Verbose,
+    /// `cargo build -vv`
+    #[default]
+    VeryVerbose,
+}
+
+impl Verbosity {
+    pub(crate) fn cargo_args(self) -> &'static [&'static str] {
+        match self {
+            Verbosity::Quiet => &["-q"],
+            Verbosity::Normal => &[],
+            Verbosity::Verbose => &["-v"],
+            Verbosity::VeryVerbose => &["-vv"],
+        }
+    }
+
+    /// Whether `arg` sets cargo's verbosity, as one of these or otherwise.
+    pub(crate) fn is_flag(arg: &str) -> bool {
+        matches!(arg, "-q" | "--quiet" | "-v" | "-vv" | "--verbose")
+    }
+}
+
+/// The cargo profile the build crate is compiled with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    #[default]
+    Debug,
+    Release,
+}
+
+impl Profile {
+    /// The profile the outer build uses, going by cargo's `PROFILE`, so a
+    /// release build of the parent gets a release build crate too.
+    /// `Debug` when `PROFILE` isn't `release`, including outside of a build.
+    pub fn outer() -> Profile {
+        match env::var("PROFILE").as_deref() {
+            Ok("release") => Profile::Release,
+            _ => Profile::Debug,
+        }
+    }
+
+    pub(crate) fn cargo_args(self) -> &'static [&'static str] {
+        match self {
+            Profile::Debug => &[],
+            Profile::Release => &["--release"],
+        }
+    }
+
+    /// The directory under `target/` the profile's artifacts end up in.
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            Profile::Debug => "debug",
+            Profile::Release => "release",
+        }
+    }
+}
+
+/// What to do when something fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop at the first error.
+    #[default]
+    FailFast,
+    /// Keep going after an error to gather as much information as possible
+    /// (the staged manifest, the inner environment, other build crates'
+    /// results), then fail with everything combined.
+    CollectAll,
+}
+
+/// The settings that can also be overridden from the environment.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Settings {
+    pub verbosity: Verbosity,
+    pub profile: Profile,
+    /// Where staging dirs are created; the system temp dir if unset.
+    pub staging_root: Option<path::PathBuf>,
+    pub offline: bool,
+    /// Pass `--locked`, so the build crate's `Cargo.lock` is used as is.
+    pub locked: bool,
+    /// The inner cargo's `--jobs`, as an argument. Its own default otherwise.
+    pub jobs: Option<String>,
+    pub keep: bool,
+    /// Keep the staging dir of a failed build.
+    pub keep_on_failure: bool,
+    /// Features of the build crate to enable.
+    pub features: Vec<String>,
+    pub no_default_features: bool,
+    pub all_features: bool,
+    /// Passed to `cargo build` after everything else.
+    pub extra_cargo_args: Vec<String>,
+    /// Where compiled build scripts are kept between runs, if anywhere.
+    pub build_cache: Option<path::PathBuf>,
+    /// A target dir staged builds share between runs, if any.
+    pub target_dir: Option<path::PathBuf>,
+    /// The inner cargo's `CARGO_HOME`, in place of the outer one.
+    pub cargo_home: Option<path::PathBuf>,
+}
+
+impl Settings {
+    pub fn cargo_args(&self) -> Vec<&str> {
+        let mut args = Vec::new();
+        args.extend(self.verbosity.cargo_args());
+        args.extend(self.profile.cargo_args());
+        if self.offline {
+            args.push("--offline");
+        }
+        if self.locked {
+            args.push("--locked");
+        }
+        if let Some(jobs) = &self.jobs {
+            args.extend(["--jobs", jobs]);
+        }
+        for feature in &self.features {
+            args.extend(["--features", feature]);
+        }
+        if self.no_default_features {
+            args.push("--no-default-features");
+        }
+        if self.all_features {
+            args.push("--all-features");
+        }
+        args.extend(self.extra_cargo_args.iter().map(String::as_str));
+        args
+    }
+}
@@ -0,0 +1,176 @@
+//! Recording what the inner cargo and the build script print, line by line
+//! and in the order it arrived, so a compiler error can be lined up with what
+//! the script was doing around it.
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::{fs, path, time};
+
+use crate::json::Json;
+
+struct Line {
+    time: time::SystemTime,
+    process: &'static str,
+    stream: &'static str,
+    text: String,
+}
+
+/// The output of one run, written to `path` as JSON lines when dropped, so
+/// it's there for failed runs too.
+pub(crate) struct OutputLog {
+    path: path::PathBuf,
+    lines: Mutex<Vec<Line>>,
+}
+
+impl OutputLog {
+    pub fn new(path: &path::Path) -> Arc<Self> {
+        Arc::new(OutputLog {
+            path: path.to_owned(),
+            lines: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn record(&self, process: &'static str, stream: &'static str, text: &[u8]) {
+        let text = text.strip_suffix(b"\n").unwrap_or(text);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        let line = Line {
+            time: time::SystemTime::now(),
+            process,
+            stream,
+            text: String::from_utf8_lossy(text).into_owned(),
+        };
+        self.lines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(line);
+    }
+
+    fn to_json_lines(&self) -> String {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines
+            .iter()
+            .map(|line| {
+                let time = line
+                    .time
+                    .duration_since(time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                Json::object(vec![
+                    ("time", Json::Number(time.as_secs_f64())),
+                    ("process", Json::string(line.process)),
+                    ("stream", Json::string(line.stream)),
+                    ("line", Json::string(line.text.clone())),
+                ])
+                .to_string()
+                    + "\n"
+            })
+            .collect()
+    }
+}
+
+impl Drop for OutputLog {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, self.to_json_lines()) {
+            println!(
+                "cargo:warning=Couldn't write the output log to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Reads from `inner`, recording each complete line in the log as it's read.
+pub(crate) struct Tee<R> {
+    inner: R,
+    log: Option<Arc<OutputLog>>,
+    process: &'static str,
+    stream: &'static str,
+    current: Vec<u8>,
+}
+
+/// `inner`, recorded in `log` as `process`'s `stream`, if there is a log.
+pub(crate) fn tee<R: Read>(
+    log: Option<&Arc<OutputLog>>,
+    inner: R,
+    process: &'static str,
+    stream: &'static str,
+) -> Tee<R> {
+    Tee {
+        inner,
+        log: log.cloned(),
+        process,
+        stream,
+        current: Vec::new(),
+    }
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(read),
+        };
+        if read == 0 {
+            // The output may not end with a newline.
+            if !self.current.is_empty() {
+                log.record(self.process, self.stream, &self.current);
+                self.current.clear();
+            }
+            return Ok(0);
+        }
+        for piece in buf[..read].split_inclusive(|&byte| byte == b'\n') {
+            self.current.extend_from_slice(piece);
+            if piece.ends_with(b"\n") {
+                log.record(self.process, self.stream, &self.current);
+                self.current.clear();
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_interleaved_lines() {
+        let path = env::temp_dir().join(format!("cargo-5730-output-log-{}", process::id()));
+        let log = OutputLog::new(&path);
+
+        let mut stdout = tee(
+            Some(&log),
+            &b"compiling\r\nno newline"[..],
+            "cargo build",
+            "stdout",
+        );
+        let mut stderr = tee(Some(&log), &b"error: oops\n"[..], "cargo build", "stderr");
+        let mut buf = [0; 4];
+        stdout.read_exact(&mut buf).unwrap();
+        io::copy(&mut stderr, &mut io::sink()).unwrap();
+        io::copy(&mut stdout, &mut io::sink()).unwrap();
+        drop((stdout, stderr));
+        drop(log);
+
+        let written = fs::read_to_string(&path).unwrap();
+        let fields: Vec<_> = written
+            .lines()
+            .map(|line| {
+                let rest = line.split_once(r#","process":"#).unwrap().1;
+                assert!(line.starts_with(r#"{"time":"#));
+                rest.to_owned()
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                r#""cargo build","stream":"stderr","line":"error: oops"}"#,
+                r#""cargo build","stream":"stdout","line":"compiling"}"#,
+                r#""cargo build","stream":"stdout","line":"no newline"}"#,
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Catching build crates that write different contents to the same file in
+//! the parent's `OUT_DIR`.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::{fs, io, path, time};
+
+use crate::fingerprint::Hasher;
+
+/// Where this crate builds in place, which no build script writes to.
+const OWN_DIR: &str = "cargo-5730";
+
+/// Size and modification time of every file in `OUT_DIR`.
+pub(crate) type Snapshot = BTreeMap<path::PathBuf, (u64, Option<time::SystemTime>)>;
+
+/// Note the state of every file under `out_dir`, to tell afterwards which
+/// ones a build script wrote.
+pub(crate) fn snapshot(out_dir: &path::Path) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    if let Err(e) = walk(out_dir, true, &mut snapshot) {
+        panic!("Couldn't read OUT_DIR {}: {}", out_dir.display(), e);
+    }
+    snapshot
+}
+
+fn walk(dir: &path::Path, is_root: bool, snapshot: &mut Snapshot) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if is_root && entry.file_name() == OWN_DIR {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(&entry.path(), false, snapshot)?;
+        } else {
+            snapshot.insert(entry.path(), (metadata.len(), metadata.modified().ok()));
+        }
+    }
+    Ok(())
+}
+
+/// Which build crate last wrote each generated file, and a hash of what it
+/// wrote.
+#[derive(Debug)]
+struct Ledger {
+    files: BTreeMap<path::PathBuf, (String, String)>,
+}
+
+impl Ledger {
+    const fn new() -> Self {
+        Ledger {
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Record that `producer` wrote `hash` to `path`, or describe the
+    /// conflict if another build crate already wrote something else there.
+    fn record(&mut self, path: &path::Path, hash: String, producer: &str) -> Result<(), String> {
+        if let Some((other_hash, other_producer)) = self.files.get(path) {
+            if other_producer != producer && *other_hash != hash {
+                return Err(format!(
+                    "{} was generated by both {} and {}, with different contents",
+                    path.display(),
+                    other_producer,
+                    producer
+                ));
+            }
+        }
+        self.files
+            .insert(path.to_owned(), (hash, producer.to_owned()));
+        Ok(())
+    }
+}
+
+/// Shared by every runner in the outer build script, so build crates run
+/// separately are checked against each other too.
+static LEDGER: Mutex<Ledger> = Mutex::new(Ledger::new());
+
+/// Attribute every file under `out_dir` that changed since `before` to
+/// `producer`, failing if another build crate generated any of them with
+/// different contents. Returns the changed files.
+pub(crate) fn record(
+    out_dir: &path::Path,
+    before: &Snapshot,
+    producer: &str,
+) -> Vec<path::PathBuf> {
+    let mut ledger = LEDGER.lock().unwrap_or_else(|e| e.into_inner());
+    let mut conflicts = Vec::new();
+    let mut changed = Vec::new();
+
+    for (path, state) in snapshot(out_dir) {
+        if before.get(&path) == Some(&state) {
+            continue;
+        }
+        let content =
+            fs::read(&path).unwrap_or_else(|e| panic!("Couldn't read {}: {}", path.display(), e));
+        let mut hasher = Hasher::new();
+        hasher.write(&content);
+        if let Err(conflict) = ledger.record(&path, hasher.finish(), producer) {
+            conflicts.push(conflict);
+        }
+        changed.push(path);
+    }
+
+    assert!(
+        conflicts.is_empty(),
+        "Build crates generated conflicting files:\n  {}",
+        conflicts.join("\n  ")
+    );
+    changed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ledger() {
+        let mut ledger = Ledger::new();
+        let path = path::Path::new("/out/bindings.rs");
+        assert!(ledger.record(path, "1".to_owned(), "a").is_ok());
+        // Rewriting its own file, or another crate writing the same thing,
+        // is fine.
+        assert!(ledger.record(path, "2".to_owned(), "a").is_ok());
+        assert!(ledger.record(path, "2".to_owned(), "b").is_ok());
+        assert_eq!(
+            ledger.record(path, "3".to_owned(), "a").unwrap_err(),
+            "/out/bindings.rs was generated by both b and a, with different contents"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Spotting crates the build crate shares with its parent, which is where
+//! feature unification would bite if it weren't isolated.
+
+use std::collections::BTreeMap;
+use std::{fs, path};
+
+/// Crate name to the versions of it in a lockfile.
+type Packages = BTreeMap<String, Vec<String>>;
+
+/// The packages listed in the `Cargo.lock` `content`.
+fn parse_lockfile(content: &str) -> Packages {
+    let mut packages = Packages::new();
+    let mut name = None;
+
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            name = None;
+        } else if let Some(value) = string_value(line, "name") {
+            name = Some(value.to_owned());
+        } else if let (Some(name), Some(version)) = (&name, string_value(line, "version")) {
+            packages
+                .entry(name.clone())
+                .or_default()
+                .push(version.to_owned());
+        }
+    }
+
+    packages
+}
+
+/// The value of `key = "value"`, if that's what `line` is.
+fn string_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let value = line.strip_prefix(key)?.trim_start().strip_prefix('=')?;
+    value.trim().strip_prefix('"')?.strip_suffix('"')
+}
+
+/// The lockfile the parent crate is resolved with: the closest `Cargo.lock`
+/// at or above its manifest dir.
+pub(crate) fn parent_lockfile(manifest_dir: &path::Path) -> Option<path::PathBuf> {
+    manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lockfile| lockfile.is_file())
+}
+
+/// One line per crate in both lockfiles, saying which versions each side has.
+fn overlap(build_crate: &Packages, parent: &Packages) -> Vec<String> {
+    build_crate
+        .iter()
+        .filter_map(|(name, versions)| {
+            let parent_versions = parent.get(name)?;
+            let relation = if versions == parent_versions {
+                "same version, so their features would be unified without isolation"
+            } else {
+                "different versions"
+            };
+            Some(format!(
+                "{} (build crate: {}, parent: {}; {})",
+                name,
+                versions.join(", "),
+                parent_versions.join(", "),
+                relation
+            ))
+        })
+        .collect()
+}
+
+/// Warn about every crate that appears in both the staged build crate's
+/// lockfile and the parent's.
+pub(crate) fn report(staged_dir: &path::Path, parent_lockfile: &path::Path) {
+    let read = |lockfile: &path::Path| {
+        fs::read_to_string(lockfile)
+            .map(|content| parse_lockfile(&content))
+            .unwrap_or_else(|e| panic!("Couldn't read {}: {}", lockfile.display(), e))
+    };
+    let build_crate = read(&staged_dir.join("Cargo.lock"));
+    let parent = read(parent_lockfile);
+
+    for line in overlap(&build_crate, &parent) {
+        println!("cargo:warning=Shared with the parent crate: {}", line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BUILD_CRATE_LOCK: &str = r#"
+version = 3
+
+[[package]]
+name = "build-script"
+version = "0.1.0"
+dependencies = [
+ "cc",
+ "libc",
+]
+
+[[package]]
+name = "cc"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "libc"
+version = "0.2.50"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    const PARENT_LOCK: &str = r#"
+[[package]]
+name = "libc"
+version = "0.2.60"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "cc"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    #[test]
+    fn test_overlap() {
+        let build_crate = parse_lockfile(BUILD_CRATE_LOCK);
+        assert_eq!(build_crate["libc"], ["0.2.50"]);
+        assert_eq!(
+            overlap(&build_crate, &parse_lockfile(PARENT_LOCK)),
+            [
+                "cc (build crate: 1.0.0, parent: 1.0.0; same version, so their features would be unified without isolation)",
+                "libc (build crate: 0.2.50, parent: 0.2.60; different versions)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! `CARGO_5730_*` environment variables, which let a developer or CI tweak
+//! behavior across a whole workspace without touching every build.rs. When
+//! set, they take precedence over whatever the build script configured.
+
+use std::{env, path, time};
+
+use crate::options::{Profile, Settings, Verbosity};
+
+/// Set to a truthy value to leave staging dirs in place after the build, or
+/// to `failure` to leave them only when the build crate fails.
+pub const KEEP_ENV: &str = "CARGO_5730_KEEP";
+/// `quiet`, `normal`, `verbose` or `very-verbose` (or `0` to `3`).
+pub const VERBOSITY_ENV: &str = "CARGO_5730_VERBOSITY";
+/// `debug` (or `dev`), `release`, or `outer` to match the outer build.
+pub const PROFILE_ENV: &str = "CARGO_5730_PROFILE";
+/// The directory staging dirs are created in.
+pub const STAGING_ROOT_ENV: &str = "CARGO_5730_STAGING_ROOT";
+/// Set to a truthy value to pass `--offline` to the inner cargo.
+pub const OFFLINE_ENV: &str = "CARGO_5730_OFFLINE";
+/// Set to a truthy value to pass `--locked` to the inner cargo.
+pub const LOCKED_ENV: &str = "CARGO_5730_LOCKED";
+/// The directory to keep compiled build scripts in between runs.
+pub const BUILD_CACHE_ENV: &str = "CARGO_5730_BUILD_CACHE";
+/// The directory staged build crates keep their target dirs in.
+pub const TARGET_DIR_ENV: &str = "CARGO_5730_TARGET_DIR";
+/// The `CARGO_HOME` the inner cargo uses, instead of the outer build's.
+pub const CARGO_HOME_ENV: &str = "CARGO_5730_CARGO_HOME";
+/// The number of jobs the inner cargo may run at once.
+pub const JOBS_ENV: &str = "CARGO_5730_JOBS";
+/// Set to a truthy value to treat the build as check-only, or to a falsy one
+/// to say it isn't; see `CheckMode`.
+pub const CHECK_ENV: &str = "CARGO_5730_CHECK";
+
+pub(crate) const ALL: &[&str] = &[
+    KEEP_ENV,
+    VERBOSITY_ENV,
+    PROFILE_ENV,
+    STAGING_ROOT_ENV,
+    OFFLINE_ENV,
+    LOCKED_ENV,
+    BUILD_CACHE_ENV,
+    TARGET_DIR_ENV,
+    CARGO_HOME_ENV,
+    JOBS_ENV,
+    CHECK_ENV,
+];
+
+/// Apply any overrides present in the environment to `settings`.
+pub(crate) fn apply(mut settings: Settings) -> Settings {
+    if let Some(verbosity) = env_value(VERBOSITY_ENV, parse_verbosity) {
+        settings.verbosity = verbosity;
+    }
+    if let Some(profile) = env_value(PROFILE_ENV, parse_profile) {
+        settings.profile = profile;
+    }
+    if let Some(staging_root) = env::var_os(STAGING_ROOT_ENV).filter(|root| !root.is_empty()) {
+        settings.staging_root = Some(path::PathBuf::from(staging_root));
+    }
+    if let Some(build_cache) = env::var_os(BUILD_CACHE_ENV).filter(|dir| !dir.is_empty()) {
+        settings.build_cache = Some(path::PathBuf::from(build_cache));
+    }
+    if let Some(target_dir) = env::var_os(TARGET_DIR_ENV).filter(|dir| !dir.is_empty()) {
+        settings.target_dir = Some(path::PathBuf::from(target_dir));
+    }
+    if let Some(cargo_home) = env::var_os(CARGO_HOME_ENV).filter(|dir| !dir.is_empty()) {
+        settings.cargo_home = Some(path::PathBuf::from(cargo_home));
+    }
+    if let Some(jobs) = env_value(JOBS_ENV, parse_jobs) {
+        settings.jobs = Some(jobs.to_string());
+    }
+    if let Some(offline) = env_flag(OFFLINE_ENV) {
+        settings.offline = offline;
+    }
+    if let Some(locked) = env_flag(LOCKED_ENV) {
+        settings.locked = locked;
+    }
+    match env::var(KEEP_ENV) {
+        Ok(keep) if keep.trim().eq_ignore_ascii_case("failure") => {
+            settings.keep_on_failure = true;
+        }
+        _ => {
+            if let Some(keep) = env_flag(KEEP_ENV) {
+                settings.keep = keep;
+            }
+        }
+    }
+    settings
+}
+
+/// Read a boolean flag from the environment. Unset or empty means `None`;
+/// `0`, `false`, `no` and `off` (in any case) mean `false`; anything else
+/// means `true`.
+pub(crate) fn env_flag(name: &str) -> Option<bool> {
+    let value = env::var(name).ok()?;
+    parse_flag(&value)
+}
+
+fn env_value<T>(name: &str, parse: fn(&str) -> Option<T>) -> Option<T> {
+    let value = env::var(name).ok()?;
+    if value.trim().is_empty() {
+        return None;
+    }
+    let parsed = parse(&value);
+    assert!(
+        parsed.is_some(),
+        "Unrecognized value for {}: {}",
+        name,
+        value
+    );
+    parsed
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    let value = value.trim().to_ascii_lowercase();
+    match value.as_str() {
+        "" => None,
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => Some(true),
+    }
+}
+
+pub(crate) fn parse_verbosity(value: &str) -> Option<Verbosity> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "quiet" | "0" => Some(Verbosity::Quiet),
+        "normal" | "1" => Some(Verbosity::Normal),
+        "verbose" | "2" => Some(Verbosity::Verbose),
+        "very-verbose" | "3" => Some(Verbosity::VeryVerbose),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_profile(value: &str) -> Option<Profile> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "debug" | "dev" => Some(Profile::Debug),
+        "release" => Some(Profile::Release),
+        "outer" => Some(Profile::outer()),
+        _ => None,
+    }
+}
+
+/// A duration like `90` or `90s`, `30m`, `12h` or `7d`.
+pub(crate) fn parse_duration(value: &str) -> Option<time::Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let number: u64 = number.parse().ok()?;
+    Some(time::Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+fn parse_jobs(value: &str) -> Option<u32> {
+    value.trim().parse().ok().filter(|&jobs| jobs > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_flag() {
+        assert_eq!(parse_flag(""), None);
+        assert_eq!(parse_flag("1"), Some(true));
+        assert_eq!(parse_flag("yes"), Some(true));
+        assert_eq!(parse_flag("0"), Some(false));
+        assert_eq!(parse_flag(" False "), Some(false));
+        assert_eq!(parse_flag("off"), Some(false));
+    }
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!(parse_verbosity("Quiet"), Some(Verbosity::Quiet));
+        assert_eq!(parse_verbosity("2"), Some(Verbosity::Verbose));
+        assert_eq!(parse_verbosity("loud"), None);
+        assert_eq!(parse_profile("dev"), Some(Profile::Debug));
+        assert_eq!(parse_profile("release"), Some(Profile::Release));
+        assert_eq!(parse_profile("bench"), None);
+        assert_eq!(parse_duration("90"), Some(time::Duration::from_secs(90)));
+        assert_eq!(
+            parse_duration("30m"),
+            Some(time::Duration::from_secs(30 * 60))
+        );
+        assert_eq!(
+            parse_duration(" 7d "),
+            Some(time::Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("1w"), None);
+        assert_eq!(parse_jobs(" 4"), Some(4));
+        assert_eq!(parse_jobs("0"), None);
+        assert_eq!(parse_jobs("many"), None);
+    }
+}
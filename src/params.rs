@@ -0,0 +1,72 @@
+//! Parameters for the build script from the outer build script, for what
+//! doesn't fit comfortably in an environment variable of its own.
+
+use std::collections::BTreeMap;
+use std::{env, fs, path};
+
+use crate::json::Json;
+
+/// Set for the build script to a file holding the parameters given with
+/// `BuildCrateRunner::script_param`, as a JSON object of strings. Only set
+/// when there are some.
+pub const PARAMS_FILE_ENV: &str = "CARGO_5730_PARAMS_FILE";
+
+/// Write `params` to `path`, for the build script to read.
+pub(crate) fn write(path: &path::Path, params: &BTreeMap<String, String>) {
+    let json = Json::object(
+        params
+            .iter()
+            .map(|(key, value)| (key.as_str(), Json::string(value.as_str()))),
+    );
+    fs::write(path, json.to_string() + "\n")
+        .unwrap_or_else(|e| panic!("Couldn't write {}: {}", path.display(), e));
+}
+
+/// The parameters the outer build script gave this build script, for build
+/// crates that depend on this crate too. Empty when there aren't any.
+pub fn read_script_params() -> BTreeMap<String, String> {
+    let path = match env::var_os(PARAMS_FILE_ENV) {
+        Some(path) => path::PathBuf::from(path),
+        None => return BTreeMap::new(),
+    };
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Couldn't read {}: {}", path.display(), e));
+    parse(&text).unwrap_or_else(|e| panic!("Couldn't parse {}: {}", path.display(), e))
+}
+
+fn parse(text: &str) -> Result<BTreeMap<String, String>, String> {
+    match Json::parse(text.trim_end())? {
+        Json::Object(fields) => fields
+            .into_iter()
+            .map(|(key, value)| match value {
+                Json::String(value) => Ok((key, value)),
+                _ => Err(format!("the value of {} isn't a string", key)),
+            })
+            .collect(),
+        _ => Err("not a JSON object".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = env::temp_dir().join(format!("cargo-5730-params-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("params.json");
+
+        let mut params = BTreeMap::new();
+        params.insert("schema".to_owned(), "C:\\schemas\\v2.json".to_owned());
+        params.insert("banner".to_owned(), "two\nlines, \"quoted\"".to_owned());
+        write(&path, &params);
+        assert_eq!(parse(&fs::read_to_string(&path).unwrap()), Ok(params));
+
+        assert!(parse("{\"jobs\": 4}").is_err());
+        assert!(parse("[]").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+//! Which variables from the outer build script's environment reach the inner
+//! cargo, which otherwise runs with an empty one.
+
+use std::{env, ffi};
+
+/// Forwarded everywhere: what it takes to find and run the toolchain.
+/// `CARGO_MAKEFLAGS` names the outer cargo's jobserver, which the inner cargo
+/// joins rather than starting as many jobs again on top of the outer build's.
+/// `CARGO_HOME` keeps it using the same registry cache as the outer build, and
+/// `HOME` is where git and ssh find their configuration and keys, for git
+/// dependencies.
+const COMMON_VARS: &[&str] = &[
+    "PATH",
+    "SSH_AUTH_SOCK",
+    "RUSTUP_HOME",
+    "RUSTUP_TOOLCHAIN",
+    "CARGO_MAKEFLAGS",
+    "CARGO_HOME",
+    "HOME",
+];
+
+/// What it takes to reach crates.io, or a mirror, from behind a proxy.
+/// Curl reads the lowercase spellings, which Unix tells apart.
+const NETWORK_VARS: &[&str] = &[
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "ALL_PROXY",
+    "NO_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "all_proxy",
+    "no_proxy",
+    "SSL_CERT_FILE",
+    "SSL_CERT_DIR",
+    "CARGO_HTTP_*",
+    "CARGO_NET_*",
+];
+
+/// Where tools, linkers and C compilers among them, look for a temp dir on
+/// this platform.
+#[cfg(windows)]
+const TEMP_VARS: &[&str] = &["TEMP", "TMP"];
+#[cfg(not(windows))]
+const TEMP_VARS: &[&str] = &["TMPDIR"];
+
+/// What tools commonly can't do without on this platform.
+#[cfg(windows)]
+const PLATFORM_VARS: &[&str] = &["SYSTEMROOT", "COMSPEC", "PATHEXT", "USERPROFILE"];
+#[cfg(target_os = "macos")]
+const PLATFORM_VARS: &[&str] = &[
+    "NIX_*",
+    "SDKROOT",
+    "DEVELOPER_DIR",
+    "MACOSX_DEPLOYMENT_TARGET",
+];
+#[cfg(not(any(windows, target_os = "macos")))]
+const PLATFORM_VARS: &[&str] = &["NIX_*"];
+
+/// What a `vcvarsall` developer prompt sets up for MSVC's linker and C
+/// compiler, when that's the toolchain the build crate is built with.
+#[cfg(target_env = "msvc")]
+const TOOLCHAIN_VARS: &[&str] = &[
+    "LIB",
+    "LIBPATH",
+    "INCLUDE",
+    "VCINSTALLDIR",
+    "VCToolsInstallDir",
+    "VCToolsVersion",
+    "VSINSTALLDIR",
+    "VSCMD_*",
+    "WindowsSdkDir",
+    "WindowsSDKVersion",
+    "WindowsSdkBinPath",
+    "UniversalCRTSdkDir",
+    "UCRTVersion",
+    "Platform",
+];
+#[cfg(not(target_env = "msvc"))]
+const TOOLCHAIN_VARS: &[&str] = &[];
+
+/// Whether `var` matches one of `patterns`: a variable's name, or a prefix
+/// followed by `*`, such as `CARGO_CFG_*`.
+pub(crate) fn matches_any(patterns: &[String], var: &str) -> bool {
+    patterns.iter().any(|pattern| matches(pattern, var))
+}
+
+fn matches(pattern: &str, var: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => var
+            .get(..prefix.len())
+            .is_some_and(|start| same_name(start, prefix)),
+        None => same_name(var, pattern),
+    }
+}
+
+/// Variable names are case-insensitive on Windows.
+fn same_name(a: &str, b: &str) -> bool {
+    if cfg!(windows) {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a == b
+    }
+}
+
+/// The variables to forward: the defaults and those matching `pass`, less
+/// those matching `deny`. Unset variables are left out, except for the temp
+/// dir's, which are set to wherever the outer build's temp dir is (`/tmp`,
+/// say, when `TMPDIR` isn't set), so that it's the same. Values are kept as
+/// `OsString`s, since paths with non-UTF-8 components are perfectly valid in
+/// `PATH` and friends.
+pub(crate) fn forwarded(pass: &[String], deny: &[String]) -> Vec<(String, ffi::OsString)> {
+    forwarded_from(env::vars_os().collect(), pass, deny)
+}
+
+/// `forwarded`, from the environment `vars`.
+fn forwarded_from(
+    vars: Vec<(ffi::OsString, ffi::OsString)>,
+    pass: &[String],
+    deny: &[String],
+) -> Vec<(String, ffi::OsString)> {
+    let vars: Vec<(String, ffi::OsString)> = vars
+        .into_iter()
+        .filter_map(|(var, value)| Some((var.into_string().ok()?, value)))
+        .collect();
+    let patterns: Vec<&str> = COMMON_VARS
+        .iter()
+        .chain(NETWORK_VARS)
+        .chain(TEMP_VARS)
+        .chain(PLATFORM_VARS)
+        .chain(TOOLCHAIN_VARS)
+        .copied()
+        .chain(pass.iter().map(String::as_str))
+        .collect();
+
+    // Names are looked up as given, so they're spelled the same whatever the
+    // platform calls them; prefixes are matched against everything that's
+    // set.
+    let named = patterns
+        .iter()
+        .filter(|pattern| !pattern.ends_with('*'))
+        .filter_map(|var| {
+            let (_, value) = vars.iter().find(|(set, _)| same_name(set, var))?;
+            Some(((*var).to_owned(), value.clone()))
+        });
+    let mut prefixed: Vec<_> = vars
+        .iter()
+        .filter(|(var, _)| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.ends_with('*') && matches(pattern, var))
+        })
+        .cloned()
+        .collect();
+    prefixed.sort();
+
+    let mut forwarded: Vec<(String, ffi::OsString)> = Vec::new();
+    for (var, value) in named.chain(prefixed) {
+        let seen = forwarded.iter().any(|(seen, _)| same_name(seen, &var));
+        if !seen && !matches_any(deny, &var) {
+            forwarded.push((var, value));
+        }
+    }
+    for var in TEMP_VARS {
+        let seen = forwarded.iter().any(|(seen, _)| same_name(seen, var));
+        if !seen && !matches_any(deny, var) {
+            forwarded.push(((*var).to_owned(), env::temp_dir().into_os_string()));
+        }
+    }
+    forwarded
+}
+
+/// Variables set for build scripts that the inner cargo passes on untouched,
+/// though their names say otherwise.
+const BUILD_SCRIPT_PREFIXES: &[&str] = &["CARGO_CFG_", "CARGO_FEATURE_", "CARGO_PKG_", "DEP_"];
+
+/// Whether the inner cargo would take `var` as configuration of its own,
+/// rather than pass it on to what it runs: `CARGO_*` config, rustflags and
+/// the tools it calls.
+pub(crate) fn configures_cargo(var: &str) -> bool {
+    match var {
+        "RUSTC"
+        | "RUSTDOC"
+        | "RUSTFLAGS"
+        | "RUSTDOCFLAGS"
+        | "RUSTC_WRAPPER"
+        | "RUSTC_WORKSPACE_WRAPPER"
+        | "RUSTUP_TOOLCHAIN" => true,
+        _ if BUILD_SCRIPT_PREFIXES
+            .iter()
+            .any(|prefix| var.starts_with(prefix)) =>
+        {
+            false
+        }
+        _ => var == "CARGO" || var.starts_with("CARGO_") || var.starts_with("__CARGO"),
+    }
+}
+
+/// The rustflags the outer build is using: `CARGO_ENCODED_RUSTFLAGS`, which
+/// cargo sets for build scripts, or failing that `RUSTFLAGS`.
+pub(crate) fn outer_rustflags() -> Vec<String> {
+    parse_rustflags(
+        env::var("CARGO_ENCODED_RUSTFLAGS").ok().as_deref(),
+        env::var("RUSTFLAGS").ok().as_deref(),
+    )
+}
+
+fn parse_rustflags(encoded: Option<&str>, plain: Option<&str>) -> Vec<String> {
+    match (encoded, plain) {
+        (Some(encoded), _) if !encoded.is_empty() => {
+            encoded.split('\x1f').map(str::to_owned).collect()
+        }
+        (_, Some(plain)) => plain.split_whitespace().map(str::to_owned).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches_any() {
+        let patterns = ["OUT_DIR".to_owned(), "CARGO_CFG_*".to_owned()];
+        assert!(matches_any(&patterns, "OUT_DIR"));
+        assert!(matches_any(&patterns, "CARGO_CFG_TARGET_OS"));
+        assert!(!matches_any(&patterns, "OUT_DIR_2"));
+        assert!(!matches_any(&patterns, "CARGO_FEATURE_STD"));
+        assert!(!matches_any(&[], "OUT_DIR"));
+    }
+
+    #[test]
+    fn test_configures_cargo() {
+        for var in [
+            "CARGO_TARGET_DIR",
+            "CARGO_ENCODED_RUSTFLAGS",
+            "RUSTFLAGS",
+            "CARGO",
+        ] {
+            assert!(configures_cargo(var), "{}", var);
+        }
+        for var in [
+            "OUT_DIR",
+            "TARGET",
+            "CARGO_CFG_TARGET_OS",
+            "CARGO_FEATURE_STD",
+        ] {
+            assert!(!configures_cargo(var), "{}", var);
+        }
+    }
+
+    #[test]
+    fn test_parse_rustflags() {
+        assert_eq!(
+            parse_rustflags(Some("-C\x1ftarget-cpu=native"), Some("--cfg ignored")),
+            ["-C", "target-cpu=native"]
+        );
+        assert_eq!(
+            parse_rustflags(Some(""), Some(" --cfg  fast ")),
+            ["--cfg", "fast"]
+        );
+        assert!(parse_rustflags(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let vars = [
+            ("CARGO_5730_TEST_PASS_A", "a"),
+            ("CARGO_5730_TEST_PASS_B", "b"),
+            ("CARGO_5730_TEST_PASS_SECRET", "s"),
+            ("CARGO_HTTP_CARGO_5730_TEST", "1"),
+            ("PATH", "/bin"),
+        ];
+        let forwarded = forwarded_from(
+            vars.iter()
+                .map(|(var, value)| (var.into(), value.into()))
+                .collect(),
+            &["CARGO_5730_TEST_PASS_*".to_owned()],
+            &["CARGO_5730_TEST_PASS_SECRET".to_owned(), "PATH".to_owned()],
+        );
+        let names: Vec<_> = forwarded.iter().map(|(var, _)| var.as_str()).collect();
+        assert!(names.contains(&"CARGO_5730_TEST_PASS_A"));
+        assert!(names.contains(&"CARGO_5730_TEST_PASS_B"));
+        assert!(!names.contains(&"CARGO_5730_TEST_PASS_SECRET"));
+        assert!(!names.contains(&"PATH"));
+        assert!(names.contains(&"CARGO_HTTP_CARGO_5730_TEST"));
+        for var in TEMP_VARS {
+            assert!(names.contains(var));
+        }
+    }
+}
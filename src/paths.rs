@@ -0,0 +1,171 @@
+//! Path helpers for the places where the platform's idea of a path leaks
+//! through.
+
+use std::path;
+
+/// Strip Windows' verbatim (`\\?\`) prefix off a path, turning
+/// `\\?\UNC\server\share\dir` into `\\server\share\dir` and `\\?\C:\dir` into
+/// `C:\dir`. Returns `None` if there's nothing to strip.
+///
+/// `canonicalize` produces verbatim paths on Windows, and resolves mapped
+/// network drives to their UNC form; neither cargo nor a lot of other tooling
+/// accepts verbatim paths in manifests or config.
+fn strip_verbatim_prefix(path: &str) -> Option<String> {
+    if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        return Some(format!(r"\\{}", unc));
+    }
+
+    let rest = path.strip_prefix(r"\\?\")?;
+    let mut chars = rest.chars();
+    match (chars.next(), chars.next()) {
+        (Some(drive), Some(':')) if drive.is_ascii_alphabetic() => Some(rest.to_owned()),
+        _ => None,
+    }
+}
+
+/// Rewrite `path` into the plain form that's safe to hand to other tools and
+/// compare against other paths. Only does anything on Windows.
+pub(crate) fn normalize(path: &path::Path) -> path::PathBuf {
+    if cfg!(windows) {
+        if let Some(stripped) = path.to_str().and_then(strip_verbatim_prefix) {
+            return path::PathBuf::from(stripped);
+        }
+    }
+    path.to_owned()
+}
+
+/// `path` as it's written into a manifest or cargo config. TOML is UTF-8, so
+/// a path that isn't can't be written at all. On Windows, it's written with
+/// forward slashes, which cargo takes just as well, rather than backslashes
+/// that a TOML basic string would have to escape.
+pub(crate) fn to_toml(path: &path::Path) -> Result<String, String> {
+    let utf8 = path.to_str().ok_or_else(|| {
+        format!(
+            "{} isn't valid UTF-8, so it can't be written into TOML",
+            path.display()
+        )
+    })?;
+    Ok(match cfg!(windows) {
+        true => forward_slashes(utf8),
+        false => utf8.to_owned(),
+    })
+}
+
+/// A Windows path with forward slashes, and without a verbatim prefix where
+/// there's a plain form: `\\?\C:\dir` becomes `C:/dir`, and `\\server\share`
+/// becomes `//server/share`.
+fn forward_slashes(path: &str) -> String {
+    strip_verbatim_prefix(path)
+        .as_deref()
+        .unwrap_or(path)
+        .replace('\\', "/")
+}
+
+/// The longest path Windows takes, unless long paths are enabled both for the
+/// system and in the manifest of the program using it, which not every tool
+/// in a build is.
+const MAX_PATH: usize = 260;
+
+/// The length of the longest of `files` if it's past `MAX_PATH`. The Rust
+/// standard library gets around the limit on its own, so staging works
+/// either way; it's the build tools that may not.
+pub(crate) fn past_max_path(files: &[path::PathBuf]) -> Option<usize> {
+    files
+        .iter()
+        .map(|file| file.as_os_str().len())
+        .max()
+        .filter(|longest| *longest > MAX_PATH)
+}
+
+/// Whether `path` lives under `root`, after normalizing both. Windows paths
+/// compare case-insensitively, since `\\SERVER\share` and `\\server\share`
+/// are the same place.
+pub(crate) fn is_within(path: &path::Path, root: &path::Path) -> bool {
+    let (path, root) = (normalize(path), normalize(root));
+    if path.starts_with(&root) {
+        return true;
+    }
+
+    if cfg!(windows) {
+        if let (Some(path), Some(root)) = (path.to_str(), root.to_str()) {
+            let (path, root) = (path.to_lowercase(), root.to_lowercase());
+            let root = root.trim_end_matches('\\');
+            return path == root || path.starts_with(&format!("{}\\", root));
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::toml;
+
+    #[test]
+    fn test_strip_verbatim_prefix() {
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\UNC\server\share\tmp\build-script-1"),
+            Some(r"\\server\share\tmp\build-script-1".to_owned())
+        );
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\C:\Users\ci\AppData\Local\Temp"),
+            Some(r"C:\Users\ci\AppData\Local\Temp".to_owned())
+        );
+        assert_eq!(strip_verbatim_prefix(r"\\server\share\tmp"), None);
+        assert_eq!(strip_verbatim_prefix(r"\\?\Volume{1234}\tmp"), None);
+        assert_eq!(strip_verbatim_prefix("/tmp/build-script-1"), None);
+    }
+
+    #[test]
+    fn test_forward_slashes() {
+        assert_eq!(
+            forward_slashes(r"C:\Users\ci\build-script"),
+            "C:/Users/ci/build-script"
+        );
+        assert_eq!(
+            forward_slashes(r"\\?\C:\Users\ci\AppData\Local\Temp"),
+            "C:/Users/ci/AppData/Local/Temp"
+        );
+        assert_eq!(
+            forward_slashes(r"\\?\UNC\server\share\build-script"),
+            "//server/share/build-script"
+        );
+        assert_eq!(forward_slashes(r"D:\it's a\\dir"), "D:/it's a//dir");
+        assert_eq!(forward_slashes("vendor"), "vendor");
+
+        let quoted = toml::quote(&forward_slashes(r#"C:\My "Odd" Dir\x"#), false);
+        assert_eq!(quoted, r#""C:/My \"Odd\" Dir/x""#);
+        assert_eq!(
+            toml::parse(&format!("path = {}", quoted))
+                .unwrap()
+                .get(&["path"])
+                .and_then(toml::Value::as_str),
+            Some(r#"C:/My "Odd" Dir/x"#)
+        );
+    }
+
+    #[test]
+    fn test_past_max_path() {
+        let deep = path::PathBuf::from("C:/").join("nested/".repeat(40));
+        let shallow = path::PathBuf::from("C:/c5730-0123abcd-42/src/main.rs");
+        assert_eq!(past_max_path(std::slice::from_ref(&shallow)), None);
+        assert_eq!(
+            past_max_path(&[shallow, deep.clone()]),
+            Some(deep.as_os_str().len())
+        );
+        assert_eq!(past_max_path(&[]), None);
+    }
+
+    #[test]
+    fn test_is_within() {
+        assert!(is_within(
+            path::Path::new("/tmp/build-script-1"),
+            path::Path::new("/tmp")
+        ));
+        assert!(!is_within(
+            path::Path::new("/tmpfoo/build-script-1"),
+            path::Path::new("/tmp")
+        ));
+    }
+}
@@ -0,0 +1,178 @@
+//! Checking that the tools the inner build will need can actually be found,
+//! before the inner cargo fails in some less obvious way.
+
+use std::{env, ffi, fmt, fs, path};
+
+/// Something the inner build needs, but which isn't reachable through the
+/// `PATH` it's going to get.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingTool {
+    /// The executable we looked for, e.g. `rustc`.
+    pub tool: String,
+    /// What the inner build needs it for.
+    pub needed_for: &'static str,
+    /// Whether the build can't possibly succeed without it. Non-fatal issues
+    /// are reported as cargo warnings.
+    pub fatal: bool,
+}
+
+impl fmt::Display for MissingTool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` ({}) was not found on the PATH forwarded to the build crate",
+            self.tool, self.needed_for
+        )
+    }
+}
+
+/// Check for the tools needed to build the crate in `build_crate_dir` with
+/// `cargo`, given the `PATH` the inner cargo will see.
+pub(crate) fn check(
+    cargo: &ffi::OsStr,
+    search_path: &ffi::OsStr,
+    build_crate_dir: &path::Path,
+) -> Vec<MissingTool> {
+    let mut missing = Vec::new();
+    let mut require = |tool: &str, needed_for, fatal| {
+        if find_tool(tool, search_path).is_none() {
+            missing.push(MissingTool {
+                tool: tool.to_owned(),
+                needed_for,
+                fatal,
+            });
+        }
+    };
+
+    // Cargo is normally handed to us as an absolute path.
+    if !path::Path::new(cargo).is_file() {
+        require(&cargo.to_string_lossy(), "building the build crate", true);
+    }
+    require("rustc", "compiling the build crate", true);
+
+    // MSVC's linker and compiler are located through the registry, not PATH.
+    let msvc = env::var("TARGET").map_or(cfg!(target_env = "msvc"), |t| t.ends_with("-msvc"));
+    if !msvc {
+        let needed_for = if uses_c_compiler(build_crate_dir) {
+            "linking the build script and compiling its C dependencies"
+        } else {
+            "linking the build script"
+        };
+        require("cc", needed_for, false);
+    }
+
+    missing
+}
+
+/// Report the issues from `check`. Fatal ones abort the build, naming every
+/// missing tool at once.
+pub(crate) fn report(missing: &[MissingTool]) {
+    for issue in missing.iter().filter(|issue| !issue.fatal) {
+        println!("cargo:warning={}", issue);
+    }
+
+    let fatal: Vec<String> = missing
+        .iter()
+        .filter(|issue| issue.fatal)
+        .map(|issue| issue.to_string())
+        .collect();
+    assert!(
+        fatal.is_empty(),
+        "Can't build the build crate:\n  {}",
+        fatal.join("\n  ")
+    );
+}
+
+fn find_tool(tool: &str, search_path: &ffi::OsStr) -> Option<path::PathBuf> {
+    let candidates: Vec<String> = if cfg!(windows) && !tool.ends_with(".exe") {
+        vec![format!("{}.exe", tool), tool.to_owned()]
+    } else {
+        vec![tool.to_owned()]
+    };
+
+    if path::Path::new(tool).components().count() > 1 {
+        return candidates
+            .iter()
+            .map(path::PathBuf::from)
+            .find(|candidate| candidate.is_file());
+    }
+
+    env::split_paths(search_path)
+        .flat_map(|dir| candidates.iter().map(move |c| dir.join(c)))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Whether the build crate looks like it compiles C code, i.e. depends on
+/// `cc` or `cmake`, or has `links` native libraries.
+fn uses_c_compiler(build_crate_dir: &path::Path) -> bool {
+    let manifest = match fs::read_to_string(build_crate_dir.join("Cargo.toml")) {
+        Ok(manifest) => manifest,
+        Err(_) => return false,
+    };
+
+    manifest.lines().any(|line| {
+        let line = line.trim();
+        ["cc", "cmake"].iter().any(|dep| {
+            line.starts_with(&format!("{} ", dep))
+                || line.starts_with(&format!("{}=", dep))
+                || line.starts_with(&format!("{}.", dep))
+                || line.ends_with(&format!(".{}]", dep))
+        }) || line.starts_with("links")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uses_c_compiler() {
+        let dir = env::temp_dir().join(format!("cargo-5730-preflight-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"x\"\n\n[dependencies]\nserde = \"1\"\n",
+        )
+        .unwrap();
+        assert!(!uses_c_compiler(&dir));
+
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"x\"\n\n[build-dependencies]\ncc = \"1\"\n",
+        )
+        .unwrap();
+        assert!(uses_c_compiler(&dir));
+
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"x\"\n\n[dependencies.cmake]\nversion = \"0.1\"\n",
+        )
+        .unwrap();
+        assert!(uses_c_compiler(&dir));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_rustc_is_fatal() {
+        let empty =
+            env::temp_dir().join(format!("cargo-5730-preflight-empty-{}", std::process::id()));
+        fs::create_dir_all(&empty).unwrap();
+
+        let missing = check(
+            ffi::OsStr::new("/nonexistent/cargo"),
+            empty.as_os_str(),
+            &empty,
+        );
+        let tools: Vec<&str> = missing.iter().map(|m| m.tool.as_str()).collect();
+        assert!(tools.contains(&"/nonexistent/cargo"));
+        assert!(tools.contains(&"rustc"));
+        assert!(missing
+            .iter()
+            .filter(|m| m.tool == "rustc")
+            .all(|m| m.fatal));
+
+        fs::remove_dir_all(&empty).unwrap();
+    }
+}
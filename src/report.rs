@@ -0,0 +1,154 @@
+//! What happened during a run, for logs, bug reports and cache keys.
+
+use std::{ffi, path, process, time};
+
+use crate::json::Json;
+use crate::Profile;
+
+/// The toolchain used inside the isolation boundary, which isn't necessarily
+/// the one running the outer build.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Toolchain {
+    /// Output of `cargo --version`, if it could be run.
+    pub cargo_version: Option<String>,
+    /// Output of `rustc --version`, if it could be run.
+    pub rustc_version: Option<String>,
+    /// The target triple rustc compiles for by default, if it could be run.
+    pub host: Option<String>,
+}
+
+impl Toolchain {
+    /// Ask the inner toolchain for its versions, from the directory the build
+    /// crate will be compiled in so toolchain overrides there are honored.
+    pub(crate) fn detect(
+        cargo: &ffi::OsStr,
+        crate_dir: &path::Path,
+        forwarded_env: &[(String, ffi::OsString)],
+    ) -> Self {
+        let version = |program: &ffi::OsStr, arg: &str| {
+            let output = process::Command::new(program)
+                .arg(arg)
+                .env_clear()
+                .envs(forwarded_env.iter().map(|(k, v)| (k, v)))
+                .current_dir(crate_dir)
+                .output()
+                .ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        };
+
+        // `rustc -vV` starts with what `rustc --version` prints.
+        let rustc_verbose = version(rustc(forwarded_env), "-vV");
+        let rustc_verbose = rustc_verbose.as_deref();
+        Toolchain {
+            cargo_version: version(cargo, "--version"),
+            rustc_version: rustc_verbose
+                .and_then(|v| v.lines().next())
+                .map(str::to_owned),
+            host: rustc_verbose
+                .and_then(|v| v.lines().find_map(|line| line.strip_prefix("host: ")))
+                .map(str::to_owned),
+        }
+    }
+
+    /// Whether the inner cargo accepts unstable `-Z` flags.
+    pub(crate) fn cargo_is_nightly(&self) -> bool {
+        self.cargo_version
+            .as_deref()
+            .is_some_and(|version| version.contains("-nightly") || version.contains("-dev"))
+    }
+
+    pub(crate) fn to_json(&self) -> Json {
+        Json::object(vec![
+            (
+                "cargo_version",
+                Json::optional_string(self.cargo_version.clone()),
+            ),
+            (
+                "rustc_version",
+                Json::optional_string(self.rustc_version.clone()),
+            ),
+            ("host", Json::optional_string(self.host.clone())),
+        ])
+    }
+}
+
+/// The rustc the inner cargo will use.
+pub(crate) fn rustc(forwarded_env: &[(String, ffi::OsString)]) -> &ffi::OsStr {
+    forwarded_env
+        .iter()
+        .find(|(key, _)| *key == "RUSTC")
+        .map_or(ffi::OsStr::new("rustc"), |(_, rustc)| rustc.as_os_str())
+}
+
+/// A summary of a build crate run.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct BuildReport {
+    /// The build crate's source dir, as given to the runner.
+    pub build_crate: path::PathBuf,
+    /// Where the build crate was staged, or `None` if it was built in place.
+    pub staging_dir: Option<path::PathBuf>,
+    pub profile: Profile,
+    /// Arguments passed to `cargo build`, after the profile, verbosity etc.
+    /// have been applied.
+    pub cargo_args: Vec<String>,
+    pub toolchain: Toolchain,
+    /// A hash of everything that went into the build; see
+    /// `BuildCrateRunner::cache_key`. Empty unless the run had a
+    /// `build_cache` or `cache_hints` to use it for.
+    pub cache_key: String,
+    /// How long staging, compiling and running took altogether.
+    pub duration: time::Duration,
+    /// Where the compiled build script was exported to; see
+    /// `BuildCrateRunner::export_executable`.
+    pub executables: Vec<path::PathBuf>,
+}
+
+impl BuildReport {
+    /// The report as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        let path = |p: &path::Path| Json::string(p.to_string_lossy());
+        Json::object(vec![
+            ("build_crate", path(&self.build_crate)),
+            (
+                "staging_dir",
+                self.staging_dir.as_deref().map_or(Json::Null, path),
+            ),
+            ("profile", Json::string(self.profile.dir_name())),
+            (
+                "cargo_args",
+                Json::Array(self.cargo_args.iter().map(Json::string).collect()),
+            ),
+            ("toolchain", self.toolchain.to_json()),
+            ("cache_key", Json::string(self.cache_key.clone())),
+            ("duration_secs", Json::Number(self.duration.as_secs_f64())),
+            (
+                "executables",
+                Json::Array(self.executables.iter().map(|p| path(p)).collect()),
+            ),
+        ])
+        .to_string()
+    }
+}
+
+/// What the build script printed, for an outer build script that does more
+/// with it than pass it on; see `BuildCrateRunner::run_with_output`.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct BuildScriptOutput {
+    /// The `cargo:` directives it emitted, as passed on to cargo: rebased,
+    /// and including any written to its directives file.
+    pub directives: Vec<String>,
+    /// Its stdout and stderr as printed, before any limits or rebasing. Run
+    /// with `RunStrategy::CargoRun`, stderr starts with what cargo printed
+    /// compiling it.
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: process::ExitStatus,
+    /// How long it ran for, not counting staging and compiling.
+    pub duration: time::Duration,
+}
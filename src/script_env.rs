@@ -0,0 +1,60 @@
+//! Environment variables describing the run, set for the build script so it
+//! can adapt without the outer build wiring each value through by hand.
+
+use std::{env, ffi, path};
+
+use crate::config;
+use crate::overrides::PROFILE_ENV;
+use crate::Profile;
+
+/// The directory the build crate was compiled in: the staging dir, or the
+/// build crate's own directory when it's built in place.
+pub const STAGING_DIR_ENV: &str = "CARGO_5730_STAGING_DIR";
+/// The parent crate's manifest dir, i.e. its `CARGO_MANIFEST_DIR`.
+pub const PARENT_MANIFEST_DIR_ENV: &str = "CARGO_5730_PARENT_MANIFEST_DIR";
+/// The parent crate's package name, i.e. its `CARGO_PKG_NAME`.
+pub const PARENT_PKG_NAME_ENV: &str = "CARGO_5730_PARENT_PKG_NAME";
+/// The parent crate's version, i.e. its `CARGO_PKG_VERSION`.
+pub const PARENT_PKG_VERSION_ENV: &str = "CARGO_5730_PARENT_PKG_VERSION";
+
+/// The parent manifest's `[package.metadata.<table>]`, as JSON. Only set
+/// when the table exists.
+pub const METADATA_ENV: &str = "CARGO_5730_METADATA";
+
+/// Variables describing the parent crate, and the `CARGO_5730_*` names
+/// they're passed on as. Under their own names they'd be mistaken for the
+/// build crate's, and they don't survive the trip to a remote host.
+const PARENT_VARS: &[(&str, &str)] = &[
+    ("CARGO_MANIFEST_DIR", PARENT_MANIFEST_DIR_ENV),
+    ("CARGO_PKG_NAME", PARENT_PKG_NAME_ENV),
+    ("CARGO_PKG_VERSION", PARENT_PKG_VERSION_ENV),
+];
+
+/// The variables to set for a build script compiled in `staging_dir` with
+/// `profile`. The profile goes in `CARGO_5730_PROFILE`, the same variable
+/// that overrides it, as `debug` or `release`.
+pub(crate) fn vars(
+    staging_dir: &path::Path,
+    profile: Profile,
+    metadata: Option<&str>,
+) -> Vec<(&'static str, ffi::OsString)> {
+    let mut vars = vec![
+        (STAGING_DIR_ENV, staging_dir.as_os_str().to_owned()),
+        (PROFILE_ENV, profile.dir_name().into()),
+    ];
+    for (parent_var, var) in PARENT_VARS {
+        if let Some(value) = env::var_os(parent_var) {
+            vars.push((*var, value));
+        }
+    }
+    if let Some(metadata) = metadata {
+        vars.push((METADATA_ENV, metadata.into()));
+    }
+    vars
+}
+
+/// The parent manifest's `[package.metadata.<table>]` serialized as JSON, if
+/// it has one.
+pub(crate) fn metadata(table: &str) -> Option<String> {
+    config::parent_metadata(table).map(|value| value.to_json().to_string())
+}
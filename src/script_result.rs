@@ -0,0 +1,50 @@
+//! Getting a value computed by the build script back into the outer build.
+
+use std::{fs, io, path};
+
+/// Set for the build script to the file it should write its result to, when
+/// run with `BuildCrateRunner::run_and_parse`.
+pub const RESULT_FILE_ENV: &str = "CARGO_5730_RESULT_FILE";
+
+/// The build script's result: the contents of `result_file` if it wrote one,
+/// otherwise the last line it printed that wasn't empty or a directive.
+pub(crate) fn read(result_file: &path::Path, last_line: Option<String>) -> String {
+    match fs::read_to_string(result_file) {
+        Ok(result) => result,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => last_line
+            .filter(|line| !line.is_empty())
+            .unwrap_or_else(|| {
+                panic!(
+                    "Build script neither wrote to {} nor printed a result",
+                    RESULT_FILE_ENV
+                )
+            }),
+        Err(e) => panic!(
+            "Couldn't read build script result from {}: {}",
+            result_file.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_read_prefers_result_file() {
+        let dir = env::temp_dir().join(format!("cargo-5730-result-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let result_file = dir.join("result");
+
+        assert_eq!(read(&result_file, Some("42".to_owned())), "42");
+        fs::write(&result_file, "{\"answer\": 42}").unwrap();
+        assert_eq!(
+            read(&result_file, Some("42".to_owned())),
+            "{\"answer\": 42}"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
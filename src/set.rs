@@ -0,0 +1,388 @@
+//! Running every build crate found under a directory.
+
+use std::collections::HashMap;
+use std::{env, fs, panic, path};
+
+use crate::diagnostics::panic_message;
+use crate::directives::Merged;
+use crate::json::Json;
+use crate::{BuildCrateRunner, BuildReport, ErrorPolicy, Outcome};
+
+/// Where `run_default_build_crates` looks for build crates, relative to the
+/// parent crate's manifest dir.
+pub const DEFAULT_BUILD_CRATES_DIR: &str = "build-scripts";
+
+/// Set for a build crate that others hand off to, as a JSON object mapping
+/// each of those build crates to `{"result": ..., "out_dir": ...}`.
+pub const INPUTS_ENV: &str = "CARGO_5730_INPUTS";
+
+/// What a build crate that hands off to others produced.
+struct Produced {
+    result: String,
+    /// The `OUT_DIR` it ran with.
+    out_dir: Option<path::PathBuf>,
+}
+
+type Configure = dyn Fn(BuildCrateRunner) -> BuildCrateRunner;
+
+/// A group of build crates that are staged, compiled and run one after the
+/// other.
+///
+/// Every subdirectory of the discovery dir containing a `Cargo.toml` is a
+/// build crate, so adding a new generator is just a matter of adding a folder.
+pub struct BuildCrateSet {
+    root: path::PathBuf,
+    crates: Vec<String>,
+    order: Vec<String>,
+    configure: Option<Box<Configure>>,
+    error_policy: ErrorPolicy,
+    merge_directives: bool,
+    /// Producer and consumer build crates.
+    handoffs: Vec<(String, String)>,
+}
+
+impl BuildCrateSet {
+    /// Find the build crates directly below `root`.
+    pub fn discover<P: AsRef<path::Path>>(root: P) -> Self {
+        let root = root.as_ref();
+        let entries = fs::read_dir(root)
+            .unwrap_or_else(|e| panic!("Couldn't read build crates dir {}: {}", root.display(), e));
+
+        let mut crates = Vec::new();
+        for entry in entries {
+            let entry = entry.unwrap_or_else(|e| {
+                panic!("Couldn't read build crates dir {}: {}", root.display(), e)
+            });
+            if !entry.path().join("Cargo.toml").is_file() {
+                continue;
+            }
+            let name = entry.file_name().into_string().unwrap_or_else(|name| {
+                panic!("Build crate dir name isn't valid UTF-8: {:?}", name)
+            });
+            crates.push(name);
+        }
+        crates.sort();
+
+        BuildCrateSet {
+            root: root.to_owned(),
+            crates,
+            order: Vec::new(),
+            configure: None,
+            error_policy: ErrorPolicy::default(),
+            merge_directives: true,
+            handoffs: Vec::new(),
+        }
+    }
+
+    /// The names of the discovered build crates, in the order they will run.
+    pub fn crates(&self) -> Vec<&str> {
+        let mut ordered: Vec<&str> = self.order.iter().map(|s| s.as_str()).collect();
+        for name in &self.crates {
+            if !self.order.contains(name) {
+                ordered.push(name);
+            }
+        }
+        ordered
+    }
+
+    /// Run the named build crates first, in the given order. Any crates not
+    /// named here run afterwards, in lexical order.
+    pub fn order<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.order = names.into_iter().map(Into::into).collect();
+        for name in &self.order {
+            assert!(
+                self.crates.contains(name),
+                "Build crate {} given in the run order doesn't exist in {}",
+                name,
+                self.root.display()
+            );
+        }
+        self
+    }
+
+    /// Adjust the runner used for each build crate, e.g. to change its
+    /// staging strategy.
+    pub fn configure<F>(mut self, configure: F) -> Self
+    where
+        F: Fn(BuildCrateRunner) -> BuildCrateRunner + 'static,
+    {
+        self.configure = Some(Box::new(configure));
+        self
+    }
+
+    /// Keep running the remaining build crates after one fails, and report
+    /// every failure together at the end. Off by default. Shorthand for
+    /// `error_policy(ErrorPolicy::CollectAll)`.
+    pub fn keep_going(self, keep_going: bool) -> Self {
+        self.error_policy(if keep_going {
+            ErrorPolicy::CollectAll
+        } else {
+            ErrorPolicy::FailFast
+        })
+    }
+
+    /// Whether the first failing build crate stops the whole set, or all of
+    /// them are attempted and the failures reported together. Also applied
+    /// to each build crate's runner.
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Hold back each build crate's directives until they've all run, then
+    /// emit them once: repeated `rustc-link-search`, `rustc-link-lib` and
+    /// other identical directives are only emitted once, and build crates
+    /// setting the same `rustc-env` variable to different values are an
+    /// error. On by default.
+    pub fn merge_directives(mut self, merge: bool) -> Self {
+        self.merge_directives = merge;
+        self
+    }
+
+    /// Pass the result of build crate `from` on to build crate `to`, which
+    /// must run after it, so one can generate the other's input. `from` is
+    /// run as with `BuildCrateRunner::run_and_parse`, and `to` finds its
+    /// result, along with the `OUT_DIR` it wrote to, in
+    /// `CARGO_5730_INPUTS`:
+    ///
+    /// ```json
+    /// {"schema": {"result": "...", "out_dir": "/path/to/out"}}
+    /// ```
+    pub fn hand_off<S: Into<String>>(mut self, from: S, to: S) -> Self {
+        let (from, to) = (from.into(), to.into());
+        for name in &[&from, &to] {
+            assert!(
+                self.crates.contains(name),
+                "Build crate {} given in a handoff doesn't exist in {}",
+                name,
+                self.root.display()
+            );
+        }
+        self.handoffs.push((from, to));
+        self
+    }
+
+    fn run_one(
+        &self,
+        name: &str,
+        merged: &mut Merged,
+        results: &mut HashMap<String, Produced>,
+    ) -> BuildReport {
+        let mut runner = BuildCrateRunner::new(self.root.join(name))
+            .error_policy(self.error_policy)
+            .collect_directives(self.merge_directives);
+        if let Some(configure) = &self.configure {
+            runner = configure(runner);
+        }
+
+        let inputs: Vec<(&str, Json)> = self
+            .handoffs
+            .iter()
+            .filter(|(_, to)| to == name)
+            .map(|(from, _)| {
+                let result = results.get(from).unwrap_or_else(|| {
+                    panic!(
+                        "Build crate {} needs the result of {}, which failed",
+                        name, from
+                    )
+                });
+                let out_dir = result.out_dir.as_ref().map(|dir| dir.display().to_string());
+                let input = Json::object(vec![
+                    ("result", Json::string(result.result.clone())),
+                    ("out_dir", Json::optional_string(out_dir)),
+                ]);
+                (from.as_str(), input)
+            })
+            .collect();
+        if !inputs.is_empty() {
+            runner = runner.script_env(INPUTS_ENV, Json::object(inputs).to_string());
+        }
+
+        let produces = self.handoffs.iter().any(|(from, _)| from == name);
+        let (
+            report,
+            Outcome {
+                result,
+                directives,
+                out_dir,
+                ..
+            },
+        ) = runner.execute(produces, false);
+        for directive in directives {
+            merged.add(name, &directive);
+        }
+        if let Some(result) = result {
+            results.insert(name.to_owned(), Produced { result, out_dir });
+        }
+        report
+    }
+
+    /// Run each build crate in turn, returning their reports in run order.
+    pub fn run(&self) -> Vec<BuildReport> {
+        // Pick up newly added build crates.
+        println!("cargo:rerun-if-changed={}", self.root.display());
+
+        let crates = self.crates();
+        for (from, to) in &self.handoffs {
+            let position = |name: &String| crates.iter().position(|c| c == name);
+            assert!(
+                position(from) < position(to),
+                "Build crate {} hands off to {}, so it must run first",
+                from,
+                to
+            );
+        }
+
+        let mut merged = Merged::default();
+        let mut results = HashMap::new();
+        let mut reports = Vec::new();
+        let mut failures = Vec::new();
+        for name in crates {
+            if self.error_policy == ErrorPolicy::FailFast {
+                reports.push(self.run_one(name, &mut merged, &mut results));
+                continue;
+            }
+
+            match panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                self.run_one(name, &mut merged, &mut results)
+            })) {
+                Ok(report) => reports.push(report),
+                Err(payload) => {
+                    println!("cargo:warning=Build crate {} failed", name);
+                    failures.push(format!("{}: {}", name, panic_message(&*payload)));
+                }
+            }
+        }
+
+        assert!(
+            failures.is_empty(),
+            "{} of {} build crates failed:\n  {}",
+            failures.len(),
+            self.crates.len(),
+            failures.join("\n  ")
+        );
+
+        let directives = merged.finish().unwrap_or_else(|conflicts| {
+            panic!(
+                "Build crates set conflicting rustc-env values:\n  {}",
+                conflicts.join("\n  ")
+            )
+        });
+        for directive in directives {
+            println!("{}", directive);
+        }
+        reports
+    }
+}
+
+/// Run every build crate in the conventional `build-scripts/` directory next
+/// to the parent crate's `Cargo.toml`.
+pub fn run_default_build_crates() {
+    let manifest_dir =
+        env::var_os("CARGO_MANIFEST_DIR").expect("Can't get CARGO_MANIFEST_DIR from env");
+    default_build_crates(path::Path::new(&manifest_dir)).run();
+}
+
+/// The build crates in `build-scripts/` under `manifest_dir`, of which there
+/// must be at least one.
+fn default_build_crates(manifest_dir: &path::Path) -> BuildCrateSet {
+    let root = manifest_dir.join(DEFAULT_BUILD_CRATES_DIR);
+    assert!(
+        root.is_dir(),
+        "No build crates found: expected a directory at {}",
+        root.display()
+    );
+    let set = BuildCrateSet::discover(&root);
+    assert!(
+        !set.crates.is_empty(),
+        "No build crates found: expected a directory with a Cargo.toml in {}",
+        root.display()
+    );
+    set
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_discovery_order() {
+        let root = env::temp_dir().join(format!("cargo-5730-set-test-{}", std::process::id()));
+        for name in &["c", "a", "b"] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            fs::write(root.join(name).join("Cargo.toml"), "[package]\n").unwrap();
+        }
+        fs::create_dir_all(root.join("not-a-crate")).unwrap();
+
+        let set = BuildCrateSet::discover(&root);
+        assert_eq!(set.crates(), vec!["a", "b", "c"]);
+
+        let set = set.order(vec!["c"]);
+        assert_eq!(set.crates(), vec!["c", "a", "b"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Build crate b hands off to a, so it must run first")]
+    fn test_hand_off_order() {
+        let root = env::temp_dir().join(format!("cargo-5730-handoff-test-{}", std::process::id()));
+        for name in &["a", "b"] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            fs::write(root.join(name).join("Cargo.toml"), "[package]\n").unwrap();
+        }
+
+        let set = BuildCrateSet::discover(&root).hand_off("b", "a");
+        fs::remove_dir_all(&root).unwrap();
+        set.run();
+    }
+
+    #[test]
+    fn test_keep_going_reports_every_failure() {
+        let root =
+            env::temp_dir().join(format!("cargo-5730-keep-going-test-{}", std::process::id()));
+        for name in &["first", "second"] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            fs::write(root.join(name).join("Cargo.toml"), "not a manifest\n").unwrap();
+        }
+
+        let set = BuildCrateSet::discover(&root).keep_going(true);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| set.run()));
+        fs::remove_dir_all(&root).unwrap();
+
+        let payload = result.unwrap_err();
+        let message = panic_message(&*payload);
+        assert!(
+            message.starts_with("2 of 2 build crates failed"),
+            "{}",
+            message
+        );
+        assert!(message.contains("first: "), "{}", message);
+        assert!(message.contains("second: "), "{}", message);
+    }
+
+    #[test]
+    fn test_default_build_crates() {
+        let root = env::temp_dir().join(format!(
+            "cargo-5730-default-set-test-{}",
+            std::process::id()
+        ));
+        let missing = panic::catch_unwind(|| default_build_crates(&root).crates.len()).unwrap_err();
+        assert!(panic_message(&*missing).starts_with("No build crates found: expected a directory"));
+
+        fs::create_dir_all(root.join(DEFAULT_BUILD_CRATES_DIR).join("not-a-crate")).unwrap();
+        let empty = panic::catch_unwind(|| default_build_crates(&root).crates.len()).unwrap_err();
+        assert!(panic_message(&*empty).contains("with a Cargo.toml"));
+
+        let codegen = root.join(DEFAULT_BUILD_CRATES_DIR).join("codegen");
+        fs::create_dir_all(&codegen).unwrap();
+        fs::write(codegen.join("Cargo.toml"), "[package]\n").unwrap();
+        assert_eq!(default_build_crates(&root).crates(), vec!["codegen"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
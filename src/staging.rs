@@ -0,0 +1,587 @@
+//! Getting a copy of the build crate's source into the staging dir.
+
+use std::collections::HashSet;
+use std::{ffi, fs, io, path};
+
+use crate::glob;
+
+/// How the build crate's source is placed in the staging dir.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StagingStrategy {
+    /// Recursively copy the build crate.
+    #[default]
+    Copy,
+    /// Recreate the directory structure, but symlink every file back to the
+    /// original source. Only the files we rewrite or cargo writes to
+    /// (`Cargo.toml`, `Cargo.lock`) are materialized as real copies.
+    ///
+    /// This is much cheaper than `Copy` for build crates carrying a lot of
+    /// data, while still keeping cargo's view of the crate isolated.
+    SymlinkOverlay,
+    /// Like `SymlinkOverlay`, but with hardlinks, which tools that resolve
+    /// symlinks can't see through. Where the staging dir is on another
+    /// filesystem, files are copied instead, as clones (reflinks) where the
+    /// filesystem supports them, e.g. on btrfs, XFS or APFS.
+    ///
+    /// A hardlinked file is the original: anything the build writes to one
+    /// in place changes the build crate's source too.
+    Link,
+}
+
+/// What staging does with the symlinks in the build crate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Stage what they point to, as if it were in the build crate. Links to
+    /// files and dirs outside of it keep working in the staging dir.
+    #[default]
+    Follow,
+    /// Recreate the links themselves, pointing where they did. Relative links
+    /// to anything outside of the build crate break.
+    Preserve,
+}
+
+/// What's left out of the staging dir unless told otherwise: version control
+/// metadata, which can be far bigger than the crate itself.
+pub const DEFAULT_STAGING_EXCLUDES: &[&str] = &[".git", ".hg", ".svn", ".jj"];
+
+/// Files that must never be symlinked back into the source tree, because we
+/// modify them in the staging dir.
+const MATERIALIZED_FILES: &[&str] = &["Cargo.toml", "Cargo.lock"];
+
+/// How a tree is staged: how each file is put in place, and which paths,
+/// relative to the root and `/`-separated, are left out. The root `target`
+/// dir always is, since the staged crate builds into a `target` of its own,
+/// and linking an existing one in would let the inner build scribble over
+/// the original.
+pub(crate) struct Walk<'a> {
+    link: LinkFn,
+    symlinks: SymlinkPolicy,
+    /// Globs of the files to stage, or all of them when empty. `Cargo.toml`
+    /// always is.
+    includes: &'a [String],
+    /// Globs of the files and dirs to leave out.
+    excludes: &'a [String],
+}
+
+impl<'a> Walk<'a> {
+    pub(crate) fn new(
+        strategy: StagingStrategy,
+        symlinks: SymlinkPolicy,
+        includes: &'a [String],
+        excludes: &'a [String],
+    ) -> Self {
+        let link = match strategy {
+            StagingStrategy::Copy => copy_file,
+            StagingStrategy::SymlinkOverlay => symlink_file,
+            StagingStrategy::Link => link_file,
+        };
+        Walk {
+            link,
+            symlinks,
+            includes,
+            excludes,
+        }
+    }
+
+    fn excluded(&self, relative: &str) -> bool {
+        relative == "target"
+            || self
+                .excludes
+                .iter()
+                .any(|exclude| glob::matches(exclude, relative))
+    }
+
+    /// How to stage `entry`, following it if it's a symlink to follow.
+    fn kind(&self, entry: &fs::DirEntry) -> io::Result<Kind> {
+        let file_type = entry.file_type()?;
+        if !file_type.is_symlink() {
+            return Ok(match file_type.is_dir() {
+                true => Kind::Dir,
+                false => Kind::File,
+            });
+        }
+        if self.symlinks == SymlinkPolicy::Preserve {
+            return Ok(Kind::Symlink);
+        }
+
+        let path = entry.path();
+        let target = fs::metadata(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{} is a broken symlink: {}", path.display(), e),
+            )
+        })?;
+        if !target.is_dir() {
+            return Ok(Kind::File);
+        }
+        // Following a link to a dir it's in would never end.
+        let resolved = path.canonicalize()?;
+        let parent = path.parent().map(path::Path::canonicalize).transpose()?;
+        if parent.is_some_and(|parent| parent.starts_with(&resolved)) {
+            return Err(io::Error::other(format!(
+                "{} links to {}, which contains it",
+                path.display(),
+                resolved.display()
+            )));
+        }
+        Ok(Kind::Dir)
+    }
+
+    fn included(&self, relative: &str) -> bool {
+        self.includes.is_empty()
+            || relative == "Cargo.toml"
+            || self
+                .includes
+                .iter()
+                .any(|include| glob::matches(include, relative))
+    }
+}
+
+/// What an entry in the build crate is staged as.
+#[derive(PartialEq, Eq)]
+enum Kind {
+    Dir,
+    File,
+    /// A symlink, recreated as it is.
+    Symlink,
+}
+
+/// Puts a file from the build crate in the staging dir.
+type LinkFn = fn(&path::Path, &path::Path) -> io::Result<()>;
+
+/// Stage `in_dir` in the empty `out_dir`.
+pub(crate) fn stage(in_dir: &path::Path, out_dir: &path::Path, walk: &Walk) {
+    let in_dir = in_dir
+        .canonicalize()
+        .unwrap_or_else(|_| panic!("Couldn't canonicalize build crate dir {}", in_dir.display()));
+    overlay(&in_dir, out_dir, "", walk).unwrap_or_else(|e| {
+        panic!(
+            "Failed to stage {} into {}: {}",
+            in_dir.display(),
+            out_dir.display(),
+            e
+        )
+    });
+}
+
+/// Bring `out_dir`, staged from `in_dir` by an earlier build, up to date with
+/// it: files whose size or modification time changed are staged again, and
+/// those no longer in `in_dir`, or now left out, are removed. The `preserved`
+/// entries at the root are left alone, as is the staged crate's target dir.
+pub(crate) fn sync(in_dir: &path::Path, out_dir: &path::Path, walk: &Walk, preserved: &[&str]) {
+    let in_dir = in_dir
+        .canonicalize()
+        .unwrap_or_else(|_| panic!("Couldn't canonicalize build crate dir {}", in_dir.display()));
+    sync_dir(&in_dir, out_dir, "", walk, preserved).unwrap_or_else(|e| {
+        panic!(
+            "Failed to update {} from {}: {}",
+            out_dir.display(),
+            in_dir.display(),
+            e
+        )
+    });
+}
+
+/// The path of `name` in the dir at `relative`.
+fn child(relative: &str, name: &ffi::OsStr) -> String {
+    match relative {
+        "" => name.to_string_lossy().into_owned(),
+        _ => format!("{}/{}", relative, name.to_string_lossy()),
+    }
+}
+
+fn overlay(
+    in_dir: &path::Path,
+    out_dir: &path::Path,
+    relative: &str,
+    walk: &Walk,
+) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+
+    for entry in fs::read_dir(in_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let entry_relative = child(relative, &name);
+        if walk.excluded(&entry_relative) {
+            continue;
+        }
+        let src = entry.path();
+        let dst = out_dir.join(&name);
+
+        let kind = walk.kind(&entry)?;
+        if kind == Kind::Dir {
+            overlay(&src, &dst, &entry_relative, walk)?;
+        } else if !walk.included(&entry_relative) {
+            continue;
+        } else if kind == Kind::Symlink {
+            copy_symlink(&src, &dst)?;
+        } else if relative.is_empty() && MATERIALIZED_FILES.iter().any(|f| name == *f) {
+            copy_file(&src, &dst)?;
+        } else {
+            (walk.link)(&src, &dst)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn sync_dir(
+    in_dir: &path::Path,
+    out_dir: &path::Path,
+    relative: &str,
+    walk: &Walk,
+    preserved: &[&str],
+) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    let is_preserved = |name: &ffi::OsStr| {
+        relative.is_empty() && (name == "target" || preserved.iter().any(|p| name == *p))
+    };
+
+    let mut staged = HashSet::new();
+    for entry in fs::read_dir(in_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let entry_relative = child(relative, &name);
+        if is_preserved(&name) || walk.excluded(&entry_relative) {
+            continue;
+        }
+        let src = entry.path();
+        let dst = out_dir.join(&name);
+        let kind = walk.kind(&entry)?;
+        if kind != Kind::Dir && !walk.included(&entry_relative) {
+            continue;
+        }
+
+        if kind == Kind::Dir {
+            if fs::symlink_metadata(&dst).is_ok_and(|metadata| !metadata.is_dir()) {
+                fs::remove_file(&dst)?;
+            }
+            sync_dir(&src, &dst, &entry_relative, walk, preserved)?;
+        } else if kind == Kind::Symlink {
+            let current = fs::read_link(&dst).ok() == Some(fs::read_link(&src)?);
+            if !current {
+                remove(&dst)?;
+                copy_symlink(&src, &dst)?;
+            }
+        } else if !up_to_date(&src, &dst) {
+            remove(&dst)?;
+            if relative.is_empty() && MATERIALIZED_FILES.iter().any(|f| name == *f) {
+                copy_file(&src, &dst)?;
+            } else {
+                (walk.link)(&src, &dst)?;
+            }
+        }
+        staged.insert(name);
+    }
+
+    for entry in fs::read_dir(out_dir)? {
+        let name = entry?.file_name();
+        if !staged.contains(&name) && !is_preserved(&name) {
+            remove(&out_dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `dst` is still the file staged from `src`. Links share the
+/// original's metadata, and copies are given its modification time.
+fn up_to_date(src: &path::Path, dst: &path::Path) -> bool {
+    match (fs::metadata(src), fs::metadata(dst)) {
+        (Ok(src), Ok(dst)) => {
+            dst.is_file()
+                && src.len() == dst.len()
+                && src
+                    .modified()
+                    .ok()
+                    .is_some_and(|modified| dst.modified().ok() == Some(modified))
+        }
+        _ => false,
+    }
+}
+
+/// Remove whatever is at `path`, if anything.
+fn remove(path: &path::Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copy `src` to `dst`, with its modification time, so `sync` can tell the
+/// copy is current, and its permissions, executable bits included. The copy
+/// is writable even if the original isn't, as in a read-only checkout, since
+/// the manifest gets rewritten, cargo may update the lockfile, and the
+/// staging dir has to be removed again.
+fn copy_file(src: &path::Path, dst: &path::Path) -> io::Result<()> {
+    fs::copy(src, dst)?;
+    let metadata = fs::metadata(src)?;
+    fs::set_permissions(dst, writable(metadata.permissions()))?;
+    fs::File::options()
+        .write(true)
+        .open(dst)?
+        .set_modified(metadata.modified()?)
+}
+
+#[cfg(unix)]
+fn writable(mut permissions: fs::Permissions) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+
+    permissions.set_mode(permissions.mode() | 0o200);
+    permissions
+}
+
+#[cfg(not(unix))]
+fn writable(mut permissions: fs::Permissions) -> fs::Permissions {
+    // Only the read-only attribute, which is what this clears here.
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    permissions
+}
+
+/// Make `dst` a symlink to wherever the symlink `src` points.
+#[cfg(unix)]
+fn copy_symlink(src: &path::Path, dst: &path::Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+}
+
+#[cfg(windows)]
+fn copy_symlink(src: &path::Path, dst: &path::Path) -> io::Result<()> {
+    let target = fs::read_link(src)?;
+    // Windows has a different kind of link for dirs.
+    match fs::metadata(src).is_ok_and(|metadata| metadata.is_dir()) {
+        true => std::os::windows::fs::symlink_dir(target, dst),
+        false => std::os::windows::fs::symlink_file(target, dst),
+    }
+}
+
+/// Hardlink `dst` to `src`, or copy it where that's not possible. `fs::copy`
+/// clones the file where the filesystem can.
+fn link_file(src: &path::Path, dst: &path::Path) -> io::Result<()> {
+    fs::hard_link(src, dst).or_else(|_| copy_file(src, dst))
+}
+
+#[cfg(unix)]
+fn symlink_file(src: &path::Path, dst: &path::Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+
+#[cfg(windows)]
+fn symlink_file(src: &path::Path, dst: &path::Path) -> io::Result<()> {
+    // Creating symlinks needs developer mode or elevated privileges on
+    // Windows; fall back to a plain copy rather than failing the build.
+    std::os::windows::fs::symlink_file(src, dst).or_else(|_| copy_file(src, dst))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_overlay_materializes_manifest() {
+        let root = env::temp_dir().join(format!("cargo-5730-overlay-test-{}", std::process::id()));
+        let src = root.join("src-crate");
+        let dst = root.join("staged");
+        fs::create_dir_all(src.join("src")).unwrap();
+        fs::create_dir_all(src.join("target").join("debug")).unwrap();
+        fs::create_dir_all(src.join(".git")).unwrap();
+        fs::write(src.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(src.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+
+        let excludes: Vec<_> = DEFAULT_STAGING_EXCLUDES
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        let walk = Walk::new(
+            StagingStrategy::SymlinkOverlay,
+            SymlinkPolicy::Follow,
+            &[],
+            &excludes,
+        );
+        stage(&src, &dst, &walk);
+
+        let manifest = fs::symlink_metadata(dst.join("Cargo.toml")).unwrap();
+        assert!(!manifest.file_type().is_symlink());
+        let main_rs = fs::symlink_metadata(dst.join("src").join("main.rs")).unwrap();
+        assert!(main_rs.file_type().is_symlink());
+        assert!(!dst.join("target").exists());
+        assert!(!dst.join(".git").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_sync() {
+        let root = env::temp_dir().join(format!("cargo-5730-sync-test-{}", std::process::id()));
+        let src = root.join("src-crate");
+        let dst = root.join("staged");
+        fs::create_dir_all(src.join("src").join("bin")).unwrap();
+        fs::write(src.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(src.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(src.join("src").join("bin").join("old.rs"), "fn main() {}\n").unwrap();
+        fs::write(src.join("src").join("lib.rs"), "").unwrap();
+        fs::write(src.join("src").join("big.bin"), "").unwrap();
+
+        let mut excludes = Vec::new();
+        let walk = Walk::new(StagingStrategy::Copy, SymlinkPolicy::Follow, &[], &excludes);
+        sync(&src, &dst, &walk, &["marker"]);
+        fs::create_dir_all(dst.join("target").join("debug")).unwrap();
+        fs::write(dst.join("marker"), "").unwrap();
+        fs::write(dst.join("src").join("main.rs"), "fn main() { 1; }\n").unwrap();
+
+        fs::remove_dir_all(src.join("src").join("bin")).unwrap();
+        fs::write(src.join("src").join("lib.rs"), "pub fn f() {}\n").unwrap();
+        excludes.push("**/*.bin".to_owned());
+        let walk = Walk::new(StagingStrategy::Copy, SymlinkPolicy::Follow, &[], &excludes);
+        sync(&src, &dst, &walk, &["marker"]);
+
+        assert_eq!(
+            fs::read_to_string(dst.join("src").join("lib.rs")).unwrap(),
+            "pub fn f() {}\n"
+        );
+        // Rewritten behind our back, so it no longer looks current.
+        assert_eq!(
+            fs::read_to_string(dst.join("src").join("main.rs")).unwrap(),
+            "fn main() {}\n"
+        );
+        assert!(!dst.join("src").join("bin").exists());
+        assert!(!dst.join("src").join("big.bin").exists());
+        assert!(dst.join("target").join("debug").is_dir());
+        assert!(dst.join("marker").is_file());
+        assert!(up_to_date(&src.join("Cargo.toml"), &dst.join("Cargo.toml")));
+
+        let includes = ["src/**".to_owned()];
+        fs::write(src.join("README.md"), "").unwrap();
+        sync(
+            &src,
+            &dst,
+            &Walk::new(
+                StagingStrategy::Copy,
+                SymlinkPolicy::Follow,
+                &includes,
+                &excludes,
+            ),
+            &[],
+        );
+        assert!(dst.join("Cargo.toml").is_file());
+        assert!(dst.join("src").join("lib.rs").is_file());
+        assert!(!dst.join("README.md").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policy() {
+        use std::os::unix::fs::symlink;
+
+        let root = env::temp_dir().join(format!("cargo-5730-symlink-test-{}", std::process::id()));
+        let src = root.join("src-crate");
+        fs::create_dir_all(root.join("shared")).unwrap();
+        fs::create_dir_all(&src).unwrap();
+        fs::write(root.join("shared").join("data.txt"), "data").unwrap();
+        fs::write(src.join("Cargo.toml"), "[package]\n").unwrap();
+        symlink("../shared", src.join("assets")).unwrap();
+        symlink("Cargo.toml", src.join("Manifest.toml")).unwrap();
+
+        let followed = root.join("followed");
+        stage(
+            &src,
+            &followed,
+            &Walk::new(StagingStrategy::Copy, SymlinkPolicy::Follow, &[], &[]),
+        );
+        assert!(fs::symlink_metadata(followed.join("assets"))
+            .unwrap()
+            .is_dir());
+        assert_eq!(
+            fs::read_to_string(followed.join("assets").join("data.txt")).unwrap(),
+            "data"
+        );
+        assert!(fs::symlink_metadata(followed.join("Manifest.toml"))
+            .unwrap()
+            .is_file());
+
+        let preserved = root.join("preserved");
+        let walk = Walk::new(StagingStrategy::Copy, SymlinkPolicy::Preserve, &[], &[]);
+        stage(&src, &preserved, &walk);
+        sync(&src, &preserved, &walk, &[]);
+        assert_eq!(
+            fs::read_link(preserved.join("assets")).unwrap(),
+            path::Path::new("../shared")
+        );
+        assert_eq!(
+            fs::read_link(preserved.join("Manifest.toml")).unwrap(),
+            path::Path::new("Cargo.toml")
+        );
+
+        symlink("..", src.join("parent")).unwrap();
+        let walk = Walk::new(StagingStrategy::Copy, SymlinkPolicy::Follow, &[], &[]);
+        let cycle = overlay(&src, &root.join("cycle"), "", &walk);
+        assert!(cycle.unwrap_err().to_string().contains("which contains it"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = env::temp_dir().join(format!("cargo-5730-perms-test-{}", std::process::id()));
+        let src = root.join("src-crate");
+        let dst = root.join("staged");
+        fs::create_dir_all(src.join("tools")).unwrap();
+        fs::write(src.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(src.join("tools").join("gen.sh"), "#!/bin/sh\n").unwrap();
+        for file in [src.join("Cargo.toml"), src.join("tools").join("gen.sh")] {
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o555)).unwrap();
+        }
+
+        stage(
+            &src,
+            &dst,
+            &Walk::new(StagingStrategy::Copy, SymlinkPolicy::Follow, &[], &[]),
+        );
+
+        let mode = |file: path::PathBuf| fs::metadata(file).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(dst.join("tools").join("gen.sh")), 0o755);
+        assert_eq!(mode(dst.join("Cargo.toml")), 0o755);
+        fs::write(dst.join("Cargo.toml"), "[package]\nname = \"staged\"\n").unwrap();
+
+        for file in [src.join("Cargo.toml"), src.join("tools").join("gen.sh")] {
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_link_materializes_manifest() {
+        let root = env::temp_dir().join(format!("cargo-5730-link-test-{}", std::process::id()));
+        let src = root.join("src-crate");
+        let dst = root.join("staged");
+        fs::create_dir_all(src.join("src")).unwrap();
+        fs::write(src.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(src.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+
+        stage(
+            &src,
+            &dst,
+            &Walk::new(StagingStrategy::Link, SymlinkPolicy::Follow, &[], &[]),
+        );
+
+        fs::write(dst.join("Cargo.toml"), "[package]\nname = \"staged\"\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(src.join("Cargo.toml")).unwrap(),
+            "[package]\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dst.join("src").join("main.rs")).unwrap(),
+            "fn main() {}\n"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+//! Throwaway parent crates for end-to-end tests of build crates, run outside
+//! of a real cargo build.
+//!
+//! ```no_run
+//! use cargo_5730::test_support::Fixture;
+//!
+//! let fixture = Fixture::new("codegen").build_crate(
+//!     "build-script",
+//!     r#"fn main() { println!("cargo:rustc-cfg=generated"); }"#,
+//! );
+//! let run = fixture.run(|runner| runner);
+//! run.assert_directive("cargo:rustc-cfg=generated");
+//! ```
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, path, process};
+
+use crate::{BuildCrateRunner, BuildReport, BuildScriptOutput, Outcome, DEFAULT_BUILD_CRATE_DIR};
+
+/// Tells fixtures created by the same process apart.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A parent crate in a temporary directory, removed when dropped.
+#[derive(Debug)]
+pub struct Fixture {
+    dir: path::PathBuf,
+}
+
+impl Fixture {
+    /// An empty parent crate called `name`, with an `OUT_DIR` of its own.
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!(
+            "cargo-5730-fixture-{}-{}-{}",
+            name,
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).expect("Couldn't remove old fixture");
+        }
+        let fixture = Fixture { dir };
+        fs::create_dir_all(fixture.out_dir()).expect("Couldn't create fixture");
+        fixture
+            .file(
+                "Cargo.toml",
+                &format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2018\"\n",
+                    name
+                ),
+            )
+            .file("src/lib.rs", "")
+    }
+
+    /// Write `contents` to `path`, relative to the parent crate.
+    pub fn file<P: AsRef<path::Path>>(self, path: P, contents: &str) -> Self {
+        let path = self.dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("Couldn't create fixture dir");
+        }
+        fs::write(&path, contents)
+            .unwrap_or_else(|e| panic!("Couldn't write {}: {}", path.display(), e));
+        self
+    }
+
+    /// Add a dependency-free build crate in `dir` whose `src/main.rs` is
+    /// `main_rs`.
+    pub fn build_crate(self, dir: &str, main_rs: &str) -> Self {
+        let name = path::Path::new(dir)
+            .file_name()
+            .expect("A build crate dir needs a name")
+            .to_string_lossy()
+            .into_owned();
+        self.file(
+            path::Path::new(dir).join("Cargo.toml"),
+            &format!(
+                "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2018\"\n",
+                name
+            ),
+        )
+        .file(path::Path::new(dir).join("src/main.rs"), main_rs)
+    }
+
+    /// The parent crate's directory.
+    pub fn path(&self) -> &path::Path {
+        &self.dir
+    }
+
+    /// The `OUT_DIR` runs see.
+    pub fn out_dir(&self) -> path::PathBuf {
+        self.dir.join("out")
+    }
+
+    /// Run the build crate in `build-script/` the way the parent's build.rs
+    /// would, with the runner adjusted by `configure`. Staging dirs are kept
+    /// inside the fixture, so they can be inspected, and the build script's
+    /// directives are collected rather than printed, its output along with
+    /// them. The build script is given the fixture's `OUT_DIR`, and otherwise
+    /// sees the test's own environment, whose `CARGO`, as `cargo test` sets
+    /// it, is the inner cargo.
+    pub fn run<F>(&self, configure: F) -> FixtureRun
+    where
+        F: FnOnce(BuildCrateRunner) -> BuildCrateRunner,
+    {
+        let runner = BuildCrateRunner::new(self.dir.join(DEFAULT_BUILD_CRATE_DIR))
+            .staging_root(self.dir.join("staging"))
+            .keep(true)
+            .out_dir(self.out_dir());
+        let (
+            report,
+            Outcome {
+                directives, output, ..
+            },
+        ) = configure(runner)
+            .collect_directives(true)
+            .execute(false, true);
+        FixtureRun {
+            report,
+            directives,
+            output,
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// What a fixture's run produced.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct FixtureRun {
+    pub report: BuildReport,
+    /// The directives the build script emitted.
+    pub directives: Vec<String>,
+    /// What the build script printed, unless it wasn't run.
+    pub output: Option<BuildScriptOutput>,
+}
+
+impl FixtureRun {
+    /// The contents of `path` in the staging dir, e.g. the rewritten
+    /// `Cargo.toml`.
+    pub fn staged_file<P: AsRef<path::Path>>(&self, path: P) -> String {
+        let staging_dir = self
+            .report
+            .staging_dir
+            .as_ref()
+            .expect("The build crate was built in place, not staged");
+        let path = staging_dir.join(path);
+        fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Couldn't read {}: {}", path.display(), e))
+    }
+
+    /// The staged, rewritten manifest.
+    pub fn staged_manifest(&self) -> String {
+        self.staged_file("Cargo.toml")
+    }
+
+    /// Fail unless the build script emitted `directive`.
+    pub fn assert_directive(&self, directive: &str) {
+        assert!(
+            self.directives.iter().any(|d| d == directive),
+            "Expected directive {:?}, got {:#?}",
+            directive,
+            self.directives
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fixture_run() {
+        let fixture = Fixture::new("fixture-parent").build_crate(
+            DEFAULT_BUILD_CRATE_DIR,
+            r#"
+use std::{env, fs, path::Path};
+
+fn main() {
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("generated.txt"), "generated").unwrap();
+    println!("cargo:rustc-cfg=from_fixture");
+}
+"#,
+        );
+
+        let run = fixture.run(|runner| runner.preflight(false));
+        run.assert_directive("cargo:rustc-cfg=from_fixture");
+        assert_eq!(
+            fs::read_to_string(fixture.out_dir().join("generated.txt")).unwrap(),
+            "generated"
+        );
+        assert!(run
+            .report
+            .staging_dir
+            .as_ref()
+            .unwrap()
+            .starts_with(fixture.path().join("staging")));
+        assert!(run.staged_manifest().contains("name = \"build-script\""));
+    }
+}
@@ -0,0 +1,74 @@
+//! Giving up on a child that runs for too long, so a hung build crate fails
+//! the build rather than stalling it forever.
+
+use std::{io, process, thread, time};
+
+/// How often a watched child is checked on.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+/// A child being waited for, that's killed once it's run past its timeout.
+pub(crate) enum Watched {
+    Unlimited(process::Child),
+    Limited(thread::JoinHandle<io::Result<process::ExitStatus>>),
+}
+
+impl Watched {
+    /// Watch `child`, which is killed if it's still running after `timeout`.
+    /// Its output should be read as usual in the meantime.
+    pub(crate) fn new(mut child: process::Child, timeout: Option<time::Duration>) -> Watched {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return Watched::Unlimited(child),
+        };
+        let start = time::Instant::now();
+        Watched::Limited(thread::spawn(move || loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                child.kill()?;
+                child.wait()?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {:?}, and was killed", timeout),
+                ));
+            }
+            thread::sleep(POLL_INTERVAL.min(timeout - elapsed));
+        }))
+    }
+
+    /// Wait for the child to exit, failing with `io::ErrorKind::TimedOut` if
+    /// it had to be killed.
+    pub(crate) fn wait(self) -> io::Result<process::ExitStatus> {
+        match self {
+            Watched::Unlimited(mut child) => child.wait(),
+            Watched::Limited(watchdog) => watchdog.join().expect("watchdog thread panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_watched() {
+        let sleep = || process::Command::new("sleep").arg("10").spawn().unwrap();
+
+        let start = time::Instant::now();
+        let error = Watched::new(sleep(), Some(time::Duration::from_millis(200)))
+            .wait()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "timed out after 200ms, and was killed");
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+
+        let done = process::Command::new("true").spawn().unwrap();
+        let status = Watched::new(done, Some(time::Duration::from_secs(10)))
+            .wait()
+            .unwrap();
+        assert!(status.success());
+    }
+}
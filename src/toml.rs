@@ -0,0 +1,811 @@
+//! Just enough TOML to read manifests, without pulling in a dependency.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::json::Json;
+
+pub(crate) type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    /// Dates and times are kept as written.
+    Datetime(String),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    /// The value at `path` of dotted keys below this one, if there is one.
+    pub fn get(&self, path: &[&str]) -> Option<&Value> {
+        match path.split_first() {
+            None => Some(self),
+            Some((key, rest)) => match self {
+                Value::Table(table) => table.get(*key)?.get(rest),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value as TOML that fits on one line, with tables inline.
+    pub fn to_inline_toml(&self) -> String {
+        match self {
+            Value::String(s) => quote(s, false),
+            Value::Integer(i) => i.to_string(),
+            Value::Float(f) if f.is_nan() => "nan".to_owned(),
+            Value::Float(f) if f.is_infinite() => {
+                (if *f > 0.0 { "inf" } else { "-inf" }).to_owned()
+            }
+            Value::Float(f) => format!("{:?}", f),
+            Value::Boolean(b) => b.to_string(),
+            Value::Datetime(d) => d.clone(),
+            Value::Array(items) => format!(
+                "[{}]",
+                items
+                    .iter()
+                    .map(Value::to_inline_toml)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Table(table) if table.is_empty() => "{}".to_owned(),
+            Value::Table(table) => format!(
+                "{{ {} }}",
+                table
+                    .iter()
+                    .map(|(key, value)| format!("{} = {}", quote_key(key), value.to_inline_toml()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// The table as a TOML document, with a `[header]` for each table in it
+    /// that holds values of its own.
+    pub fn to_document(&self) -> String {
+        let mut document = String::new();
+        if let Value::Table(table) = self {
+            write_table(&mut document, &mut Vec::new(), table);
+        }
+        document
+    }
+
+    pub fn to_json(&self) -> Json {
+        match self {
+            Value::String(s) | Value::Datetime(s) => Json::string(s.clone()),
+            Value::Integer(i) => Json::Integer(*i),
+            Value::Float(f) => Json::Number(*f),
+            Value::Boolean(b) => Json::Bool(*b),
+            Value::Array(items) => Json::Array(items.iter().map(Value::to_json).collect()),
+            Value::Table(table) => Json::object(
+                table
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.to_json())),
+            ),
+        }
+    }
+}
+
+/// Where a string value was written, for rewriting it in place.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct StringSpan {
+    /// The dotted keys leading to the string from the root table. Array
+    /// elements, and the tables in an array of tables, share the array's key.
+    pub key: Vec<String>,
+    pub value: String,
+    /// The string as written, quotes included, in chars from the start of
+    /// the document.
+    pub chars: Range<usize>,
+}
+
+/// Where a `key = value` line was written.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Statement {
+    /// The full key, from the root table.
+    pub key: Vec<String>,
+    /// How much of `key` comes from the `[table]` header the statement is
+    /// under, rather than the statement itself.
+    pub header_len: usize,
+    /// From the start of the key to the end of the value, in chars from the
+    /// start of the document.
+    pub chars: Range<usize>,
+}
+
+fn write_table(document: &mut String, path: &mut Vec<String>, table: &Table) {
+    let (tables, values): (Vec<_>, Vec<_>) = table
+        .iter()
+        .partition(|(_, value)| matches!(value, Value::Table(_)));
+    if !path.is_empty() && (!values.is_empty() || tables.is_empty()) {
+        if !document.is_empty() {
+            document.push('\n');
+        }
+        let header: Vec<_> = path.iter().map(|key| quote_key(key)).collect();
+        document.push_str(&format!("[{}]\n", header.join(".")));
+    }
+    for (key, value) in values {
+        document.push_str(&format!(
+            "{} = {}\n",
+            quote_key(key),
+            value.to_inline_toml()
+        ));
+    }
+    for (key, value) in tables {
+        if let Value::Table(table) = value {
+            path.push(key.clone());
+            write_table(document, path, table);
+            path.pop();
+        }
+    }
+}
+
+/// `value` as a TOML string: a literal one when asked for and possible, a
+/// basic one otherwise.
+pub(crate) fn quote(value: &str, literal: bool) -> String {
+    if literal && !value.contains(['\'', '\n', '\r']) {
+        return format!("'{}'", value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `key` as a single part of a TOML key, quoted if it has to be.
+pub(crate) fn quote_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_owned()
+    } else {
+        quote(key, false)
+    }
+}
+
+/// Parse a TOML document into its root table.
+pub(crate) fn parse(input: &str) -> Result<Value, String> {
+    let mut parser = Parser::new(input);
+    parser
+        .document()
+        .map(Value::Table)
+        .map_err(|e| format!("line {}: {}", parser.line(), e))
+}
+
+/// Every string value in a TOML document, in the order they're written.
+pub(crate) fn strings(input: &str) -> Result<Vec<StringSpan>, String> {
+    let mut parser = Parser::new(input);
+    match parser.document() {
+        Ok(_) => Ok(parser.strings),
+        Err(e) => Err(format!("line {}: {}", parser.line(), e)),
+    }
+}
+
+/// Every `key = value` line in a TOML document, in the order they're
+/// written.
+pub(crate) fn statements(input: &str) -> Result<Vec<Statement>, String> {
+    let mut parser = Parser::new(input);
+    match parser.document() {
+        Ok(_) => Ok(parser.statements),
+        Err(e) => Err(format!("line {}: {}", parser.line(), e)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// The key of the value being parsed.
+    key: Vec<String>,
+    strings: Vec<StringSpan>,
+    statements: Vec<Statement>,
+}
+
+impl Parser {
+    fn new(input: &str) -> Self {
+        Parser {
+            chars: input.chars().collect(),
+            pos: 0,
+            key: Vec::new(),
+            strings: Vec::new(),
+            statements: Vec::new(),
+        }
+    }
+
+    fn line(&self) -> usize {
+        1 + self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn looking_at(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.peek().ok_or("unexpected end of input")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => Err(format!("expected {:?}, found {:?}", expected, c)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skip whitespace, comments and newlines, as allowed between array
+    /// elements and between statements.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_whitespace();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    /// After a statement, only a comment may follow on the same line.
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+        self.skip_comment();
+        if self.looking_at("\r\n") {
+            self.pos += 2;
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => Err(format!("expected end of line, found {:?}", c)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Table, String> {
+        let mut root = Table::new();
+        let mut current = Vec::new();
+
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') if self.peek_at(1) == Some('[') => {
+                    self.pos += 2;
+                    let path = self.key()?;
+                    self.expect(']')?;
+                    self.expect(']')?;
+                    let (last, parent) = path.split_last().expect("keys aren't empty");
+                    let parent = table_at(&mut root, parent)?;
+                    match parent
+                        .entry(last.clone())
+                        .or_insert_with(|| Value::Array(Vec::new()))
+                    {
+                        Value::Array(items) => items.push(Value::Table(Table::new())),
+                        _ => return Err(format!("{} isn't an array of tables", path.join("."))),
+                    }
+                    current = path;
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let path = self.key()?;
+                    self.expect(']')?;
+                    table_at(&mut root, &path)?;
+                    current = path;
+                }
+                Some(_) => {
+                    let start = self.pos;
+                    let key = self.key()?;
+                    self.expect('=')?;
+                    self.skip_whitespace();
+                    self.key = current.iter().chain(&key).cloned().collect();
+                    let value = self.value()?;
+                    self.statements.push(Statement {
+                        key: self.key.clone(),
+                        header_len: current.len(),
+                        chars: start..self.pos,
+                    });
+                    let table = table_at(&mut root, &current)?;
+                    insert(table, &key, value)?;
+                }
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    /// A possibly dotted key, followed by any whitespace.
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut path = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let part = match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    self.basic_string()?
+                }
+                Some('\'') => {
+                    self.pos += 1;
+                    self.literal_string()?
+                }
+                _ => {
+                    let start = self.pos;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err("expected a key".to_owned());
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            path.push(part);
+            self.skip_whitespace();
+            if self.peek() != Some('.') {
+                return Ok(path);
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        let value = self.bare_value()?;
+        if let Value::String(s) = &value {
+            self.strings.push(StringSpan {
+                key: self.key.clone(),
+                value: s.clone(),
+                chars: start..self.pos,
+            });
+        }
+        Ok(value)
+    }
+
+    fn bare_value(&mut self) -> Result<Value, String> {
+        match self.peek().ok_or("expected a value")? {
+            '"' if self.looking_at("\"\"\"") => {
+                self.pos += 3;
+                self.multiline_basic_string().map(Value::String)
+            }
+            '"' => {
+                self.pos += 1;
+                self.basic_string().map(Value::String)
+            }
+            '\'' if self.looking_at("'''") => {
+                self.pos += 3;
+                self.multiline_literal_string().map(Value::String)
+            }
+            '\'' => {
+                self.pos += 1;
+                self.literal_string().map(Value::String)
+            }
+            '[' => {
+                self.pos += 1;
+                self.array()
+            }
+            '{' => {
+                self.pos += 1;
+                self.inline_table()
+            }
+            _ if self.looking_at("true") => {
+                self.pos += 4;
+                Ok(Value::Boolean(true))
+            }
+            _ if self.looking_at("false") => {
+                self.pos += 5;
+                Ok(Value::Boolean(false))
+            }
+            _ => self.scalar(),
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, String> {
+        let hex = |parser: &mut Parser, len: usize| {
+            let digits: String = (0..len).map(|_| parser.next()).collect::<Result<_, _>>()?;
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("invalid unicode escape {:?}", digits))
+        };
+        Ok(match self.next()? {
+            'b' => '\u{8}',
+            't' => '\t',
+            'n' => '\n',
+            'f' => '\u{c}',
+            'r' => '\r',
+            'e' => '\u{1b}',
+            '"' => '"',
+            '\\' => '\\',
+            'u' => hex(self, 4)?,
+            'U' => hex(self, 8)?,
+            c => return Err(format!("invalid escape \\{}", c)),
+        })
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => s.push(self.escape()?),
+                '\n' => return Err("newline in string".to_owned()),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn multiline_basic_string(&mut self) -> Result<String, String> {
+        self.trim_leading_newline();
+        let mut s = String::new();
+        loop {
+            if self.looking_at("\"\"\"") && !self.looking_at("\"\"\"\"") {
+                self.pos += 3;
+                return Ok(s);
+            }
+            match self.next()? {
+                '\\' if matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) => {
+                    // A line ending backslash trims all whitespace up to the
+                    // next non-whitespace character.
+                    while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                        self.pos += 1;
+                    }
+                }
+                '\\' => s.push(self.escape()?),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '\'' => return Ok(s),
+                '\n' => return Err("newline in string".to_owned()),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn multiline_literal_string(&mut self) -> Result<String, String> {
+        self.trim_leading_newline();
+        let mut s = String::new();
+        loop {
+            if self.looking_at("'''") && !self.looking_at("''''") {
+                self.pos += 3;
+                return Ok(s);
+            }
+            s.push(self.next()?);
+        }
+    }
+
+    fn trim_leading_newline(&mut self) {
+        if self.looking_at("\r\n") {
+            self.pos += 2;
+        } else if self.peek() == Some('\n') {
+            self.pos += 1;
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.next()? {
+                ',' => {}
+                ']' => return Ok(Value::Array(items)),
+                c => return Err(format!("expected ',' or ']' in array, found {:?}", c)),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, String> {
+        let mut table = Table::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Table(table));
+        }
+        loop {
+            let key = self.key()?;
+            self.expect('=')?;
+            self.skip_whitespace();
+            let depth = self.key.len();
+            self.key.extend(key.iter().cloned());
+            let value = self.value()?;
+            self.key.truncate(depth);
+            insert(&mut table, &key, value)?;
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => {}
+                '}' => return Ok(Value::Table(table)),
+                c => return Err(format!("expected ',' or '}}' in table, found {:?}", c)),
+            }
+        }
+    }
+
+    /// Numbers and dates.
+    fn scalar(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-_.:".contains(c)) {
+            self.pos += 1;
+        }
+        // A date may be separated from its time by a space.
+        if self.peek() == Some(' ')
+            && self.peek_at(1).is_some_and(|c| c.is_ascii_digit())
+            && is_date(&self.chars[start..self.pos])
+        {
+            self.pos += 1;
+            while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-.:".contains(c))
+            {
+                self.pos += 1;
+            }
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        if token.is_empty() {
+            return Err(format!("expected a value, found {:?}", self.peek()));
+        }
+
+        if is_date(&self.chars[start..self.pos]) || token.contains(':') {
+            return Ok(Value::Datetime(token));
+        }
+        let digits = token.replace('_', "");
+        let (sign, unsigned) = match digits.strip_prefix('-') {
+            Some(unsigned) => (-1, unsigned),
+            None => (1, digits.strip_prefix('+').unwrap_or(&digits)),
+        };
+        for (prefix, radix) in [("0x", 16), ("0o", 8), ("0b", 2)] {
+            if let Some(digits) = unsigned.strip_prefix(prefix) {
+                return i64::from_str_radix(digits, radix)
+                    .map(|i| Value::Integer(sign * i))
+                    .map_err(|_| format!("invalid integer {:?}", token));
+            }
+        }
+        if let Ok(i) = digits.parse() {
+            return Ok(Value::Integer(i));
+        }
+        match unsigned {
+            "inf" if sign < 0 => Ok(Value::Float(f64::NEG_INFINITY)),
+            "inf" => Ok(Value::Float(f64::INFINITY)),
+            "nan" => Ok(Value::Float(f64::NAN)),
+            _ => digits
+                .parse()
+                .map(Value::Float)
+                .map_err(|_| format!("invalid value {:?}", token)),
+        }
+    }
+}
+
+/// Whether `token` starts with a `YYYY-MM-DD` date.
+fn is_date(token: &[char]) -> bool {
+    token.len() >= 10
+        && token[..10].iter().enumerate().all(|(i, c)| {
+            if i == 4 || i == 7 {
+                *c == '-'
+            } else {
+                c.is_ascii_digit()
+            }
+        })
+}
+
+/// The table at `path` below `root`, creating any that are missing. For an
+/// array of tables, that's its last element.
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    let mut table = root;
+    for key in path {
+        table = match table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(table) => table,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(table)) => table,
+                _ => return Err(format!("{} isn't a table", key)),
+            },
+            _ => return Err(format!("{} isn't a table", key)),
+        };
+    }
+    Ok(table)
+}
+
+/// Set the possibly dotted `key` in `table`.
+fn insert(table: &mut Table, key: &[String], value: Value) -> Result<(), String> {
+    let (last, parent) = key.split_last().expect("keys aren't empty");
+    let table = table_at(table, parent)?;
+    if table.contains_key(last) {
+        return Err(format!("duplicate key {}", key.join(".")));
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = parse(
+            r#"
+# A comment
+[package]
+name = "parent" # trailing comment
+version = '0.1.0'
+authors = [
+    "a <a@example.com>",
+    "b",
+]
+
+[package.metadata.cargo-5730]
+profile = "release"
+jobs = 4
+ratio = 0.5
+strict = true
+features = ["x", "y"]
+paths = { include = ["src/**"], "quoted key" = 'lit\eral' }
+built = 1979-05-27 07:32:00Z
+description = """
+multi \
+   line"""
+
+[[bin]]
+name = "one"
+
+[[bin]]
+name = "two"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            manifest.get(&["package", "name"]),
+            Some(&Value::String("parent".to_owned()))
+        );
+        let metadata = manifest
+            .get(&["package", "metadata", "cargo-5730"])
+            .unwrap();
+        assert_eq!(
+            metadata.to_json().to_string(),
+            r#"{"built":"1979-05-27 07:32:00Z","description":"multi line","features":["x","y"],"jobs":4,"paths":{"include":["src/**"],"quoted key":"lit\\eral"},"profile":"release","ratio":0.5,"strict":true}"#
+        );
+        match manifest.get(&["bin"]) {
+            Some(Value::Array(bins)) => {
+                assert_eq!(
+                    bins[1].get(&["name"]),
+                    Some(&Value::String("two".to_owned()))
+                )
+            }
+            other => panic!("unexpected bin: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let input = "[a]\nb = { c.d = [\"x\", 'y'] }\n[[e]]\nf = 1\ng = \"\\u00e9\"\n";
+        let strings = strings(input).unwrap();
+        let chars: Vec<char> = input.chars().collect();
+        let written = |span: &StringSpan| chars[span.chars.clone()].iter().collect::<String>();
+
+        assert_eq!(strings.len(), 3);
+        assert_eq!(strings[0].key, ["a", "b", "c", "d"]);
+        assert_eq!(written(&strings[0]), "\"x\"");
+        assert_eq!(written(&strings[1]), "'y'");
+        assert_eq!(strings[2].key, ["e", "g"]);
+        assert_eq!(strings[2].value, "é");
+        assert_eq!(written(&strings[2]), "\"\\u00e9\"");
+    }
+
+    #[test]
+    fn test_statements() {
+        let input = "a.b = 1 # comment\n[c.d]\ne = { f = 2 }\n";
+        let statements = statements(input).unwrap();
+        let chars: Vec<char> = input.chars().collect();
+
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].key, ["a", "b"]);
+        assert_eq!(statements[0].header_len, 0);
+        assert_eq!(
+            chars[statements[0].chars.clone()]
+                .iter()
+                .collect::<String>(),
+            "a.b = 1"
+        );
+        assert_eq!(statements[1].key, ["c", "d", "e"]);
+        assert_eq!(statements[1].header_len, 2);
+        assert_eq!(
+            chars[statements[1].chars.clone()]
+                .iter()
+                .collect::<String>(),
+            "e = { f = 2 }"
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/a/b", true), "'/a/b'");
+        assert_eq!(quote("/it's", true), r#""/it's""#);
+        assert_eq!(quote(r#"C:\a "b""#, false), r#""C:\\a \"b\"""#);
+    }
+
+    #[test]
+    fn test_to_inline_toml() {
+        let input = r#"a = { "b c" = [1, 2.5, "d"], e = true, f = {} }"#;
+        let value = parse(input).unwrap();
+        let inline = value.get(&["a"]).unwrap().to_inline_toml();
+        assert_eq!(inline, r#"{ "b c" = [1, 2.5, "d"], e = true, f = {} }"#);
+        assert_eq!(parse(&format!("a = {}", inline)).unwrap(), value);
+    }
+
+    #[test]
+    fn test_to_document() {
+        let input = "top = 1\n\n[net]\ngit-fetch-with-cli = true\n\n\
+                     [source.crates-io]\nreplace-with = \"vendored\"\n\n\
+                     [source.\"git+https://example.com\"]\ngit = \"https://example.com\"\n";
+        let value = parse(input).unwrap();
+        assert_eq!(value.to_document(), input);
+        assert_eq!(parse(&value.to_document()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("a = 1\na = 2\n").unwrap_err(),
+            "line 2: duplicate key a"
+        );
+        assert!(parse("a = \"unterminated\n").is_err());
+        assert!(parse("a = 1 b = 2\n").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Pinning the toolchain used inside the isolation boundary.
+
+use std::{env, ffi, path, process};
+
+/// The toolchain to build the build crate with, regardless of the
+/// `RUSTUP_TOOLCHAIN` the outer build inherited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ToolchainOverride {
+    /// A rustup toolchain name, e.g. `stable` or `nightly-2024-01-01`.
+    Rustup(String),
+    /// A toolchain directory containing `bin/cargo` and `bin/rustc`, e.g. a
+    /// standalone install or a `rustup toolchain link` target.
+    Path(path::PathBuf),
+}
+
+/// Resolve `toolchain` to the cargo executable to run, and update the inner
+/// environment so that cargo picks up the matching rustc. Panics if the
+/// toolchain isn't installed.
+pub(crate) fn apply(
+    toolchain: &ToolchainOverride,
+    forwarded_env: &mut Vec<(String, ffi::OsString)>,
+) -> ffi::OsString {
+    match toolchain {
+        ToolchainOverride::Rustup(name) => {
+            let output = process::Command::new("rustup")
+                .args(["which", "--toolchain", name, "cargo"])
+                .output()
+                .unwrap_or_else(|e| {
+                    panic!("Couldn't run rustup to locate toolchain {}: {}", name, e)
+                });
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                panic!(
+                    "Toolchain {} is unavailable: {}",
+                    name,
+                    stderr.lines().next().unwrap_or("rustup which failed")
+                );
+            }
+
+            set_var(forwarded_env, "RUSTUP_TOOLCHAIN", name.into());
+            let cargo = String::from_utf8_lossy(&output.stdout);
+            ffi::OsString::from(cargo.trim())
+        }
+        ToolchainOverride::Path(dir) => {
+            let bin = dir.join("bin");
+            let exe = |name: &str| bin.join(format!("{}{}", name, env::consts::EXE_SUFFIX));
+            let (cargo, rustc) = (exe("cargo"), exe("rustc"));
+            for tool in &[&cargo, &rustc] {
+                assert!(
+                    tool.is_file(),
+                    "Toolchain at {} is missing {}",
+                    dir.display(),
+                    tool.display()
+                );
+            }
+
+            // Keep rustup proxies (if any) from redirecting to another toolchain.
+            forwarded_env.retain(|(key, _)| *key != "RUSTUP_TOOLCHAIN");
+            set_var(forwarded_env, "RUSTC", rustc.into_os_string());
+
+            let search_path = forwarded_env
+                .iter()
+                .find(|(key, _)| *key == "PATH")
+                .map(|(_, value)| env::split_paths(value).collect())
+                .unwrap_or_else(Vec::new);
+            let search_path = env::join_paths(Some(bin).into_iter().chain(search_path))
+                .expect("Toolchain bin dir can't be added to PATH");
+            set_var(forwarded_env, "PATH", search_path);
+
+            cargo.into_os_string()
+        }
+    }
+}
+
+fn set_var(
+    forwarded_env: &mut Vec<(String, ffi::OsString)>,
+    key: &'static str,
+    value: ffi::OsString,
+) {
+    forwarded_env.retain(|(k, _)| *k != key);
+    forwarded_env.push((key.to_owned(), value));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_path_toolchain() {
+        let dir = env::temp_dir().join(format!("cargo-5730-toolchain-test-{}", process::id()));
+        let bin = dir.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        for tool in &["cargo", "rustc"] {
+            fs::write(bin.join(format!("{}{}", tool, env::consts::EXE_SUFFIX)), "").unwrap();
+        }
+
+        let mut forwarded_env = vec![
+            ("PATH".to_owned(), ffi::OsString::from("/usr/bin")),
+            ("RUSTUP_TOOLCHAIN".to_owned(), ffi::OsString::from("stable")),
+        ];
+        let cargo = apply(&ToolchainOverride::Path(dir.clone()), &mut forwarded_env);
+
+        assert_eq!(
+            path::PathBuf::from(cargo),
+            bin.join(format!("cargo{}", env::consts::EXE_SUFFIX))
+        );
+        assert!(!forwarded_env.iter().any(|(k, _)| *k == "RUSTUP_TOOLCHAIN"));
+        let search_path = &forwarded_env.iter().find(|(k, _)| *k == "PATH").unwrap().1;
+        assert_eq!(env::split_paths(search_path).next().unwrap(), bin);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "is missing")]
+    fn test_incomplete_path_toolchain() {
+        let dir = env::temp_dir().join(format!("cargo-5730-toolchain-missing-{}", process::id()));
+        apply(&ToolchainOverride::Path(dir), &mut Vec::new());
+    }
+}
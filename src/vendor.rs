@@ -0,0 +1,120 @@
+//! Building the staged crate from a `cargo vendor` directory, for builds that
+//! can't reach the network.
+
+use std::{fs, path};
+
+use crate::cargo_config::StagedConfig;
+use crate::toml::{self, Table, Value};
+
+/// Where a vendor dir from outside the build crate is staged, beside the
+/// crate's own files.
+pub(crate) const STAGED_VENDOR_DIR: &str = "cargo-5730-vendor";
+
+/// The source everything is replaced with.
+const VENDORED_SOURCE: &str = "vendored-sources";
+
+/// Point cargo in `staged_dir` at the vendored crates in `vendor_dir`, a path
+/// relative to it, by adding source replacement to the staged crate's cargo
+/// `config`. Relative, so it still holds wherever the staged crate is built.
+pub(crate) fn configure(config: &mut StagedConfig, staged_dir: &path::Path, vendor_dir: &str) {
+    assert!(
+        config.get(&["source"]).is_none(),
+        "The build crate's {} already configures sources, so vendored ones can't be added",
+        config.path().display()
+    );
+    let lockfile = fs::read_to_string(staged_dir.join("Cargo.lock")).ok();
+    let sources = sources(vendor_dir, lockfile.as_deref())
+        .unwrap_or_else(|e| panic!("Can't read the build crate's Cargo.lock: {}", e));
+    config.fill(&["source"], &sources);
+}
+
+/// The `[source]` table replacing crates.io, and every git source in
+/// `lockfile`, with the crates vendored in `vendor_dir`.
+fn sources(vendor_dir: &str, lockfile: Option<&str>) -> Result<Value, String> {
+    let replaced = |mut fields: Table| {
+        fields.insert(
+            "replace-with".to_owned(),
+            Value::String(VENDORED_SOURCE.to_owned()),
+        );
+        Value::Table(fields)
+    };
+
+    let mut sources = Table::new();
+    sources.insert("crates-io".to_owned(), replaced(Table::new()));
+    if let Some(lockfile) = lockfile {
+        let lockfile = toml::parse(lockfile)?;
+        if let Some(Value::Array(packages)) = lockfile.get(&["package"]) {
+            for package in packages {
+                let source = package.get(&["source"]).and_then(Value::as_str);
+                if let Some((name, fields)) = source.and_then(git_source) {
+                    sources.insert(name, replaced(fields));
+                }
+            }
+        }
+    }
+    let mut vendored = Table::new();
+    vendored.insert("directory".to_owned(), Value::String(vendor_dir.to_owned()));
+    sources.insert(VENDORED_SOURCE.to_owned(), Value::Table(vendored));
+    Ok(Value::Table(sources))
+}
+
+/// The name and fields of the source for a lockfile's `git+URL?rev=X#commit`
+/// source, as `cargo vendor` would write it.
+fn git_source(source: &str) -> Option<(String, Table)> {
+    let source = source.strip_prefix("git+")?;
+    let source = source.split('#').next().unwrap_or(source);
+    let (url, query) = match source.split_once('?') {
+        Some((url, query)) => (url, Some(query)),
+        None => (source, None),
+    };
+
+    let mut fields = Table::new();
+    fields.insert("git".to_owned(), Value::String(url.to_owned()));
+    for pair in query.into_iter().flat_map(|query| query.split('&')) {
+        if let Some((key @ ("branch" | "tag" | "rev"), value)) = pair.split_once('=') {
+            fields.insert(key.to_owned(), Value::String(value.to_owned()));
+        }
+    }
+    Some((format!("git+{}", source), fields))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sources() {
+        let lockfile = r#"
+version = 3
+
+[[package]]
+name = "build-script"
+version = "0.1.0"
+
+[[package]]
+name = "cfg-if"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "forked"
+version = "0.2.0"
+source = "git+https://example.com/forked.git?branch=fix#0123abcd"
+"#;
+        let expected = r#"[crates-io]
+replace-with = "vendored-sources"
+
+["git+https://example.com/forked.git?branch=fix"]
+git = "https://example.com/forked.git"
+branch = "fix"
+replace-with = "vendored-sources"
+
+[vendored-sources]
+directory = "cargo-5730-vendor"
+"#;
+        assert_eq!(
+            sources(STAGED_VENDOR_DIR, Some(lockfile)).unwrap(),
+            toml::parse(expected).unwrap()
+        );
+    }
+}